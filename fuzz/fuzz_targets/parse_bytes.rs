@@ -0,0 +1,10 @@
+// Run with `cargo fuzz run parse_bytes`.
+//
+// Malformed files found by the fuzzer (or in the wild) belong in regressions/parse_bytes, and can
+// all be replayed with `cargo fuzz run parse_bytes regressions/parse_bytes/*`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = png_header_scanner::parse_bytes(data);
+});
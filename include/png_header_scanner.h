@@ -0,0 +1,95 @@
+#ifndef PNG_HEADER_SCANNER_H
+#define PNG_HEADER_SCANNER_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum PhsStatus {
+  PHS_STATUS_OK = 0,
+  PHS_STATUS_INVALID_ARGUMENT = 1,
+  PHS_STATUS_OPEN_FAILED = 2,
+  PHS_STATUS_READ_FAILED = 3,
+  PHS_STATUS_INVALID_PNG = 4,
+  PHS_STATUS_APPLE_CGBI = 5,
+  PHS_STATUS_ANIMATED = 6,
+  PHS_STATUS_TOO_LARGE = 7,
+  PHS_STATUS_WOULD_GROW = 8,
+  PHS_STATUS_VERIFICATION_FAILED = 9,
+  PHS_STATUS_FIX_FAILED = 10,
+  PHS_STATUS_INTERNAL_ERROR = 11,
+} PhsStatus;
+
+typedef struct PhsHeader {
+  uint32_t width;
+  uint32_t height;
+  uint8_t bit_depth;
+  uint8_t color_type;
+  bool interlaced;
+  bool apple_cgbi;
+} PhsHeader;
+
+typedef struct PhsFixOptions {
+  bool deinterlace;
+  bool force;
+  bool force_apng;
+  bool convert_any;
+  bool downconvert_16bit;
+} PhsFixOptions;
+
+typedef struct PhsFixResult {
+  uint64_t size_before;
+  uint64_t size_after;
+  uint8_t color_type;
+} PhsFixResult;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Read the header of the PNG file at path into out. out is only written when PHS_STATUS_OK is
+ * returned.
+ *
+ * # Safety
+ *
+ * path must be a null terminated string, and out must point to a PhsHeader.
+ */
+PhsStatus phs_scan_header(const char *path, PhsHeader *out);
+
+/**
+ * Like phs_scan_header, for a PNG held in memory. Every chunk up to the image data is checked
+ * too, not just the header.
+ *
+ * # Safety
+ *
+ * data must point to length readable bytes, and out must point to a PhsHeader.
+ */
+PhsStatus phs_scan_header_bytes(const uint8_t *data, size_t length, PhsHeader *out);
+
+/**
+ * Convert the PNG file at path to RGB/RGBA and optimize it, replacing the file once the new
+ * image has been verified. options may be NULL for the defaults, and out may be NULL if the
+ * result isn't needed.
+ *
+ * # Safety
+ *
+ * path must be a null terminated string. options and out must each be NULL or point to their
+ * struct.
+ */
+PhsStatus phs_fix_image(const char *path, const PhsFixOptions *options, PhsFixResult *out);
+
+/**
+ * A description of a status, as a static null terminated string which must not be freed.
+ */
+const char *phs_status_message(PhsStatus status);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PNG_HEADER_SCANNER_H */
@@ -0,0 +1,24 @@
+#!/bin/bash
+# Compare how fast headers are read with and without --io-uring, for each folder given:
+#
+#   scripts/bench_headers.sh /mnt/nvme/assets /mnt/nfs/assets
+#
+# Needs hyperfine. As root the page cache is dropped before every run, so every header comes from
+# the disk or the network instead of memory.
+[ $# -gt 0 ] || { echo "Usage: $0 FOLDER..."; exit 1; }
+command -v hyperfine > /dev/null || { echo "hyperfine is needed"; exit 1; }
+
+SCANNER=${SCANNER:-./png_header_scanner}
+PREPARE=true
+[ "$(id -u)" -eq 0 ] && PREPARE="sync; echo 3 > /proc/sys/vm/drop_caches"
+
+for folder in "$@"; do
+    files=$(find "$folder" -iname '*.png' | wc -l)
+    echo "[$folder]: $files PNGs"
+    # --check exits with 1 when it finds something, which hyperfine would count as a failure
+    hyperfine --ignore-failure --warmup 1 --prepare "$PREPARE" \
+        --export-markdown "bench_$(basename "$folder").md" \
+        "$SCANNER --check --no-cache --quiet --jobs 0 '$folder'" \
+        "$SCANNER --check --no-cache --quiet --jobs 0 --io-uring '$folder'" \
+        "$SCANNER --check --no-cache --quiet --jobs 0 --io-uring --io-concurrency 1024 '$folder'" || exit 1
+done
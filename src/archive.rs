@@ -0,0 +1,87 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::result::ZipResult;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+use png_header_scanner::{read_header, ParseResult};
+
+// Zip files, and the game data formats which are zip files with another extension
+pub fn is_archive(path : &Path) -> bool {
+    match path.extension() {
+        Some(ext) => ext == OsStr::new("zip") || ext == OsStr::new("pak") || ext == OsStr::new("pk3"),
+        None => false,
+    }
+}
+
+// Path of an entry in an archive as shown in reports, like archive.zip!inner/path.png
+pub fn member_path(rel_path : &Path, entry_name : &str) -> PathBuf {
+    PathBuf::from(format!("{}!{}", crate::slash_path(rel_path), entry_name))
+}
+
+// Read the header of every PNG in the zip file at path. Each entry is only decompressed as far as
+// its IHDR chunk, nothing is extracted.
+// Zip files made on macOS have a ._ copy of every PNG's metadata under __MACOSX
+fn is_png_entry(name : &str) -> bool {
+    crate::is_png(Path::new(name)) && !crate::is_apple_metadata_path(Path::new(name))
+}
+
+pub fn scan_archive(path : &Path) -> ZipResult<Vec<(String, ParseResult)>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+
+    let mut headers = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        if !entry.is_file() || !is_png_entry(entry.name()) {
+            continue;
+        }
+        let name = entry.name().to_string();
+        headers.push((name, read_header(&mut entry)));
+    }
+    Ok(headers)
+}
+
+// Rebuild the zip file at path with fix_entry applied to the contents of every PNG in it.
+// fix_entry returns the new contents, or None to keep the entry. Changed entries keep their
+// compression method, timestamp and permissions, and everything else is copied without
+// recompressing it. Returns the new archive, or None if no entry changed.
+pub fn rewrite_archive<F>(path : &Path, mut fix_entry : F) -> ZipResult<Option<Vec<u8>>>
+    where F: FnMut(&str, &[u8]) -> Option<Vec<u8>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.set_comment(String::from_utf8_lossy(archive.comment()).into_owned());
+
+    let mut changed = false;
+    for index in 0..archive.len() {
+        let fixed_data = {
+            let mut entry = archive.by_index(index)?;
+            if entry.is_file() && is_png_entry(entry.name()) {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                fix_entry(entry.name(), &data).map(|fixed_data| (entry.name().to_string(), fixed_data))
+            } else {
+                None
+            }
+        };
+
+        let entry = archive.by_index(index)?;
+        match fixed_data {
+            Some((name, fixed_data)) => {
+                let mut options = FileOptions::default()
+                    .compression_method(entry.compression())
+                    .last_modified_time(entry.last_modified());
+                if let Some(mode) = entry.unix_mode() {
+                    options = options.unix_permissions(mode);
+                }
+                zip.start_file(name, options)?;
+                zip.write_all(&fixed_data)?;
+                changed = true;
+            },
+            None => zip.raw_copy_file(entry)?,
+        }
+    }
+
+    let archive_data = zip.finish()?.into_inner();
+    Ok(if changed { Some(archive_data) } else { None })
+}
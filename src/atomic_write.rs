@@ -0,0 +1,189 @@
+use std::fs::{self, FileTimes, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    // Keep the original file's modified and accessed times
+    pub preserve_times: bool,
+    // Rewrite files with the read-only attribute set instead of refusing to
+    pub force_readonly: bool,
+}
+
+// Makes temp file names unique between threads of the same process
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Hidden file next to the target, so the rename stays on the same filesystem
+fn temp_path(path : &Path) -> io::Result<PathBuf> {
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}-{}.tmp", process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    Ok(path.with_file_name(temp_name))
+}
+
+// Whether the file looks like one of our temp files, which is left behind if a write is interrupted
+pub fn is_temp_file(path : &Path) -> bool {
+    let file_name = match path.file_name().and_then(|file_name| file_name.to_str()) {
+        Some(file_name) => file_name,
+        None => return false,
+    };
+    let inner = match file_name.strip_prefix('.').and_then(|name| name.strip_suffix(".tmp")) {
+        Some(inner) => inner,
+        None => return false,
+    };
+
+    match inner.rsplit_once('.').and_then(|(name, suffix)| Some((name, suffix.split_once('-')?))) {
+        Some((name, (pid, counter))) => {
+            let is_number = |part : &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+            !name.is_empty() && is_number(pid) && is_number(counter)
+        },
+        None => false,
+    }
+}
+
+fn write_temp_file(temp_path : &Path, original_path : &Path, data : &[u8], options : &WriteOptions) -> io::Result<()> {
+    let original_metadata = fs::metadata(original_path).ok();
+
+    let mut file = OpenOptions::new().write(true).create_new(true).open(temp_path)?;
+    file.write_all(data)?;
+
+    if options.preserve_times {
+        if let Some(metadata) = &original_metadata {
+            file.set_times(FileTimes::new()
+                .set_accessed(metadata.accessed()?)
+                .set_modified(metadata.modified()?))?;
+        }
+    }
+    file.sync_all()?;
+
+    // Keep the original file's permissions (the mode bits on Unix)
+    if let Some(metadata) = original_metadata {
+        fs::set_permissions(temp_path, metadata.permissions())?;
+    }
+    Ok(())
+}
+
+// Whether the file has the read-only attribute (no write bits at all on Unix)
+pub fn is_read_only(path : &Path) -> bool {
+    fs::metadata(path).map(|metadata| metadata.permissions().readonly()).unwrap_or(false)
+}
+
+// Number of hard links to the file, always 1 where that isn't known
+pub fn hard_link_count(path : &Path) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Ok(metadata) = fs::metadata(path) {
+            return metadata.nlink();
+        }
+    }
+    1
+}
+
+// Where write_in_place keeps the new contents while it overwrites the file. It isn't named like a
+// temp file, so it isn't cleaned up by --resume if the write is interrupted.
+fn in_place_copy_path(path : &Path) -> io::Result<PathBuf> {
+    temp_path(path).map(|temp_path| temp_path.with_extension("new"))
+}
+
+// Overwrite the file itself, temporarily making it writable if it's read-only. This can't be
+// atomic, so the new contents are written to a copy next to it first. Running out of space then
+// fails before the file is touched, and if overwriting it fails midway the copy is kept.
+fn write_in_place(path : &Path, data : &[u8], options : &WriteOptions) -> io::Result<()> {
+    let original_metadata = fs::metadata(path)?;
+    let original_permissions = original_metadata.permissions();
+
+    let copy_path = in_place_copy_path(path)?;
+    let copied = OpenOptions::new().write(true).create_new(true).open(&copy_path)
+        .and_then(|mut copy| copy.write_all(data).and_then(|()| copy.sync_all()))
+        .and_then(|()| {
+            if !original_permissions.readonly() {
+                return Ok(());
+            }
+            let mut writable = original_permissions.clone();
+            #[allow(clippy::permissions_set_readonly_false)]
+            writable.set_readonly(false);
+            fs::set_permissions(path, writable)
+        });
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&copy_path);
+        return Err(e);
+    }
+
+    let result = (|| {
+        let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
+        file.write_all(data)?;
+        if options.preserve_times {
+            file.set_times(FileTimes::new()
+                .set_accessed(original_metadata.accessed()?)
+                .set_modified(original_metadata.modified()?))?;
+        }
+        file.sync_all()
+    })();
+
+    if original_permissions.readonly() {
+        fs::set_permissions(path, original_permissions)?;
+    }
+    match result {
+        Ok(()) => fs::remove_file(&copy_path),
+        Err(e) => Err(io::Error::new(e.kind(), format!("{}, the new contents are in {}", e, copy_path.display()))),
+    }
+}
+
+// Windows refuses to rename over a read-only file, so the attribute is cleared on the original
+// first. The temp file already carries the original permissions, so the rename restores it.
+fn replace_read_only(temp_path : &Path, path : &Path) -> io::Result<()> {
+    // Renaming only needs the folder to be writable on Unix
+    if !cfg!(windows) {
+        return fs::rename(temp_path, path);
+    }
+
+    let original_permissions = fs::metadata(path)?.permissions();
+    let mut writable = original_permissions.clone();
+    #[allow(clippy::permissions_set_readonly_false)]
+    writable.set_readonly(false);
+    fs::set_permissions(path, writable)?;
+
+    let result = fs::rename(temp_path, path);
+    if result.is_err() {
+        let _ = fs::set_permissions(path, original_permissions);
+    }
+    result
+}
+
+// Replace the file's contents by writing a temp file in the same folder and renaming it over the
+// original. If the process is killed midway, the original file is left untouched.
+//
+// Files with several hard links are overwritten in place instead, because the rename would
+// detach the file from its other links and leave them with the old data. That isn't atomic, see
+// write_in_place.
+pub fn write_atomic(path : &Path, data : &[u8], options : &WriteOptions) -> io::Result<()> {
+    let read_only = is_read_only(path);
+    if read_only && !options.force_readonly {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} is read-only", path.display())));
+    }
+
+    if hard_link_count(path) > 1 {
+        return write_in_place(path, data, options);
+    }
+
+    let temp_path = temp_path(path)?;
+
+    let result = write_temp_file(&temp_path, path, data, options).and_then(|()| {
+        if read_only {
+            replace_read_only(&temp_path, path)
+        } else {
+            fs::rename(&temp_path, path)
+        }
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use crate::{ScanSummary, SizeChange};
+
+fn format_kb(bytes : u64) -> String {
+    format!("{:.1}KB", bytes as f32 / 1000f32)
+}
+
+fn format_saved(size_change : &SizeChange) -> String {
+    format!("{:+.1}KB ({:.0}%)",
+            -size_change.saved() as f32 / 1000f32,
+            size_change.after as f32 / size_change.before as f32 * 100f32)
+}
+
+// Write a summary of the run as BBCode tables, for pasting into forum posts
+pub fn write_report(output_path : &Path, summary : &ScanSummary) -> io::Result<()> {
+    let mut out = File::create(output_path)?;
+
+    writeln!(out, "[b]PNG Header Scanner results[/b]")?;
+    writeln!(out, "Scanned {} PNGs, fixed {} indexed PNGs.", summary.num_scanned(), summary.num_fixed())?;
+
+    if summary.fixed.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out)?;
+    writeln!(out, "[table]")?;
+    writeln!(out, "[tr][th]File[/th][th]Before[/th][th]After[/th][th]Change[/th][/tr]")?;
+    for fixed_file in &summary.fixed {
+        writeln!(out, "[tr][td]{}[/td][td]{}[/td][td]{}[/td][td]{}[/td][/tr]",
+                 crate::slash_path(&fixed_file.rel_path),
+                 format_kb(fixed_file.size_change.before),
+                 format_kb(fixed_file.size_change.after),
+                 format_saved(&fixed_file.size_change))?;
+    }
+    let total = summary.total_size_change();
+    writeln!(out, "[tr][td][b]Total[/b][/td][td][b]{}[/b][/td][td][b]{}[/b][/td][td][b]{}[/b][/td][/tr]",
+             format_kb(total.before),
+             format_kb(total.after),
+             format_saved(&total))?;
+    writeln!(out, "[/table]")?;
+
+    Ok(())
+}
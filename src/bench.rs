@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+use log::{error, info, warn};
+use png_header_scanner::optimizer::{OptimizeSettings, Optimizer, Oxipng};
+use png_header_scanner::{parse_one, ParseResult};
+
+// Every oxipng level, then zopfli on top of the default level
+const LEVELS: [u8; 7] = [0, 1, 2, 3, 4, 5, 6];
+const ZOPFLI_LEVEL: u8 = 2;
+
+// Settings for the bench subcommand
+pub struct BenchOptions {
+    // How many of the PNGs under the folder to optimize with each preset
+    pub sample: usize,
+    // Zopfli is often 100 times slower than the other presets
+    pub zopfli: bool,
+}
+
+struct Preset {
+    name: String,
+    optimizer: Oxipng,
+}
+
+#[derive(Default)]
+struct PresetResult {
+    time: Duration,
+    size_before: u64,
+    size_after: u64,
+    num_failed: usize,
+}
+
+fn presets(options : &BenchOptions) -> Vec<Preset> {
+    let mut presets : Vec<Preset> = LEVELS.iter()
+        .map(|&level| Preset {
+            name: format!("--oxipng-level {}", level),
+            optimizer: Oxipng { level, ..Oxipng::default() },
+        })
+        .collect();
+    if options.zopfli {
+        presets.push(Preset {
+            name: format!("--oxipng-level {} --oxipng-zopfli", ZOPFLI_LEVEL),
+            optimizer: Oxipng { level: ZOPFLI_LEVEL, zopfli: true, ..Oxipng::default() },
+        });
+    }
+    presets
+}
+
+// The valid PNGs to benchmark with, a random selection of them if there are too many
+fn sample_paths(bench_path : &Path, sample : usize) -> Vec<PathBuf> {
+    let mut paths : Vec<PathBuf> = if bench_path.is_file() {
+        vec![bench_path.to_path_buf()]
+    } else {
+        WalkDir::new(bench_path).into_iter()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.into_path())
+            .filter(|path| path.is_file() && crate::is_png(path))
+            .collect()
+    };
+    paths.retain(|path| matches!(parse_one(path), ParseResult::Valid(_)));
+    crate::random_sample(&mut paths, sample);
+    paths
+}
+
+fn run_preset(preset : &Preset, images : &[(PathBuf, Vec<u8>)]) -> PresetResult {
+    let mut result = PresetResult::default();
+    for (path, data) in images {
+        let start = Instant::now();
+        let optimized = preset.optimizer.optimize(data, &OptimizeSettings::default());
+        result.time += start.elapsed();
+        match optimized {
+            Ok(optimized_data) => {
+                result.size_before += data.len() as u64;
+                // Nothing is written if it doesn't get smaller
+                result.size_after += data.len().min(optimized_data.len()) as u64;
+            },
+            Err(e) => {
+                warn!("Warning: {} failed with {}: {}", preset.name, path.display(), e);
+                result.num_failed += 1;
+            },
+        }
+    }
+    result
+}
+
+// Optimize a sample of the PNGs at bench_path with each oxipng preset, and print how long each
+// took and how much it saved. The files themselves aren't changed.
+pub fn bench(bench_path : &Path, options : &BenchOptions) {
+    let images : Vec<(PathBuf, Vec<u8>)> = sample_paths(bench_path, options.sample).into_iter()
+        .filter_map(|path| fs::read(&path).ok().map(|data| (path, data)))
+        .collect();
+    if images.is_empty() {
+        error!("No valid PNGs found in [{}]", bench_path.display());
+        return;
+    }
+    let total_size : usize = images.iter().map(|(_, data)| data.len()).sum();
+    info!("Benchmarking with {} PNGs, {:.1}KB in total", images.len(), total_size as f32 / 1000f32);
+
+    info!("  {:<36}{:>10}{:>12}{:>12}{:>8}", "Preset", "Time", "Per image", "Size", "Saved");
+    for preset in presets(options) {
+        let result = run_preset(&preset, &images);
+        let per_image = result.time / images.len() as u32;
+        let saved = if result.size_before > 0 {
+            (result.size_before - result.size_after) as f32 / result.size_before as f32 * 100f32
+        } else {
+            0f32
+        };
+        let failed = if result.num_failed > 0 {
+            format!(" ({} of {} failed)", result.num_failed, images.len())
+        } else {
+            String::new()
+        };
+        info!("  {:<36}{:>9.2}s{:>10}ms{:>10.1}KB{:>7.1}%{}",
+              preset.name,
+              result.time.as_secs_f32(),
+              per_image.as_millis(),
+              result.size_after as f32 / 1000f32,
+              saved,
+              failed);
+    }
+}
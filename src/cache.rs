@@ -0,0 +1,158 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use png_header_scanner::{PixelFormat, PngHeader};
+
+// Kept at the root of the scanned folder, so it moves along with the files it describes
+pub const CACHE_FILE_NAME: &str = ".png_header_scanner_cache.json";
+
+// Size and modification time, either of which changes when a file is rewritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+pub fn file_stamp(path : &Path) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(FileStamp {
+        size: metadata.len(),
+        modified_secs: modified.as_secs(),
+        modified_nanos: modified.subsec_nanos(),
+    })
+}
+
+struct CacheEntry {
+    stamp: FileStamp,
+    header: PngHeader,
+}
+
+// Files which had nothing to report or fix when they were last scanned with the same settings.
+// Re-scanning them is skipped as long as they keep the same size and modification time.
+pub struct Cache {
+    path: PathBuf,
+    // Anything which could change the results of a scan, so a cache from other settings isn't used
+    settings: String,
+    previous: HashMap<String, CacheEntry>,
+    // Only clean files which still exist are written back
+    current: HashMap<String, CacheEntry>,
+}
+
+fn color_type(pixel_format : PixelFormat) -> u8 {
+    match pixel_format {
+        PixelFormat::Greyscale => 0,
+        PixelFormat::TrueColor => 2,
+        PixelFormat::IndexedColor => 3,
+        PixelFormat::GreyscaleWithAlpha => 4,
+        PixelFormat::TrueColorWithAlpha => 6,
+    }
+}
+
+// Stored as [size, modified_secs, modified_nanos, width, height, bit_depth, color_type, interlaced]
+fn entry_to_json(entry : &CacheEntry) -> Value {
+    json!([
+        entry.stamp.size,
+        entry.stamp.modified_secs,
+        entry.stamp.modified_nanos,
+        entry.header.width,
+        entry.header.height,
+        entry.header.bit_depth,
+        color_type(entry.header.pixel_format),
+        entry.header.interlaced,
+    ])
+}
+
+fn entry_from_json(value : &Value) -> Option<CacheEntry> {
+    let fields = value.as_array()?;
+    if fields.len() != 8 {
+        return None;
+    }
+    let number = |index : usize| fields[index].as_u64();
+
+    Some(CacheEntry {
+        stamp: FileStamp {
+            size: number(0)?,
+            modified_secs: number(1)?,
+            modified_nanos: number(2)? as u32,
+        },
+        header: PngHeader {
+            width: number(3)? as u32,
+            height: number(4)? as u32,
+            bit_depth: number(5)? as u8,
+            pixel_format: crate::pixel_format_from_color_type(number(6)? as u8)?,
+            interlaced: fields[7].as_bool()?,
+        },
+    })
+}
+
+fn load_entries(path : &Path, settings : &str) -> Option<HashMap<String, CacheEntry>> {
+    let cache : Value = serde_json::from_reader(File::open(path).ok()?).ok()?;
+    if cache.get("settings")?.as_str()? != settings {
+        return None;
+    }
+
+    let files = cache.get("files")?.as_object()?;
+    Some(files.iter()
+        .filter_map(|(rel_path, entry)| Some((rel_path.clone(), entry_from_json(entry)?)))
+        .collect())
+}
+
+impl Cache {
+    // A missing or unreadable cache, or one from different settings, just starts out empty
+    pub fn load(scan_path : &Path, settings : String) -> Cache {
+        let path = scan_path.join(CACHE_FILE_NAME);
+        let previous = load_entries(&path, &settings).unwrap_or_default();
+        Cache { path, settings, previous, current: HashMap::new() }
+    }
+
+    pub fn clear(scan_path : &Path) -> io::Result<()> {
+        match fs::remove_file(scan_path.join(CACHE_FILE_NAME)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    // Header of the file if it was clean last time and hasn't changed since
+    pub fn lookup(&self, rel_path : &Path, stamp : FileStamp) -> Option<PngHeader> {
+        self.previous.get(&crate::slash_path(rel_path))
+            .filter(|entry| entry.stamp == stamp)
+            .map(|entry| entry.header)
+    }
+
+    pub fn insert(&mut self, rel_path : &Path, stamp : FileStamp, header : PngHeader) {
+        self.current.insert(crate::slash_path(rel_path), CacheEntry { stamp, header });
+    }
+
+    // Keep the entry of a file which wasn't scanned this time
+    pub fn carry_over(&mut self, rel_path : &Path) {
+        let key = crate::slash_path(rel_path);
+        if let Some(entry) = self.previous.remove(&key) {
+            self.current.insert(key, entry);
+        }
+    }
+
+    // Use what this scan found as the starting point for the next one, for --daemon which scans
+    // the same folder again without reloading the cache
+    #[cfg(unix)]
+    pub fn start_next_scan(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let files : serde_json::Map<String, Value> = self.current.iter()
+            .map(|(rel_path, entry)| (rel_path.clone(), entry_to_json(entry)))
+            .collect();
+        let cache = json!({
+            "settings": self.settings,
+            "files": files,
+        });
+
+        serde_json::to_writer(File::create(&self.path)?, &cache)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{self, ErrorKind};
+use crate::chunks;
+use crate::{PixelFormat, PngHeader, EXPECTED_PNG_HEADER};
+
+// Apple's Xcode "optimizes" PNGs for iOS into a private variant, marked by a CgBI chunk before
+// IHDR. The image data is a raw deflate stream without the zlib header and checksum, and the
+// pixels are stored as BGRA with premultiplied alpha, so standard decoders reject them.
+
+fn invalid(message : &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn paeth(a : u8, b : u8, c : u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let pa = (p - i16::from(a)).abs();
+    let pb = (p - i16::from(b)).abs();
+    let pc = (p - i16::from(c)).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Undo the per-row filters, leaving just the pixel bytes
+fn unfilter(filtered : &[u8], stride : usize, bpp : usize, height : usize) -> io::Result<Vec<u8>> {
+    if filtered.len() != (stride + 1) * height {
+        return Err(invalid("image data has the wrong size"));
+    }
+
+    let mut pixels = vec![0u8; stride * height];
+    for row in 0..height {
+        let filter = filtered[row * (stride + 1)];
+        let line = &filtered[row * (stride + 1) + 1..(row + 1) * (stride + 1)];
+        let (previous_rows, current) = pixels.split_at_mut(row * stride);
+        let current = &mut current[..stride];
+        let previous = if row > 0 { Some(&previous_rows[(row - 1) * stride..]) } else { None };
+
+        for x in 0..stride {
+            let a = if x >= bpp { current[x - bpp] } else { 0 };
+            let b = previous.map_or(0, |previous| previous[x]);
+            let c = if x >= bpp { previous.map_or(0, |previous| previous[x - bpp]) } else { 0 };
+            current[x] = match filter {
+                0 => line[x],
+                1 => line[x].wrapping_add(a),
+                2 => line[x].wrapping_add(b),
+                3 => line[x].wrapping_add(((u16::from(a) + u16::from(b)) / 2) as u8),
+                4 => line[x].wrapping_add(paeth(a, b, c)),
+                _ => return Err(invalid("unknown filter type")),
+            };
+        }
+    }
+
+    Ok(pixels)
+}
+
+// Swap BGR(A) to RGB(A), and undo the alpha premultiplication
+fn convert_pixels(pixels : &mut [u8], bpp : usize) {
+    for pixel in pixels.chunks_mut(bpp) {
+        pixel.swap(0, 2);
+
+        if bpp == 4 {
+            let alpha = u32::from(pixel[3]);
+            if alpha > 0 && alpha < 255 {
+                for channel in &mut pixel[..3] {
+                    *channel = ((u32::from(*channel) * 255 + alpha / 2) / alpha).min(255) as u8;
+                }
+            }
+        }
+    }
+}
+
+// Convert a CgBI PNG back into a standard PNG. The other ancillary chunks are kept as they are.
+pub fn repair(data : &[u8], header : &PngHeader) -> io::Result<Vec<u8>> {
+    let bpp = match (header.pixel_format, header.bit_depth) {
+        (PixelFormat::TrueColor, 8) => 3,
+        (PixelFormat::TrueColorWithAlpha, 8) => 4,
+        _ => return Err(invalid("only 8-bit RGB/RGBA CgBI images are supported")),
+    };
+    if header.interlaced {
+        return Err(invalid("interlaced CgBI images are not supported"));
+    }
+    if header.width == 0 || header.height == 0 {
+        return Err(invalid("image is empty"));
+    }
+
+    let chunks = chunks::split_chunks(data)?;
+
+    let mut compressed = Vec::new();
+    for chunk in chunks.iter().filter(|chunk| &chunk.chunk_type == b"IDAT") {
+        compressed.extend_from_slice(chunk.data);
+    }
+
+    let filtered = miniz_oxide::inflate::decompress_to_vec(&compressed)
+        .map_err(|_e| invalid("image data is not a valid deflate stream"))?;
+
+    let width = header.width as usize;
+    let height = header.height as usize;
+    let stride = width * bpp;
+    let mut pixels = unfilter(&filtered, stride, bpp, height)?;
+    convert_pixels(&mut pixels, bpp);
+
+    // Write the rows unfiltered, the image is optimized again later anyway
+    let mut raw = Vec::with_capacity((stride + 1) * height);
+    for row in pixels.chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let image_data = miniz_oxide::deflate::compress_to_vec_zlib(&raw, 6);
+
+    let mut output = EXPECTED_PNG_HEADER.to_vec();
+    let mut wrote_image_data = false;
+    for chunk in &chunks {
+        match &chunk.chunk_type {
+            // iDOT is another Apple chunk, holding offsets into the old image data
+            b"CgBI" | b"iDOT" => {},
+            b"IDAT" => {
+                if !wrote_image_data {
+                    chunks::write_chunk(&mut output, b"IDAT", &image_data);
+                    wrote_image_data = true;
+                }
+            },
+            _ => output.extend_from_slice(chunk.bytes),
+        }
+    }
+
+    Ok(output)
+}
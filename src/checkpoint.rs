@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const CHECKPOINT_FILE_NAME: &str = ".png_header_scanner_checkpoint";
+
+// Records every file as soon as it's completely handled, so an interrupted run can be resumed.
+// A file which was being fixed when the run stopped was never recorded, so it's handled again.
+pub struct Checkpoint {
+    path: PathBuf,
+    // One relative path per line, appended as files finish in any order
+    file: Mutex<File>,
+    done: HashSet<String>,
+    resumed: bool,
+}
+
+fn read_done_paths(path : &Path) -> io::Result<HashSet<String>> {
+    match File::open(path) {
+        Ok(file) => BufReader::new(file).lines().collect(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+impl Checkpoint {
+    // Start recording, keeping the progress of the last run if resuming
+    pub fn start(scan_path : &Path, resume : bool) -> io::Result<Checkpoint> {
+        let path = scan_path.join(CHECKPOINT_FILE_NAME);
+        let done = if resume { read_done_paths(&path)? } else { HashSet::new() };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(&path)?;
+        Ok(Checkpoint { path, file: Mutex::new(file), done, resumed: resume })
+    }
+
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    pub fn num_done(&self) -> usize {
+        self.done.len()
+    }
+
+    // Whether the last run already finished the file
+    pub fn is_done(&self, rel_path : &Path) -> bool {
+        self.done.contains(&crate::slash_path(rel_path))
+    }
+
+    pub fn record(&self, rel_path : &Path) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", crate::slash_path(rel_path))?;
+        file.flush()
+    }
+
+    // The run completed, so there's nothing left to resume
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)
+    }
+}
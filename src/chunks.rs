@@ -0,0 +1,520 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// The ancillary chunks which decide how the pixel data has to be interpreted
+#[derive(Debug, Default)]
+pub struct ChunkSummary {
+    pub palette: Option<Vec<u8>>,
+    pub transparency: Option<Vec<u8>>,
+    // Has an acTL chunk, so it's an APNG
+    pub animated: bool,
+    pub physical: Option<PhysicalDimensions>,
+    // The gAMA value, the gamma times 100000
+    pub gamma: Option<u32>,
+    // The sRGB chunk's rendering intent
+    pub srgb_intent: Option<u8>,
+    pub has_icc_profile: bool,
+}
+
+impl ChunkSummary {
+    pub fn palette_entries(&self) -> Option<usize> {
+        self.palette.as_ref().map(|palette| palette.len() / 3)
+    }
+
+    // For indexed images tRNS holds per-entry alpha, for greyscale/truecolor it holds a color key
+    pub fn has_transparency(&self) -> bool {
+        self.transparency.is_some()
+    }
+
+    // gAMA, sRGB and iCCP chunks which viewers would disagree about, the first one found
+    pub fn color_space_problem(&self) -> Option<ColorSpaceProblem> {
+        if let Some(intent) = self.srgb_intent {
+            if intent > MAX_RENDERING_INTENT {
+                return Some(ColorSpaceProblem::BogusRenderingIntent(intent));
+            }
+            if self.has_icc_profile {
+                return Some(ColorSpaceProblem::IccProfileWithSrgb);
+            }
+        }
+        match self.gamma {
+            Some(gamma) if self.srgb_intent.is_some() && gamma != SRGB_GAMMA => {
+                Some(ColorSpaceProblem::GammaConflictsWithSrgb(gamma))
+            },
+            Some(gamma) if !PLAUSIBLE_GAMMA.contains(&gamma) => Some(ColorSpaceProblem::BogusGamma(gamma)),
+            _ => None,
+        }
+    }
+
+    // The rendering intent to keep when the color space chunks are replaced by a single sRGB chunk.
+    // Perceptual if there's no valid one.
+    pub fn normalized_srgb_intent(&self) -> u8 {
+        self.srgb_intent.filter(|&intent| intent <= MAX_RENDERING_INTENT).unwrap_or(0)
+    }
+
+    // Look at the PLTE and tRNS entries, only the ones marked in used if it's given
+    pub fn palette_report(&self, used : Option<&[bool]>) -> Option<PaletteReport> {
+        let palette = self.palette.as_ref()?;
+        let alphas = self.transparency.as_deref().unwrap_or_default();
+        // Entries without a tRNS value are opaque
+        let entries : Vec<[u8; 4]> = palette.chunks_exact(3)
+            .enumerate()
+            .map(|(index, rgb)| [rgb[0], rgb[1], rgb[2], alphas.get(index).copied().unwrap_or(255)])
+            .collect();
+        let is_used = |index : usize| used.is_none_or(|used| used.get(index).copied().unwrap_or(false));
+
+        let used_entries : Vec<&[u8; 4]> = entries.iter()
+            .enumerate()
+            .filter(|&(index, _)| is_used(index))
+            .map(|(_, entry)| entry)
+            .collect();
+        let duplicate_entries = entries.iter()
+            .enumerate()
+            .filter(|&(index, entry)| entries[..index].contains(entry))
+            .count();
+        Some(PaletteReport {
+            entries: entries.len(),
+            used_entries: used.map(|_| used_entries.len()),
+            duplicate_entries,
+            greyscale: used_entries.iter().all(|entry| entry[0] == entry[1] && entry[1] == entry[2]),
+            transparent: used_entries.iter().any(|entry| entry[3] != 255),
+        })
+    }
+}
+
+// What --palette-report says about an indexed image's palette
+#[derive(Debug, Clone, Copy)]
+pub struct PaletteReport {
+    pub entries: usize,
+    // Entries used by at least one pixel. None if the image data wasn't decoded.
+    pub used_entries: Option<usize>,
+    // Entries with the same color and alpha as an earlier entry
+    pub duplicate_entries: usize,
+    // Every used entry is grey, so the image could be stored as greyscale without any loss
+    pub greyscale: bool,
+    // Some used entry isn't opaque, so it would have to be greyscale with alpha
+    pub transparent: bool,
+}
+
+// The pHYs chunk: how many pixels fit in a unit each way. Without a unit it only gives the shape
+// of the pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalDimensions {
+    pub pixels_per_unit_x: u32,
+    pub pixels_per_unit_y: u32,
+    pub in_meters: bool,
+}
+
+const PHYS_LENGTH: u32 = 9;
+const METERS_PER_INCH: f64 = 0.0254;
+
+impl PhysicalDimensions {
+    pub fn parse(mut data : &[u8]) -> Option<PhysicalDimensions> {
+        let pixels_per_unit_x = data.read_u32::<BigEndian>().ok()?;
+        let pixels_per_unit_y = data.read_u32::<BigEndian>().ok()?;
+        let in_meters = data.read_u8().ok()? == 1;
+        Some(PhysicalDimensions { pixels_per_unit_x, pixels_per_unit_y, in_meters })
+    }
+
+    // pHYs is always in pixels per meter, so 72 DPI is stored as 2835
+    pub fn from_dpi(dpi : u32) -> PhysicalDimensions {
+        let pixels_per_meter = (f64::from(dpi) / METERS_PER_INCH).round() as u32;
+        PhysicalDimensions { pixels_per_unit_x: pixels_per_meter, pixels_per_unit_y: pixels_per_meter, in_meters: true }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PHYS_LENGTH as usize);
+        data.write_u32::<BigEndian>(self.pixels_per_unit_x).unwrap();
+        data.write_u32::<BigEndian>(self.pixels_per_unit_y).unwrap();
+        data.push(u8::from(self.in_meters));
+        data
+    }
+
+    // Horizontal and vertical DPI, if the unit is known
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        if self.in_meters {
+            let dpi = |pixels_per_meter : u32| f64::from(pixels_per_meter) * METERS_PER_INCH;
+            Some((dpi(self.pixels_per_unit_x), dpi(self.pixels_per_unit_y)))
+        } else {
+            None
+        }
+    }
+
+    // Width of a pixel divided by its height. None if either is 0, which the spec doesn't allow.
+    pub fn pixel_aspect_ratio(&self) -> Option<f64> {
+        if self.pixels_per_unit_x == 0 || self.pixels_per_unit_y == 0 {
+            return None;
+        }
+        Some(f64::from(self.pixels_per_unit_y) / f64::from(self.pixels_per_unit_x))
+    }
+
+    pub fn is_square(&self) -> bool {
+        self.pixels_per_unit_x == self.pixels_per_unit_y
+    }
+}
+
+// The gAMA value an sRGB image must have if it has one, 1/2.2
+const SRGB_GAMMA: u32 = 45455;
+
+// From 0.1 to linear. Anything over 1 is most likely the display gamma written by mistake, like
+// 220000 instead of 45455.
+const PLAUSIBLE_GAMMA: std::ops::RangeInclusive<u32> = 10_000..=100_000;
+
+// Perceptual, relative colorimetric, saturation and absolute colorimetric
+const MAX_RENDERING_INTENT: u8 = 3;
+
+const GAMA_LENGTH: u32 = 4;
+const SRGB_LENGTH: u32 = 1;
+
+// Why the color space chunks of an image would be rendered differently by different viewers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpaceProblem {
+    // An sRGB image must have a gAMA of SRGB_GAMMA, if it has one at all
+    GammaConflictsWithSrgb(u32),
+    // The spec doesn't allow both, and viewers pick either one
+    IccProfileWithSrgb,
+    // A gAMA of 0 or outside PLAUSIBLE_GAMMA
+    BogusGamma(u32),
+    BogusRenderingIntent(u8),
+}
+
+fn gamma_value(gamma : u32) -> f64 {
+    f64::from(gamma) / 100_000f64
+}
+
+impl fmt::Display for ColorSpaceProblem {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColorSpaceProblem::GammaConflictsWithSrgb(gamma) => {
+                write!(f, "has an sRGB chunk, but a gamma of {:.5} instead of {:.5}",
+                       gamma_value(*gamma), gamma_value(SRGB_GAMMA))
+            },
+            ColorSpaceProblem::IccProfileWithSrgb => write!(f, "has both an sRGB chunk and an ICC profile"),
+            ColorSpaceProblem::BogusGamma(gamma) => write!(f, "has a bogus gamma of {:.5}", gamma_value(*gamma)),
+            ColorSpaceProblem::BogusRenderingIntent(intent) => {
+                write!(f, "has an unknown sRGB rendering intent {}", intent)
+            },
+        }
+    }
+}
+
+// Largest valid PLTE (256 RGB entries) and tRNS (256 alpha entries) chunks
+const MAX_PLTE_LENGTH: u32 = 256 * 3;
+const MAX_TRNS_LENGTH: u32 = 256;
+
+// The length comes from the file, so check it before allocating anything
+fn read_chunk_data<R: Read>(reader : &mut R, length : u32, max_length : u32) -> io::Result<Vec<u8>> {
+    if length > max_length {
+        return Err(io::Error::new(ErrorKind::InvalidData, "chunk is larger than the PNG spec allows"));
+    }
+
+    let mut data = vec![0; length as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// Walk the chunks before the image data, keeping PLTE and tRNS and noting acTL.
+// No image data is read or decoded.
+pub fn read_chunks<R: Read + Seek>(reader : &mut R) -> io::Result<ChunkSummary> {
+    let mut summary = ChunkSummary::default();
+
+    //skip the png header
+    reader.seek(SeekFrom::Start(8))?;
+
+    loop {
+        let length = reader.read_u32::<BigEndian>()?;
+        let mut chunk_type : [u8; 4] = [0; 4];
+        reader.read_exact(&mut chunk_type)?;
+
+        match &chunk_type {
+            b"PLTE" => summary.palette = Some(read_chunk_data(reader, length, MAX_PLTE_LENGTH)?),
+            b"tRNS" => summary.transparency = Some(read_chunk_data(reader, length, MAX_TRNS_LENGTH)?),
+            b"acTL" => {
+                summary.animated = true;
+                reader.seek(SeekFrom::Current(i64::from(length)))?;
+            },
+            // A pHYs chunk of the wrong size is ignored, like decoders do
+            b"pHYs" if length == PHYS_LENGTH => {
+                summary.physical = PhysicalDimensions::parse(&read_chunk_data(reader, length, PHYS_LENGTH)?);
+            },
+            b"gAMA" if length == GAMA_LENGTH => summary.gamma = Some(reader.read_u32::<BigEndian>()?),
+            b"sRGB" if length == SRGB_LENGTH => summary.srgb_intent = Some(reader.read_u8()?),
+            b"iCCP" => {
+                summary.has_icc_profile = true;
+                reader.seek(SeekFrom::Current(i64::from(length)))?;
+            },
+            // PLTE, tRNS, acTL, pHYs, gAMA, sRGB and iCCP must all come before the first IDAT
+            b"IDAT" | b"IEND" => return Ok(summary),
+            _ => { reader.seek(SeekFrom::Current(i64::from(length)))?; },
+        }
+
+        //skip crc
+        reader.seek(SeekFrom::Current(4))?;
+    }
+}
+
+pub fn read_chunk_summary(filename : &Path) -> io::Result<ChunkSummary> {
+    read_chunks(&mut File::open(filename)?)
+}
+
+// A chunk anywhere in the file, with the keyword of text chunks
+#[derive(Debug, Clone)]
+pub struct ChunkInfo {
+    pub chunk_type: [u8; 4],
+    // Length of the chunk data
+    pub length: u32,
+    pub keyword: Option<String>,
+}
+
+// Keywords are 1-79 bytes followed by a null separator
+const MAX_KEYWORD_LENGTH: u32 = 80;
+
+// tEXt, zTXt and iTXt all start with a null terminated keyword
+fn is_text_chunk(chunk_type : &[u8; 4]) -> bool {
+    matches!(chunk_type, b"tEXt" | b"zTXt" | b"iTXt")
+}
+
+fn parse_keyword(data : &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+// Walk every chunk up to IEND, including the ones after the image data. Only the start of
+// text chunks is read, everything else is skipped over.
+pub fn list_chunks<R: Read + Seek>(reader : &mut R) -> io::Result<Vec<ChunkInfo>> {
+    let mut chunks = Vec::new();
+
+    //skip the png header
+    reader.seek(SeekFrom::Start(8))?;
+
+    loop {
+        let length = reader.read_u32::<BigEndian>()?;
+        let mut chunk_type : [u8; 4] = [0; 4];
+        reader.read_exact(&mut chunk_type)?;
+
+        let keyword = if is_text_chunk(&chunk_type) {
+            let keyword_length = length.min(MAX_KEYWORD_LENGTH);
+            let keyword_data = read_chunk_data(reader, keyword_length, MAX_KEYWORD_LENGTH)?;
+            reader.seek(SeekFrom::Current(i64::from(length - keyword_length)))?;
+            Some(parse_keyword(&keyword_data))
+        } else {
+            reader.seek(SeekFrom::Current(i64::from(length)))?;
+            None
+        };
+
+        chunks.push(ChunkInfo { chunk_type, length, keyword });
+
+        if &chunk_type == b"IEND" {
+            return Ok(chunks);
+        }
+
+        //skip crc
+        reader.seek(SeekFrom::Current(4))?;
+    }
+}
+
+pub fn read_chunk_list(filename : &Path) -> io::Result<Vec<ChunkInfo>> {
+    list_chunks(&mut File::open(filename)?)
+}
+
+// Number of bytes after the end of the IEND chunk. Only the chunk headers are read.
+pub fn trailing_data_length<R: Read + Seek>(reader : &mut R) -> io::Result<u64> {
+    //skip the png header
+    reader.seek(SeekFrom::Start(8))?;
+
+    loop {
+        let length = reader.read_u32::<BigEndian>()?;
+        let mut chunk_type : [u8; 4] = [0; 4];
+        reader.read_exact(&mut chunk_type)?;
+
+        //skip data and crc
+        let end = reader.seek(SeekFrom::Current(i64::from(length) + 4))?;
+
+        if &chunk_type == b"IEND" {
+            let file_length = reader.seek(SeekFrom::End(0))?;
+            if file_length < end {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "IEND chunk is truncated"));
+            }
+            return Ok(file_length - end);
+        }
+    }
+}
+
+pub fn read_trailing_data_length(filename : &Path) -> io::Result<u64> {
+    trailing_data_length(&mut File::open(filename)?)
+}
+
+// A chunk of a PNG held in memory
+pub struct RawChunk<'a> {
+    pub chunk_type: [u8; 4],
+    pub data: &'a [u8],
+    // The whole chunk: length, type, data and crc
+    pub bytes: &'a [u8],
+}
+
+fn truncated_chunk() -> io::Error {
+    io::Error::new(ErrorKind::UnexpectedEof, "chunk runs past the end of the file")
+}
+
+// Walks the chunks of a whole PNG file held in memory, without checking the CRCs. Stops after
+// IEND, so data appended to the file isn't read as chunks.
+pub struct RawChunks<'a> {
+    data: &'a [u8],
+    offset: usize,
+    finished: bool,
+}
+
+impl<'a> RawChunks<'a> {
+    pub fn new(data : &'a [u8]) -> io::Result<RawChunks<'a>> {
+        if data.len() < 8 {
+            return Err(truncated_chunk());
+        }
+        Ok(RawChunks { data, offset: 8, finished: false })
+    }
+
+    // Whatever comes after IEND, once the walk has reached it
+    pub fn trailing_data(&self) -> &'a [u8] {
+        &self.data[self.offset..]
+    }
+
+    fn read_chunk(&self) -> io::Result<RawChunk<'a>> {
+        let mut header = self.data.get(self.offset..self.offset + 8).ok_or_else(truncated_chunk)?;
+        let length = header.read_u32::<BigEndian>()? as usize;
+        let mut chunk_type : [u8; 4] = [0; 4];
+        header.read_exact(&mut chunk_type)?;
+
+        // length + type + data + crc
+        let bytes = self.data.get(self.offset..self.offset + 12 + length).ok_or_else(truncated_chunk)?;
+        Ok(RawChunk { chunk_type, data: &bytes[8..8 + length], bytes })
+    }
+}
+
+impl<'a> Iterator for RawChunks<'a> {
+    type Item = io::Result<RawChunk<'a>>;
+
+    fn next(&mut self) -> Option<io::Result<RawChunk<'a>>> {
+        if self.finished || self.offset >= self.data.len() {
+            return None;
+        }
+        let chunk = self.read_chunk();
+        match &chunk {
+            Ok(raw_chunk) => {
+                self.offset += raw_chunk.bytes.len();
+                self.finished = &raw_chunk.chunk_type == b"IEND";
+            },
+            Err(_e) => self.finished = true,
+        }
+        Some(chunk)
+    }
+}
+
+// Split a whole PNG file into its chunks up to IEND, without checking the CRCs
+pub fn split_chunks(data : &[u8]) -> io::Result<Vec<RawChunk<'_>>> {
+    RawChunks::new(data)?.collect()
+}
+
+// Copy a whole PNG file, leaving out every chunk for which keep returns false. The kept chunks
+// are copied byte for byte, including their CRCs, and anything after IEND is kept as it is.
+pub fn filter_chunks<F>(data : &[u8], keep : F) -> io::Result<Vec<u8>> where F: Fn(&ChunkInfo) -> bool {
+    let mut chunks = RawChunks::new(data)?;
+    let mut output = data[..8].to_vec();
+
+    for chunk in chunks.by_ref() {
+        let chunk = chunk?;
+        let keyword = if is_text_chunk(&chunk.chunk_type) {
+            Some(parse_keyword(chunk.data))
+        } else {
+            None
+        };
+
+        if keep(&ChunkInfo { chunk_type: chunk.chunk_type, length: chunk.data.len() as u32, keyword }) {
+            output.extend_from_slice(chunk.bytes);
+        }
+    }
+
+    output.extend_from_slice(chunks.trailing_data());
+    Ok(output)
+}
+
+// Append a chunk with its length and crc
+pub fn write_chunk(output : &mut Vec<u8>, chunk_type : &[u8; 4], data : &[u8]) {
+    let mut crc_data = chunk_type.to_vec();
+    crc_data.extend_from_slice(data);
+
+    output.write_u32::<BigEndian>(data.len() as u32).unwrap();
+    output.extend_from_slice(chunk_type);
+    output.extend_from_slice(data);
+    output.write_u32::<BigEndian>(crc::crc32::checksum_ieee(&crc_data)).unwrap();
+}
+
+// Copy a whole PNG file, replacing every chunk of this type with a single one holding data, or
+// removing them if data is None. The new chunk goes straight after IHDR, which is early enough
+// for any chunk that has to come before PLTE or IDAT. Anything after IEND is kept as it is.
+pub fn replace_chunk(data : &[u8], chunk_type : &[u8; 4], chunk_data : Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let mut chunks = RawChunks::new(data)?;
+    let mut output = data[..8].to_vec();
+
+    for chunk in chunks.by_ref() {
+        let chunk = chunk?;
+        if &chunk.chunk_type == chunk_type {
+            continue;
+        }
+        output.extend_from_slice(chunk.bytes);
+        if &chunk.chunk_type == b"IHDR" {
+            if let Some(chunk_data) = chunk_data {
+                write_chunk(&mut output, chunk_type, chunk_data);
+            }
+        }
+    }
+
+    output.extend_from_slice(chunks.trailing_data());
+    Ok(output)
+}
+
+// Copy a whole PNG file, declaring it as sRGB with a single sRGB chunk and nothing else which
+// could contradict it
+pub fn normalize_srgb(data : &[u8], intent : u8) -> io::Result<Vec<u8>> {
+    let without_others = filter_chunks(data, |chunk| !matches!(&chunk.chunk_type, b"gAMA" | b"cHRM" | b"iCCP"))?;
+    replace_chunk(&without_others, b"sRGB", Some(&[intent]))
+}
+
+// Chunks which have to stay where they are: the critical chunks, and APNG's frame chunks, whose
+// order makes up the animation
+fn has_fixed_position(chunk_type : &[u8; 4]) -> bool {
+    chunk_type[0].is_ascii_uppercase() || matches!(chunk_type, b"acTL" | b"fcTL" | b"fdAT")
+}
+
+// Copy a whole PNG file without its tIME chunks, and with the ancillary chunks between each pair
+// of critical chunks sorted by type, so the same image always gives the same bytes. Chunks of the
+// same type, like several tEXt chunks, keep their order. Anything after IEND is kept as it is.
+pub fn canonicalize_chunks(data : &[u8]) -> io::Result<Vec<u8>> {
+    let mut chunks = RawChunks::new(data)?;
+    let mut output = data[..8].to_vec();
+    let mut ancillary : Vec<RawChunk<'_>> = Vec::new();
+
+    for chunk in chunks.by_ref() {
+        let chunk = chunk?;
+        if &chunk.chunk_type == b"tIME" {
+            continue;
+        }
+        if !has_fixed_position(&chunk.chunk_type) {
+            ancillary.push(chunk);
+            continue;
+        }
+        ancillary.sort_by_key(|ancillary_chunk| ancillary_chunk.chunk_type);
+        for ancillary_chunk in ancillary.drain(..) {
+            output.extend_from_slice(ancillary_chunk.bytes);
+        }
+        output.extend_from_slice(chunk.bytes);
+    }
+
+    // Only left over if there's no IEND
+    ancillary.sort_by_key(|ancillary_chunk| ancillary_chunk.chunk_type);
+    for ancillary_chunk in ancillary {
+        output.extend_from_slice(ancillary_chunk.bytes);
+    }
+    output.extend_from_slice(chunks.trailing_data());
+    Ok(output)
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+// Looked for in the current folder and every folder above it, unless --config is given
+pub const CONFIG_FILE_NAME: &str = "pngscanner.toml";
+
+// The nearest config file in start_path or a folder above it
+pub fn find_config(start_path : &Path) -> Option<PathBuf> {
+    start_path.ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|config_path| config_path.is_file())
+}
+
+// A setting as it would be written on the command line
+fn setting_args(name : &str, value : &Value) -> Result<Vec<OsString>, String> {
+    let arg = |value : &dyn std::fmt::Display| OsString::from(format!("--{}={}", name, value));
+    match value {
+        // A flag can't be turned off again, so false just leaves it out
+        Value::Boolean(true) => Ok(vec![OsString::from(format!("--{}", name))]),
+        Value::Boolean(false) => Ok(Vec::new()),
+        Value::String(value) => Ok(vec![arg(value)]),
+        Value::Integer(value) => Ok(vec![arg(value)]),
+        Value::Float(value) => Ok(vec![arg(value)]),
+        // Options taking several values, like match
+        Value::Array(values) => values.iter()
+            .map(|value| match value {
+                Value::String(value) => Ok(arg(value)),
+                Value::Integer(value) => Ok(arg(value)),
+                _ => Err(format!("{} must be a list of strings or numbers", name)),
+            })
+            .collect(),
+        _ => Err(format!("{} must be a string, number, boolean or list", name)),
+    }
+}
+
+fn table_settings<'a>(table : &'a Value, section : &str) -> Result<BTreeMap<&'a str, &'a Value>, String> {
+    let table = table.as_table().ok_or_else(|| format!("{} must be a table", section))?;
+    Ok(table.iter().map(|(name, value)| (name.as_str(), value)).collect())
+}
+
+// Settings from a pngscanner.toml file, named like the long options, e.g.
+//
+// jobs = 4
+// match = ["color-type=3,0", "bit-depth=1,2,4"]
+//
+// [profile.release]
+// oxipng-level = 6
+// oxipng-zopfli = true
+//
+// [profile.dev]
+// oxipng-level = 0
+//
+// The top-level settings always apply, and the --profile section's settings replace them.
+// Options given on the command line take priority over both.
+//
+// Returns the settings as command line arguments, leaving out the ones is_given returns true for.
+pub fn load_config<F>(path : &Path, profile : Option<&str>, is_given : F) -> Result<Vec<OsString>, String>
+    where F: Fn(&str) -> bool {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let config : Value = text.parse().map_err(|e : toml::de::Error| e.to_string())?;
+
+    let mut settings = table_settings(&config, "the config file")?;
+    let profiles = settings.remove("profile");
+    if let Some((name, _)) = settings.iter().find(|(_, value)| value.as_table().is_some()) {
+        return Err(format!("unknown section [{}], profiles are written [profile.NAME]", name));
+    }
+
+    if let Some(profile) = profile {
+        let profile_settings = profiles
+            .and_then(|profiles| profiles.get(profile))
+            .ok_or_else(|| format!("there's no [profile.{}] section", profile))?;
+        settings.extend(table_settings(profile_settings, &format!("[profile.{}]", profile))?);
+    }
+
+    let mut args = Vec::new();
+    for (name, value) in settings {
+        if !is_given(name) {
+            args.extend(setting_args(name, value)?);
+        }
+    }
+    Ok(args)
+}
@@ -0,0 +1,308 @@
+use log::{info, warn};
+use rayon::ThreadPool;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::cache::Cache;
+use crate::{lock, ScanOptions};
+
+// How often the idle daemon checks whether it was asked to stop with Ctrl-C
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Standard JSON-RPC 2.0 error codes, and one of the implementation defined ones for a request
+// which was understood but couldn't be done
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code : i64, message : impl Into<String>) -> RpcError {
+        RpcError { code, message: message.into() }
+    }
+}
+
+type RpcResult = Result<Value, RpcError>;
+
+// The state kept between requests
+struct Daemon<'a> {
+    options: ScanOptions,
+    thread_pool: &'a ThreadPool,
+    // Kept in memory after a folder's first scan, unless --no-cache was given
+    caches: Option<HashMap<PathBuf, Cache>>,
+    // With --assert-read-only the caches aren't written into the scanned folders
+    save_caches: bool,
+    started: Instant,
+    num_requests: u64,
+    num_scanned: u64,
+    num_fixed: u64,
+    // Set by the shutdown method, once its response has been sent
+    stopping: bool,
+}
+
+fn path_param(params : &Value) -> Result<PathBuf, RpcError> {
+    params.get("path")
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing \"path\" string"))
+}
+
+// The run would stop here, but the daemon only fails the request
+fn mismatch_error(path : &Path) -> RpcError {
+    RpcError::new(REQUEST_FAILED,
+                  format!("optimized image wasn't identical to original image ({})", path.display()))
+}
+
+fn fix_param(params : &Value) -> Result<bool, RpcError> {
+    match params.get("fix") {
+        None => Ok(false),
+        Some(fix) => fix.as_bool().ok_or_else(|| RpcError::new(INVALID_PARAMS, "\"fix\" must be true or false")),
+    }
+}
+
+impl<'a> Daemon<'a> {
+    // Results for every file in a folder, the same as a normal run of the folder. It's only
+    // checked unless params has "fix": true.
+    fn scan(&mut self, params : &Value) -> RpcResult {
+        let scan_path = path_param(params)?;
+        let fix = fix_param(params)?;
+        if !scan_path.is_dir() {
+            return Err(RpcError::new(REQUEST_FAILED, format!("[{}] isn't a folder", scan_path.display())));
+        }
+        if fix && self.options.check_only {
+            return Err(RpcError::new(REQUEST_FAILED, "this daemon only checks files, it can't fix them"));
+        }
+
+        let _scan_lock = if fix {
+            match lock::lock_folder(&scan_path, false).map_err(|e| RpcError::new(REQUEST_FAILED, e.to_string()))? {
+                lock::LockResult::Locked(scan_lock) => Some(scan_lock),
+                lock::LockResult::Busy(_) => {
+                    return Err(RpcError::new(REQUEST_FAILED,
+                                             format!("[{}] is being processed by another instance",
+                                                     scan_path.display())));
+                },
+            }
+        } else {
+            None
+        };
+
+        let settings = crate::cache_settings(&self.options);
+        let cache_key = fs::canonicalize(&scan_path).unwrap_or_else(|_| scan_path.clone());
+        let mut cache = self.caches.as_mut().map(|caches| {
+            caches.entry(cache_key)
+                .or_insert_with(|| Cache::load(&scan_path, settings))
+        });
+
+        let check_only = self.options.check_only;
+        self.options.check_only = !fix;
+        let options = &self.options;
+        let summary = self.thread_pool.install(|| {
+            crate::scan_folder(&scan_path, options, cache.as_deref_mut(), None)
+        });
+        self.options.check_only = check_only;
+
+        if let Some(cache) = cache {
+            if self.save_caches {
+                cache.save()
+                    .map_err(|e| RpcError::new(REQUEST_FAILED, format!("failed to write the cache: {}", e)))?;
+            }
+            cache.start_next_scan();
+        }
+
+        self.num_scanned += summary.num_scanned() as u64;
+        if let Some(finding) = summary.findings.iter().find(|finding| finding.kind.is_verification_mismatch()) {
+            return Err(mismatch_error(&finding.rel_path));
+        }
+        if fix {
+            self.num_fixed += summary.num_fixed() as u64;
+        }
+
+        let findings : Vec<_> = summary.findings.iter()
+            .map(|finding| json!({
+                "path": crate::slash_path(&finding.rel_path),
+                "id": finding.kind.id(),
+                "message": finding.kind.description(),
+            }))
+            .collect();
+        Ok(json!({
+            "scanned": summary.num_scanned(),
+            "fixed": if fix { summary.num_fixed() } else { 0 },
+            "findings": findings,
+        }))
+    }
+
+    // Check and fix a single file, with the settings the daemon was started with
+    fn fix(&mut self, params : &Value) -> RpcResult {
+        let path = path_param(params)?;
+        if !path.is_file() {
+            return Err(RpcError::new(REQUEST_FAILED, format!("[{}] isn't a file", path.display())));
+        }
+        if self.options.check_only {
+            return Err(RpcError::new(REQUEST_FAILED, "this daemon only checks files, it can't fix them"));
+        }
+
+        let options = &self.options;
+        let result = self.thread_pool.install(|| crate::scan_one_file(&path, &path, options));
+        self.num_scanned += 1;
+        if result.findings.iter().any(|kind| kind.is_verification_mismatch()) {
+            return Err(mismatch_error(&path));
+        }
+        if let Some((member_path, _)) = result.archive_members.iter()
+            .find(|(_, member_result)| member_result.findings.iter().any(|kind| kind.is_verification_mismatch())) {
+            return Err(mismatch_error(member_path));
+        }
+        if result.fix_outcome.is_some() {
+            self.num_fixed += 1;
+        }
+        Ok(crate::jsonl::file_result_json(&path, &result))
+    }
+
+    fn status(&self) -> RpcResult {
+        Ok(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "requests": self.num_requests,
+            "files_scanned": self.num_scanned,
+            "files_fixed": self.num_fixed,
+            "cached_folders": self.caches.as_ref().map_or(0, |caches| caches.len()),
+        }))
+    }
+
+    fn call(&mut self, method : &str, params : &Value) -> RpcResult {
+        match method {
+            "scan" => self.scan(params),
+            "fix" => self.fix(params),
+            "status" => self.status(),
+            "shutdown" => {
+                self.stopping = true;
+                Ok(Value::Null)
+            },
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        }
+    }
+
+    // The response to one line of JSON-RPC, or None for a notification, which has no id
+    fn handle_line(&mut self, line : &str) -> Option<Value> {
+        let request : Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()))),
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => {
+                let error = RpcError::new(INVALID_REQUEST, "missing \"method\" string");
+                return Some(error_response(id.unwrap_or(Value::Null), error));
+            },
+        };
+
+        self.num_requests += 1;
+        info!("Request: {}", method);
+        let result = self.call(method, request.get("params").unwrap_or(&Value::Null));
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    // Requests are answered in order, one line of JSON each, until the client disconnects
+    fn serve_connection(&mut self, stream : UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle_line(&line) {
+                writeln!(writer, "{}", response)?;
+                writer.flush()?;
+            }
+            if self.stopping {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn error_response(id : Value, error : RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
+
+// A socket file left behind by a daemon which didn't shut down cleanly can be replaced, but not
+// one which is still being listened on
+fn bind(socket_path : &Path) -> io::Result<UnixListener> {
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                      format!("another daemon is already listening on [{}]", socket_path.display())));
+        }
+        fs::remove_file(socket_path)?;
+    }
+    UnixListener::bind(socket_path)
+}
+
+// Stay running and answer JSON-RPC 2.0 requests on a Unix socket, one JSON object per line:
+//
+// {"jsonrpc": "2.0", "id": 1, "method": "scan", "params": {"path": "assets", "fix": false}}
+// {"jsonrpc": "2.0", "id": 2, "method": "fix", "params": {"path": "assets/ui/button.png"}}
+// {"jsonrpc": "2.0", "id": 3, "method": "status"}
+// {"jsonrpc": "2.0", "id": 4, "method": "shutdown"}
+//
+// Requests are handled one at a time, with the settings from the command line. Runs until the
+// shutdown method is called or Ctrl-C is pressed.
+pub fn run(socket_path : &Path,
+           options : ScanOptions,
+           thread_pool : &ThreadPool,
+           use_cache : bool,
+           save_caches : bool) -> io::Result<()> {
+    let listener = bind(socket_path)?;
+    // Blocking accepts would never notice Ctrl-C
+    listener.set_nonblocking(true)?;
+    info!("Listening on [{}]", socket_path.display());
+
+    let mut daemon = Daemon {
+        options,
+        thread_pool,
+        caches: if use_cache { Some(HashMap::new()) } else { None },
+        save_caches,
+        started: Instant::now(),
+        num_requests: 0,
+        num_scanned: 0,
+        num_fixed: 0,
+        stopping: false,
+    };
+    while !daemon.stopping && !crate::is_interrupted() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                // One client going away mid-request shouldn't stop the daemon
+                if let Err(e) = daemon.serve_connection(stream) {
+                    warn!("Warning: lost connection to client: {}", e);
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => return Err(e),
+        }
+    }
+
+    info!("Shutting down");
+    fs::remove_file(socket_path)
+}
+
@@ -0,0 +1,164 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+use walkdir::WalkDir;
+use log::{error, info, warn};
+use png_header_scanner::atomic_write;
+
+// What to do with the other files in a group of identical images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replace {
+    ReportOnly,
+    HardLink,
+    Copy,
+}
+
+struct DecodedFile {
+    rel_path: PathBuf,
+    size: u64,
+    // Hash of the dimensions and RGBA pixels, so the same image in any color type compares equal
+    pixel_hash: u64,
+}
+
+fn decode_rgba(path : &Path) -> image::ImageResult<image::RgbaImage> {
+    Ok(image::open(path)?.to_rgba())
+}
+
+fn hash_pixels(image : &image::RgbaImage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u32(image.width());
+    hasher.write_u32(image.height());
+    hasher.write(image);
+    hasher.finish()
+}
+
+fn decode_file(scan_path : &Path, rel_path : &Path) -> Option<DecodedFile> {
+    let path = scan_path.join(rel_path);
+    let size = fs::metadata(&path).ok()?.len();
+    match decode_rgba(&path) {
+        Ok(image) => Some(DecodedFile { rel_path: rel_path.to_path_buf(), size, pixel_hash: hash_pixels(&image) }),
+        Err(e) => {
+            warn!("Failed to decode {}: {}", rel_path.display(), e);
+            None
+        }
+    }
+}
+
+// Hashes can collide, so the pixels are compared for real before replacing a file
+fn pixels_identical(original_path : &Path, duplicate_path : &Path) -> bool {
+    match (decode_rgba(original_path), decode_rgba(duplicate_path)) {
+        (Ok(original), Ok(duplicate)) => {
+            original.dimensions() == duplicate.dimensions() && original.into_raw() == duplicate.into_raw()
+        },
+        _ => false,
+    }
+}
+
+// Swap in a hard link through a temp name, so the duplicate is never missing
+fn replace_with_hard_link(original_path : &Path, duplicate_path : &Path) -> io::Result<()> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(duplicate_path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.link.tmp", std::process::id()));
+    let temp_path = duplicate_path.with_file_name(temp_name);
+
+    fs::hard_link(original_path, &temp_path)?;
+    let result = fs::rename(&temp_path, duplicate_path);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn replace_duplicate(original_path : &Path, duplicate_path : &Path, replace : Replace) -> io::Result<()> {
+    match replace {
+        Replace::ReportOnly => Ok(()),
+        Replace::HardLink => replace_with_hard_link(original_path, duplicate_path),
+        Replace::Copy => atomic_write::write_atomic(duplicate_path, &fs::read(original_path)?, &Default::default()),
+    }
+}
+
+// Find PNGs under scan_path which decode to the same pixels, even if their files differ, and
+// optionally replace every copy with the smallest file. Returns the number of duplicate files.
+pub fn dedup(scan_path : &Path, replace : Replace) -> usize {
+    let mut rel_paths = Vec::new();
+    let mut seen_files = HashSet::new();
+    for entry in WalkDir::new(scan_path).into_iter().filter_map(|entry| entry.ok()) {
+        if !entry.path().is_file() || !crate::is_png(entry.path()) {
+            continue;
+        }
+        // Hard links to the same file are already deduplicated
+        if let Some(file_id) = crate::file_id(entry.path()) {
+            if !seen_files.insert(file_id) {
+                continue;
+            }
+        }
+        rel_paths.push(entry.path().strip_prefix(scan_path).unwrap().to_path_buf());
+    }
+    rel_paths.sort();
+
+    let decoded_files : Vec<DecodedFile> = rel_paths.par_iter()
+        .filter_map(|rel_path| decode_file(scan_path, rel_path))
+        .collect();
+
+    let mut groups : HashMap<u64, Vec<DecodedFile>> = HashMap::new();
+    for decoded_file in decoded_files {
+        groups.entry(decoded_file.pixel_hash).or_default().push(decoded_file);
+    }
+
+    let mut groups : Vec<Vec<DecodedFile>> = groups.into_values()
+        .filter(|group| group.len() > 1)
+        .collect();
+    for group in &mut groups {
+        // Smallest file first, by path when sizes are equal
+        group.sort_by(|a, b| a.size.cmp(&b.size).then_with(|| a.rel_path.cmp(&b.rel_path)));
+    }
+    groups.sort_by(|a, b| a[0].rel_path.cmp(&b[0].rel_path));
+
+    let mut num_duplicates = 0;
+    let mut num_replaced = 0;
+    let mut bytes_saved = 0;
+    for group in &groups {
+        let (smallest, duplicates) = group.split_first().unwrap();
+        info!("{} identical images:", group.len());
+        info!("    {} ({} bytes, kept)", smallest.rel_path.display(), smallest.size);
+
+        let smallest_path = scan_path.join(&smallest.rel_path);
+        for duplicate in duplicates {
+            info!("    {} ({} bytes)", duplicate.rel_path.display(), duplicate.size);
+            num_duplicates += 1;
+
+            if replace == Replace::ReportOnly {
+                // As if it was hard-linked
+                bytes_saved += duplicate.size;
+                continue;
+            }
+            let duplicate_path = scan_path.join(&duplicate.rel_path);
+            if !pixels_identical(&smallest_path, &duplicate_path) {
+                info!("        Not replaced, the pixels only have the same hash");
+                continue;
+            }
+            match replace_duplicate(&smallest_path, &duplicate_path, replace) {
+                Ok(()) => {
+                    num_replaced += 1;
+                    bytes_saved += match replace {
+                        Replace::Copy => duplicate.size - smallest.size,
+                        _ => duplicate.size,
+                    };
+                },
+                Err(e) => error!("        Failed to replace: {}", e),
+            }
+        }
+    }
+
+    info!("Found {} duplicates in {} groups", num_duplicates, groups.len());
+    if replace == Replace::ReportOnly {
+        info!("Hard-linking them would save {} bytes", bytes_saved);
+    } else {
+        changed!("Replaced {} duplicates, saving {} bytes", num_replaced, bytes_saved);
+    }
+    num_duplicates
+}
@@ -0,0 +1,87 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+use log::{debug, warn};
+use crate::{FileResult, FindingKind, ScanOptions};
+
+// When --exec runs its command, from --exec-for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecFor {
+    // Once for each file which was fixed
+    Fixed,
+    // Once for each finding, fixed or not
+    Finding,
+}
+
+// Command run for each file which was fixed, or each finding, from --exec. The command is split
+// on whitespace and run without a shell, and these are replaced in each argument:
+//   {path}          the file, as found under the scanned folder
+//   {rel_path}      the file relative to the folder, or archive.zip!inner/path.png inside archives
+//   {finding}       the finding's id, or every finding's id separated by commas when fixed
+//   {description}   what was found, or every finding separated by "; " when fixed
+//   {size_before}   the size in bytes before fixing, empty if it wasn't fixed
+//   {size_after}    the size in bytes after fixing, empty if it wasn't fixed
+#[derive(Debug, Clone)]
+pub struct ExecCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub exec_for: ExecFor,
+}
+
+impl ExecCommand {
+    // None if the command is empty
+    pub fn parse(command : &str, exec_for : ExecFor) -> Option<ExecCommand> {
+        let mut words = command.split_whitespace().map(String::from);
+        Some(ExecCommand { program: words.next()?, args: words.collect(), exec_for })
+    }
+
+    fn run(&self, path : &Path, rel_path : &Path, findings : &[&FindingKind], result : &FileResult) {
+        let ids : Vec<&str> = findings.iter().map(|kind| kind.id()).collect();
+        let descriptions : Vec<String> = findings.iter().map(|kind| kind.description()).collect();
+        let size_change = result.fix_outcome.as_ref().map(|fix_outcome| fix_outcome.size_change);
+        let size_before = size_change.map(|size_change| size_change.before.to_string()).unwrap_or_default();
+        let size_after = size_change.map(|size_change| size_change.after.to_string()).unwrap_or_default();
+
+        let mut command = Command::new(&self.program);
+        for arg in &self.args {
+            command.arg(arg.replace("{path}", &path.to_string_lossy())
+                           .replace("{rel_path}", &rel_path.to_string_lossy())
+                           .replace("{finding}", &ids.join(","))
+                           .replace("{description}", &descriptions.join("; "))
+                           .replace("{size_before}", &size_before)
+                           .replace("{size_after}", &size_after));
+        }
+        debug!("Running {:?}", command);
+        // Its output goes to ours, after this file's messages
+        match command.stdin(Stdio::null()).status() {
+            Ok(status) if status.success() => {},
+            Ok(status) => warn!("Warning: --exec failed for {} with {}", rel_path.display(), status),
+            Err(e) => warn!("Warning: can't run {} for --exec: {}", self.program, e),
+        }
+    }
+}
+
+// Run the --exec command for a file which was just handled, and the PNGs inside it if it's an
+// archive. Nothing runs for fixed files with --estimate, since they weren't changed.
+pub fn run_for_result(path : &Path, rel_path : &Path, result : &FileResult, options : &ScanOptions) {
+    let exec = match &options.exec {
+        Some(exec) => exec,
+        None => return,
+    };
+    let member_results = result.archive_members.iter().map(|(member_path, member_result)| {
+        (member_path.as_path(), member_result)
+    });
+    for (rel_path, result) in std::iter::once((rel_path, result)).chain(member_results) {
+        match exec.exec_for {
+            ExecFor::Fixed if result.fix_outcome.is_some() && !options.estimate => {
+                let findings : Vec<&FindingKind> = result.findings.iter().collect();
+                exec.run(path, rel_path, &findings, result);
+            },
+            ExecFor::Fixed => {},
+            ExecFor::Finding => {
+                for kind in &result.findings {
+                    exec.run(path, rel_path, &[kind], result);
+                }
+            },
+        }
+    }
+}
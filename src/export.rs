@@ -0,0 +1,181 @@
+use crate::chunks::ChunkSummary;
+use crate::fix::{FixError, FixOptions, FixOutcome, FixResult};
+use crate::PngHeader;
+#[cfg(feature = "fix")]
+use std::time::Instant;
+#[cfg(feature = "fix")]
+use crate::fix::{FixTimings, SizeChange, Verification};
+#[cfg(feature = "fix")]
+use crate::{fix, palette, PixelFormat};
+
+// Lossless delivery formats for --convert-to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    WebP,
+    Jxl,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::WebP => "webp",
+            ExportFormat::Jxl => "jxl",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::WebP => "WebP",
+            ExportFormat::Jxl => "JPEG XL",
+        }
+    }
+}
+
+// 8-bit RGB or RGBA pixels, the only layouts both encoders take
+#[cfg(feature = "fix")]
+struct Pixels {
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    data: Vec<u8>,
+}
+
+#[cfg(feature = "fix")]
+fn format_error(message : String) -> FixError {
+    FixError::Decode(image::ImageError::FormatError(message))
+}
+
+#[cfg(feature = "fix")]
+fn decode_png(data : &[u8], header : &PngHeader) -> FixResult<Pixels> {
+    // The image crate gets low bit depth palettes with partial tRNS chunks wrong
+    let image = if header.pixel_format == PixelFormat::IndexedColor {
+        palette::expand_indexed(data)?
+    } else {
+        fix::to_truecolor(image::load_from_memory(data)?)
+    };
+
+    let (width, height, has_alpha, data) = match image {
+        image::DynamicImage::ImageRgb8(buffer) => (buffer.width(), buffer.height(), false, buffer.into_raw()),
+        image::DynamicImage::ImageRgba8(buffer) => (buffer.width(), buffer.height(), true, buffer.into_raw()),
+        image => {
+            let buffer = image.to_rgba();
+            (buffer.width(), buffer.height(), true, buffer.into_raw())
+        },
+    };
+    Ok(Pixels { width, height, has_alpha, data })
+}
+
+#[cfg(feature = "fix")]
+fn encode_webp(pixels : &Pixels) -> FixResult<Vec<u8>> {
+    let encoder = if pixels.has_alpha {
+        webp::Encoder::from_rgba(&pixels.data, pixels.width, pixels.height)
+    } else {
+        webp::Encoder::from_rgb(&pixels.data, pixels.width, pixels.height)
+    };
+    Ok(encoder.encode_lossless().to_vec())
+}
+
+#[cfg(feature = "fix")]
+fn decode_webp(data : &[u8]) -> FixResult<Pixels> {
+    let image = webp::Decoder::new(data).decode()
+        .ok_or_else(|| format_error("failed to decode WebP image".to_string()))?;
+    Ok(Pixels {
+        width: image.width(),
+        height: image.height(),
+        has_alpha: image.is_alpha(),
+        data: image.to_vec(),
+    })
+}
+
+#[cfg(feature = "fix")]
+fn encode_jxl(pixels : &Pixels) -> FixResult<Vec<u8>> {
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .lossless(true)
+        .uses_original_profile(true)
+        .has_alpha(pixels.has_alpha)
+        .build()
+        .map_err(|e| format_error(e.to_string()))?;
+    let result : jpegxl_rs::encode::EncoderResult<u8> = encoder
+        .encode::<u8, u8>(&pixels.data, pixels.width, pixels.height)
+        .map_err(|e| format_error(e.to_string()))?;
+    Ok(result.data)
+}
+
+#[cfg(feature = "fix")]
+fn decode_jxl(data : &[u8]) -> FixResult<Pixels> {
+    let decoder = jpegxl_rs::decoder_builder().build().map_err(|e| format_error(e.to_string()))?;
+    let (metadata, data) = decoder.decode_with::<u8>(data).map_err(|e| format_error(e.to_string()))?;
+    Ok(Pixels {
+        width: metadata.width,
+        height: metadata.height,
+        has_alpha: metadata.has_alpha_channel,
+        data,
+    })
+}
+
+// Convert a PNG held in memory to a lossless WebP or JPEG XL image, returning the new file
+// contents. The new image is decoded again, and must have exactly the same pixels. Only the
+// animation and decode limit settings of fix_options apply.
+#[cfg(feature = "fix")]
+pub fn export_data(data : &[u8],
+                   header : &PngHeader,
+                   summary : &ChunkSummary,
+                   format : ExportFormat,
+                   fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    if summary.animated && !fix_options.force_apng {
+        return Err(FixError::Animated);
+    }
+    fix::check_decode_limits(header, fix_options)?;
+
+    // Both formats would need the extra bits thrown away
+    if header.bit_depth == 16 {
+        return Err(FixError::Unsupported("16-bit images can't be exported losslessly"));
+    }
+
+    let mut timings = FixTimings::default();
+
+    let start = Instant::now();
+    let pixels = decode_png(data, header)?;
+    let exported_data = match format {
+        ExportFormat::WebP => encode_webp(&pixels)?,
+        ExportFormat::Jxl => encode_jxl(&pixels)?,
+    };
+    timings.convert = start.elapsed();
+
+    let start = Instant::now();
+    let exported_pixels = match format {
+        ExportFormat::WebP => decode_webp(&exported_data)?,
+        ExportFormat::Jxl => decode_jxl(&exported_data)?,
+    };
+    let identical = (exported_pixels.width, exported_pixels.height) == (pixels.width, pixels.height) &&
+        exported_pixels.has_alpha == pixels.has_alpha &&
+        exported_pixels.data == pixels.data;
+    if !identical {
+        return Err(FixError::VerificationFailed);
+    }
+    timings.verify = start.elapsed();
+
+    let outcome = FixOutcome {
+        size_change: SizeChange {
+            before: data.len() as u64,
+            after: exported_data.len() as u64,
+        },
+        pixel_format: if pixels.has_alpha { PixelFormat::TrueColorWithAlpha } else { PixelFormat::TrueColor },
+        // None of the PNG chunks carry over to the new format
+        chunks_preserved: Vec::new(),
+        chunks_stripped: fix::chunk_types(data)?,
+        timings,
+        verification: Verification::PixelsIdentical,
+        thumbnails: None,
+    };
+    Ok((exported_data, outcome))
+}
+
+#[cfg(not(feature = "fix"))]
+pub fn export_data(_data : &[u8],
+                   _header : &PngHeader,
+                   _summary : &ChunkSummary,
+                   _format : ExportFormat,
+                   _fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    Err(FixError::Unsupported("this build can only scan, it was built without the fix feature"))
+}
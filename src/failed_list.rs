@@ -0,0 +1,27 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::{Finding, FindingKind};
+
+// One file per line, as its slash separated relative path and the kind of error, separated by a tab
+pub fn write_failed_list(output_path : &Path, findings : &[Finding]) -> io::Result<usize> {
+    let mut out = File::create(output_path)?;
+    let mut num_failed = 0;
+    for finding in findings {
+        if let FindingKind::FixFailed(error_kind) = finding.kind {
+            writeln!(out, "{}\t{}", crate::slash_path(&finding.rel_path), error_kind)?;
+            num_failed += 1;
+        }
+    }
+    Ok(num_failed)
+}
+
+// Relative paths of the files in a list written by write_failed_list
+pub fn read_failed_list(list_path : &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(fs::read_to_string(list_path)?
+        .lines()
+        .filter_map(|line| line.split('\t').next())
+        .filter(|rel_path| !rel_path.is_empty())
+        .map(|rel_path| rel_path.split('/').collect())
+        .collect())
+}
@@ -0,0 +1,244 @@
+// C interface for linking the scanner into other tools. include/png_header_scanner.h declares all
+// of it, and is regenerated with:
+//
+// cbindgen --config cbindgen.toml --output include/png_header_scanner.h
+//
+// Nothing here panics across the boundary: a panic inside is caught and returned as
+// PHS_STATUS_INTERNAL_ERROR.
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::slice;
+use crate::fix::{self, FixError, FixOptions};
+use crate::{ParseResult, PixelFormat, PngHeader};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhsStatus {
+    Ok = 0,
+    // A required pointer was null, or the path wasn't valid UTF-8
+    InvalidArgument = 1,
+    OpenFailed = 2,
+    // The file ended early, or couldn't be read
+    ReadFailed = 3,
+    // Not a PNG, or the IHDR chunk is missing or has invalid values
+    InvalidPng = 4,
+    // Apple CgBI PNG, which has to be repaired before it can be fixed
+    AppleCgbi = 5,
+    // Animated PNG, which would lose all but its first frame
+    Animated = 6,
+    // Too large to decode with the given limits
+    TooLarge = 7,
+    // The fixed image was larger than the original, and force wasn't set. The file is unchanged.
+    WouldGrow = 8,
+    // The fixed image didn't have the same pixels as the original. The file is unchanged.
+    VerificationFailed = 9,
+    // Fixing failed for another reason, or this library was built without the fix feature
+    FixFailed = 10,
+    InternalError = 11,
+}
+
+// The IHDR fields, as stored in the file
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhsHeader {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    // PNG color type: 0 greyscale, 2 truecolor, 3 indexed, 4 greyscale with alpha, 6 truecolor with alpha
+    pub color_type: u8,
+    pub interlaced: bool,
+    pub apple_cgbi: bool,
+}
+
+// Settings for phs_fix_image. Passing NULL uses the defaults, which are all false.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhsFixOptions {
+    // Rewrite interlaced images as non-interlaced
+    pub deinterlace: bool,
+    // Keep the result even if it's larger than the original
+    pub force: bool,
+    // Fix animated PNGs even though only the first frame survives
+    pub force_apng: bool,
+    // Convert to RGB/RGBA even if the image isn't indexed
+    pub convert_any: bool,
+    // Rewrite 16-bit images as 8-bit
+    pub downconvert_16bit: bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhsFixResult {
+    pub size_before: u64,
+    pub size_after: u64,
+    // PNG color type of the written image
+    pub color_type: u8,
+}
+
+fn color_type(pixel_format : PixelFormat) -> u8 {
+    match pixel_format {
+        PixelFormat::Greyscale => 0,
+        PixelFormat::TrueColor => 2,
+        PixelFormat::IndexedColor => 3,
+        PixelFormat::GreyscaleWithAlpha => 4,
+        PixelFormat::TrueColorWithAlpha => 6,
+    }
+}
+
+fn to_phs_header(header : PngHeader, apple_cgbi : bool) -> PhsHeader {
+    PhsHeader {
+        width: header.width,
+        height: header.height,
+        bit_depth: header.bit_depth,
+        color_type: color_type(header.pixel_format),
+        interlaced: header.interlaced,
+        apple_cgbi,
+    }
+}
+
+fn parse_status(parse_result : ParseResult) -> PhsStatus {
+    match parse_result {
+        ParseResult::Valid(_) | ParseResult::AppleCgbi(_) => PhsStatus::Ok,
+        ParseResult::OpenFail => PhsStatus::OpenFailed,
+        ParseResult::ReadFail => PhsStatus::ReadFailed,
+        ParseResult::InvalidPngHeader |
+        ParseResult::InvalidIhdr |
+        ParseResult::InvalidPixelFormat |
+        ParseResult::IllegalBitDepth => PhsStatus::InvalidPng,
+    }
+}
+
+fn fix_status(error : &FixError) -> PhsStatus {
+    match error {
+        FixError::Io(_e) => PhsStatus::ReadFailed,
+        FixError::InvalidPng(ParseResult::AppleCgbi(_)) => PhsStatus::AppleCgbi,
+        FixError::InvalidPng(parse_result) => parse_status(*parse_result),
+        FixError::Animated => PhsStatus::Animated,
+        FixError::TooLarge { .. } => PhsStatus::TooLarge,
+        FixError::WouldGrow(_) | FixError::BelowMinSavings(_) => PhsStatus::WouldGrow,
+        FixError::VerificationFailed => PhsStatus::VerificationFailed,
+        _ => PhsStatus::FixFailed,
+    }
+}
+
+// Nothing is looked at again after a panic, so it doesn't matter what state it left behind
+fn catch_panic<F: FnOnce() -> PhsStatus>(f : F) -> PhsStatus {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(PhsStatus::InternalError)
+}
+
+fn write_header(parse_result : ParseResult, out : &mut PhsHeader) -> PhsStatus {
+    match parse_result {
+        ParseResult::Valid(header) => *out = to_phs_header(header, false),
+        ParseResult::AppleCgbi(header) => *out = to_phs_header(header, true),
+        _ => {},
+    }
+    parse_status(parse_result)
+}
+
+unsafe fn path_arg<'a>(path : *const c_char) -> Option<&'a Path> {
+    if path.is_null() {
+        return None;
+    }
+    CStr::from_ptr(path).to_str().ok().map(Path::new)
+}
+
+/// Read the header of the PNG file at path into out. out is only written when PHS_STATUS_OK is
+/// returned.
+///
+/// # Safety
+///
+/// path must be a null terminated string, and out must point to a PhsHeader.
+#[no_mangle]
+pub unsafe extern "C" fn phs_scan_header(path : *const c_char, out : *mut PhsHeader) -> PhsStatus {
+    let path = match path_arg(path) {
+        Some(path) if !out.is_null() => path,
+        _ => return PhsStatus::InvalidArgument,
+    };
+    let out = &mut *out;
+    catch_panic(move || write_header(crate::parse_one(path), out))
+}
+
+/// Like phs_scan_header, for a PNG held in memory. Every chunk up to the image data is checked
+/// too, not just the header.
+///
+/// # Safety
+///
+/// data must point to length readable bytes, and out must point to a PhsHeader.
+#[no_mangle]
+pub unsafe extern "C" fn phs_scan_header_bytes(data : *const u8,
+                                               length : usize,
+                                               out : *mut PhsHeader) -> PhsStatus {
+    if data.is_null() || out.is_null() {
+        return PhsStatus::InvalidArgument;
+    }
+    let data = slice::from_raw_parts(data, length);
+    let out = &mut *out;
+    catch_panic(move || write_header(crate::parse_bytes(data), out))
+}
+
+/// Convert the PNG file at path to RGB/RGBA and optimize it, replacing the file once the new
+/// image has been verified. options may be NULL for the defaults, and out may be NULL if the
+/// result isn't needed.
+///
+/// # Safety
+///
+/// path must be a null terminated string. options and out must each be NULL or point to their
+/// struct.
+#[no_mangle]
+pub unsafe extern "C" fn phs_fix_image(path : *const c_char,
+                                       options : *const PhsFixOptions,
+                                       out : *mut PhsFixResult) -> PhsStatus {
+    let path = match path_arg(path) {
+        Some(path) => path,
+        None => return PhsStatus::InvalidArgument,
+    };
+    let options = options.as_ref().copied().unwrap_or_default();
+    let out = out.as_mut();
+
+    catch_panic(move || {
+        let fix_options = FixOptions {
+            deinterlace: options.deinterlace,
+            force: options.force,
+            force_apng: options.force_apng,
+            convert_any: options.convert_any,
+            downconvert_16bit: options.downconvert_16bit,
+            ..FixOptions::default()
+        };
+        match fix::fix(path, &fix_options) {
+            Ok(outcome) => {
+                if let Some(out) = out {
+                    *out = PhsFixResult {
+                        size_before: outcome.size_change.before,
+                        size_after: outcome.size_change.after,
+                        color_type: color_type(outcome.pixel_format),
+                    };
+                }
+                PhsStatus::Ok
+            },
+            Err(e) => fix_status(&e),
+        }
+    })
+}
+
+/// A description of a status, as a static null terminated string which must not be freed.
+#[no_mangle]
+pub extern "C" fn phs_status_message(status : PhsStatus) -> *const c_char {
+    let message : &'static [u8] = match status {
+        PhsStatus::Ok => b"ok\0",
+        PhsStatus::InvalidArgument => b"invalid argument\0",
+        PhsStatus::OpenFailed => b"failed to open the file\0",
+        PhsStatus::ReadFailed => b"failed to read the file\0",
+        PhsStatus::InvalidPng => b"not a valid PNG\0",
+        PhsStatus::AppleCgbi => b"Apple CgBI PNG, which must be repaired first\0",
+        PhsStatus::Animated => b"animated PNG, which would lose all but its first frame\0",
+        PhsStatus::TooLarge => b"image too large to decode safely\0",
+        PhsStatus::WouldGrow => b"the fixed image would be larger\0",
+        PhsStatus::VerificationFailed => b"the fixed image didn't match the original\0",
+        PhsStatus::FixFailed => b"failed to fix the image\0",
+        PhsStatus::InternalError => b"internal error\0",
+    };
+    message.as_ptr() as *const c_char
+}
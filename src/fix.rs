@@ -0,0 +1,281 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Cursor};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::chunks;
+use crate::optimizer::Optimizer;
+use crate::{ParseResult, PixelFormat, PngHeader};
+#[cfg(not(feature = "fix"))]
+use crate::chunks::ChunkSummary;
+#[cfg(not(feature = "fix"))]
+use std::path::Path;
+
+// Decoding, converting and optimizing images needs the image and oxipng crates, which a
+// scan-only build leaves out
+#[cfg(feature = "fix")]
+mod pipeline;
+#[cfg(feature = "fix")]
+pub use pipeline::{convert_other_data, fix, fix_data, resize_to_fit, to_truecolor};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SizeChange {
+    pub before: u64,
+    pub after: u64,
+}
+
+impl SizeChange {
+    pub fn saved(&self) -> i64 {
+        self.before as i64 - self.after as i64
+    }
+}
+
+
+// How the converted image is compared with the original
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerifyMode {
+    // The decoded pixel bytes must be identical
+    #[default]
+    Exact,
+    // Each channel may differ by up to the tolerance, and different channel layouts of the same
+    // pixels (like RGB and opaque RGBA) are equal
+    Tolerant { tolerance: u8 },
+}
+
+// Smallest saving worth rewriting a file for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MinSavings {
+    Bytes(u64),
+    // Percentage of the original file size
+    Percent(f64),
+}
+
+impl MinSavings {
+    #[cfg(feature = "fix")]
+    fn is_met(&self, size_change : &SizeChange) -> bool {
+        match *self {
+            MinSavings::Bytes(bytes) => size_change.saved() >= bytes as i64,
+            MinSavings::Percent(percent) => {
+                size_change.saved() as f64 >= size_change.before as f64 * percent / 100f64
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FixOptions {
+    // Keep small PNG encoded previews of the image before and after fixing
+    pub thumbnails: bool,
+    // Rewrite interlaced images as non-interlaced
+    pub deinterlace: bool,
+    // Fix animated PNGs even though only the first frame survives
+    pub force_apng: bool,
+    // Refuse to decode images larger than this, so broken or malicious files can't use up all
+    // the memory. None means unlimited.
+    pub max_pixels: Option<u64>,
+    pub max_decode_mem: Option<u64>,
+    pub verify: VerifyMode,
+    // Only keep the result if it's at least this much smaller
+    pub min_savings: Option<MinSavings>,
+    // Keep the result even if it's larger than the original file, or doesn't meet min_savings
+    pub force: bool,
+    // Convert to RGB/RGBA even if the color type wouldn't need it, e.g. greyscale images. 8-bit RGB/RGBA
+    // images without tRNS are only optimized.
+    pub convert_any: bool,
+    // Rewrite 16-bit images as 8-bit RGB/RGBA
+    pub downconvert_16bit: bool,
+    // Rewrite RGBA images whose pixels are all opaque as RGB
+    pub drop_opaque_alpha: bool,
+    // None uses the built-in oxipng optimizer
+    pub optimizer: Option<Arc<dyn Optimizer>>,
+    // Give up optimizing an image after this long and keep the original. None means no limit.
+    pub timeout: Option<Duration>,
+    // Write the same bytes for the same image on every run and machine, see
+    // OptimizeSettings::reproducible
+    pub reproducible: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Thumbnails {
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    // The image was decoded and converted, and the result has exactly the same pixels
+    PixelsIdentical,
+    // Compared with VerifyMode::Tolerant. No channel differed by more than max_difference.
+    WithinTolerance { max_difference: u8 },
+    // Only recompressed without decoding, so there was nothing to compare
+    NotDecoded,
+}
+
+// How long each step took. Steps which didn't run are zero.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixTimings {
+    pub convert: Duration,
+    pub optimize: Duration,
+    pub verify: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct FixOutcome {
+    pub size_change: SizeChange,
+    // Color type of the written image
+    pub pixel_format: PixelFormat,
+    pub chunks_preserved: Vec<[u8; 4]>,
+    pub chunks_stripped: Vec<[u8; 4]>,
+    pub timings: FixTimings,
+    pub verification: Verification,
+    pub thumbnails: Option<Thumbnails>,
+}
+
+#[derive(Debug)]
+pub enum FixError {
+    Io(io::Error),
+    InvalidPng(ParseResult),
+    Animated,
+    #[cfg(feature = "fix")]
+    Decode(image::ImageError),
+    #[cfg(feature = "fix")]
+    Optimize(oxipng::PngError),
+    // The --optimizer program couldn't be run, or failed
+    ExternalOptimizer(String),
+    // Optimizing took longer than FixOptions::timeout
+    TimedOut(Duration),
+    // The optimized image didn't have the same pixels as the original
+    VerificationFailed,
+    // Decoding the image would go over FixOptions::max_pixels or max_decode_mem
+    TooLarge { pixels: u64, decode_mem: u64 },
+    // The fixed image is larger than the original, and FixOptions::force isn't set
+    WouldGrow(SizeChange),
+    // The fixed image doesn't save FixOptions::min_savings, and FixOptions::force isn't set
+    BelowMinSavings(SizeChange),
+    // The image can't be converted without losing information
+    Unsupported(&'static str),
+    // Only checked for FixOptions::drop_opaque_alpha, some pixels aren't opaque so the image has
+    // nothing to fix
+    NotOpaque,
+}
+
+pub type FixResult<T> = Result<T, FixError>;
+
+impl fmt::Display for FixError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixError::Io(e) => write!(f, "I/O error: {}", e),
+            FixError::InvalidPng(parse_result) => write!(f, "invalid PNG ({:?})", parse_result),
+            FixError::Animated => write!(f, "animated PNGs would be flattened to their first frame"),
+            #[cfg(feature = "fix")]
+            FixError::Decode(e) => write!(f, "failed to decode image: {}", e),
+            #[cfg(feature = "fix")]
+            FixError::Optimize(e) => write!(f, "optimize failed: {}", e),
+            FixError::ExternalOptimizer(message) => write!(f, "optimize failed: {}", message),
+            FixError::TimedOut(timeout) => write!(f, "gave up optimizing after {:?}", timeout),
+            FixError::VerificationFailed => write!(f, "optimized image wasn't identical to original image"),
+            FixError::TooLarge { pixels, decode_mem } => {
+                write!(f, "image is too large to decode ({} pixels, about {}MB)", pixels, decode_mem / 1_000_000)
+            },
+            FixError::WouldGrow(size_change) => {
+                write!(f, "fixed image would grow from {} to {} bytes", size_change.before, size_change.after)
+            },
+            FixError::BelowMinSavings(size_change) => {
+                write!(f, "fixed image would only save {} bytes", size_change.saved())
+            },
+            FixError::Unsupported(reason) => write!(f, "{}", reason),
+            FixError::NotOpaque => write!(f, "not every pixel is opaque"),
+        }
+    }
+}
+
+impl Error for FixError {}
+
+impl From<io::Error> for FixError {
+    fn from(e : io::Error) -> FixError {
+        FixError::Io(e)
+    }
+}
+
+#[cfg(feature = "fix")]
+impl From<image::ImageError> for FixError {
+    fn from(e : image::ImageError) -> FixError {
+        FixError::Decode(e)
+    }
+}
+
+#[cfg(feature = "fix")]
+impl From<oxipng::PngError> for FixError {
+    fn from(e : oxipng::PngError) -> FixError {
+        FixError::Optimize(e)
+    }
+}
+
+// Worst case bytes per pixel once decoded: palettes are expanded to RGBA, tRNS color keys add an
+// alpha channel, and 16-bit images keep 2 bytes per channel
+fn decoded_bytes_per_pixel(header : &PngHeader) -> u64 {
+    let channels = match header.pixel_format {
+        PixelFormat::Greyscale | PixelFormat::GreyscaleWithAlpha => 2,
+        PixelFormat::TrueColor | PixelFormat::TrueColorWithAlpha | PixelFormat::IndexedColor => 4,
+    };
+    if header.bit_depth == 16 { channels * 2 } else { channels }
+}
+
+// Memory needed for the decoded pixels. Only the original image is fully decoded, the optimized
+// image is verified a row at a time.
+pub fn estimated_decode_memory(header : &PngHeader) -> u64 {
+    u64::from(header.width) * u64::from(header.height) * decoded_bytes_per_pixel(header)
+}
+
+// Check the size from IHDR against the limits before anything is decoded
+pub fn check_decode_limits(header : &PngHeader, fix_options : &FixOptions) -> FixResult<()> {
+    let pixels = u64::from(header.width) * u64::from(header.height);
+    let decode_mem = estimated_decode_memory(header);
+
+    let too_many_pixels = fix_options.max_pixels.is_some_and(|max_pixels| pixels > max_pixels);
+    let too_much_memory = fix_options.max_decode_mem.is_some_and(|max_decode_mem| decode_mem > max_decode_mem);
+    if too_many_pixels || too_much_memory {
+        return Err(FixError::TooLarge { pixels, decode_mem });
+    }
+    Ok(())
+}
+
+// Unique chunk types in file order
+pub fn chunk_types(data : &[u8]) -> FixResult<Vec<[u8; 4]>> {
+    let mut types = Vec::new();
+    for chunk in chunks::list_chunks(&mut Cursor::new(data))? {
+        if !types.contains(&chunk.chunk_type) {
+            types.push(chunk.chunk_type);
+        }
+    }
+    Ok(types)
+}
+
+#[cfg(not(feature = "fix"))]
+const FIX_DISABLED: &str = "this build can only scan, it was built without the fix feature";
+
+#[cfg(not(feature = "fix"))]
+pub fn fix_data(_data : &[u8],
+                _header : &PngHeader,
+                _summary : &ChunkSummary,
+                _fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    Err(FixError::Unsupported(FIX_DISABLED))
+}
+
+#[cfg(not(feature = "fix"))]
+pub fn convert_other_data(_data : &[u8], _fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    Err(FixError::Unsupported(FIX_DISABLED))
+}
+
+#[cfg(not(feature = "fix"))]
+pub fn resize_to_fit(_data : &[u8],
+                     _header : &PngHeader,
+                     _max_dimension : u32,
+                     _fix_options : &FixOptions) -> FixResult<(Vec<u8>, PngHeader)> {
+    Err(FixError::Unsupported(FIX_DISABLED))
+}
+
+#[cfg(not(feature = "fix"))]
+pub fn fix(_path : &Path, _fix_options : &FixOptions) -> FixResult<FixOutcome> {
+    Err(FixError::Unsupported(FIX_DISABLED))
+}
@@ -0,0 +1,524 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::Instant;
+use image::GenericImageView;
+use crate::chunks::{self, ChunkSummary};
+use crate::optimizer::{OptimizeSettings, Optimizer, Oxipng};
+use crate::palette;
+use crate::{parse_header, ParseResult, PixelFormat, PngHeader, EXPECTED_PNG_HEADER};
+use super::{chunk_types, check_decode_limits};
+use super::{FixError, FixOptions, FixOutcome, FixResult, FixTimings, SizeChange, Thumbnails, Verification, VerifyMode};
+
+// Largest side of the thumbnails generated for reports
+const THUMBNAIL_SIZE: u32 = 96;
+
+fn make_thumbnail(image : &image::DynamicImage) -> FixResult<Vec<u8>> {
+    let mut thumbnail_data = Vec::new();
+    image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut thumbnail_data, image::ImageOutputFormat::PNG)?;
+    Ok(thumbnail_data)
+}
+
+fn make_thumbnails(before : &image::DynamicImage, after_data : &[u8]) -> FixResult<Thumbnails> {
+    let after = image::load_from_memory(after_data)?;
+    Ok(Thumbnails {
+        before: make_thumbnail(before)?,
+        after: make_thumbnail(&after)?,
+    })
+}
+
+// Palette images and greyscale/truecolor images with a tRNS color key need to be expanded to
+// RGB/RGBA. Anything else can be optimized without decoding it.
+fn needs_conversion(pixel_format : &PixelFormat, summary : &ChunkSummary) -> bool {
+    match pixel_format {
+        PixelFormat::IndexedColor => true,
+        PixelFormat::Greyscale | PixelFormat::TrueColor => summary.has_transparency(),
+        PixelFormat::GreyscaleWithAlpha | PixelFormat::TrueColorWithAlpha => false,
+    }
+}
+
+// 8-bit RGB/RGBA images without a tRNS color key are already what converting would write, so
+// when --match selects one it only needs the raw-chunk optimization
+fn is_converted_already(header : &PngHeader, summary : &ChunkSummary) -> bool {
+    let truecolor = matches!(header.pixel_format, PixelFormat::TrueColor | PixelFormat::TrueColorWithAlpha);
+    truecolor && header.bit_depth == 8 && !summary.has_transparency()
+}
+
+fn converts(header : &PngHeader, summary : &ChunkSummary, fix_options : &FixOptions) -> bool {
+    needs_conversion(&header.pixel_format, summary) ||
+        (fix_options.convert_any && !is_converted_already(header, summary))
+}
+
+// Whether fixing the image will decode its pixels
+fn needs_decoding(header : &PngHeader, summary : &ChunkSummary, fix_options : &FixOptions) -> bool {
+    converts(header, summary, fix_options) ||
+        needs_downconversion(header, fix_options) ||
+        checks_alpha(header, fix_options) ||
+        fix_options.thumbnails
+}
+
+fn needs_downconversion(header : &PngHeader, fix_options : &FixOptions) -> bool {
+    header.bit_depth == 16 && fix_options.downconvert_16bit
+}
+
+// 8-bit RGBA images are decoded to check whether every pixel is opaque. 16-bit ones are only
+// checked when they're downconverted anyway.
+fn checks_alpha(header : &PngHeader, fix_options : &FixOptions) -> bool {
+    fix_options.drop_opaque_alpha && header.pixel_format == PixelFormat::TrueColorWithAlpha && header.bit_depth == 8
+}
+
+fn pixel_bytes(image : &image::DynamicImage) -> &[u8] {
+    match image {
+        image::DynamicImage::ImageLuma8(buffer) => buffer,
+        image::DynamicImage::ImageLumaA8(buffer) => buffer,
+        image::DynamicImage::ImageRgb8(buffer) => buffer,
+        image::DynamicImage::ImageRgba8(buffer) => buffer,
+        image::DynamicImage::ImageBgr8(buffer) => buffer,
+        image::DynamicImage::ImageBgra8(buffer) => buffer,
+    }
+}
+
+// Hash of the decoded pixels, read straight from the image's buffer
+fn hash_image(image : &image::DynamicImage) -> u64 {
+    let (width, height) = image.dimensions();
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u32(width);
+    hasher.write_u32(height);
+    hasher.write(pixel_bytes(image));
+    hasher.finish()
+}
+
+// Order of the channels in a row of 8-bit pixels
+#[derive(Debug, Clone, Copy)]
+enum ChannelLayout {
+    Luma,
+    LumaAlpha,
+    Rgb,
+    Rgba,
+    Bgr,
+    Bgra,
+}
+
+impl ChannelLayout {
+    fn channels(self) -> usize {
+        match self {
+            ChannelLayout::Luma => 1,
+            ChannelLayout::LumaAlpha => 2,
+            ChannelLayout::Rgb | ChannelLayout::Bgr => 3,
+            ChannelLayout::Rgba | ChannelLayout::Bgra => 4,
+        }
+    }
+
+    fn to_rgba(self, pixel : &[u8]) -> [u8; 4] {
+        match self {
+            ChannelLayout::Luma => [pixel[0], pixel[0], pixel[0], 255],
+            ChannelLayout::LumaAlpha => [pixel[0], pixel[0], pixel[0], pixel[1]],
+            ChannelLayout::Rgb => [pixel[0], pixel[1], pixel[2], 255],
+            ChannelLayout::Rgba => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            ChannelLayout::Bgr => [pixel[2], pixel[1], pixel[0], 255],
+            ChannelLayout::Bgra => [pixel[2], pixel[1], pixel[0], pixel[3]],
+        }
+    }
+}
+
+fn image_layout(image : &image::DynamicImage) -> ChannelLayout {
+    match image {
+        image::DynamicImage::ImageLuma8(_) => ChannelLayout::Luma,
+        image::DynamicImage::ImageLumaA8(_) => ChannelLayout::LumaAlpha,
+        image::DynamicImage::ImageRgb8(_) => ChannelLayout::Rgb,
+        image::DynamicImage::ImageRgba8(_) => ChannelLayout::Rgba,
+        image::DynamicImage::ImageBgr8(_) => ChannelLayout::Bgr,
+        image::DynamicImage::ImageBgra8(_) => ChannelLayout::Bgra,
+    }
+}
+
+fn png_decode_error(e : png::DecodingError) -> FixError {
+    FixError::Decode(image::ImageError::FormatError(e.to_string()))
+}
+
+// The decoded rows of a PNG, top to bottom. next_row gives the rows of each Adam7 pass in turn,
+// so interlaced images, like those written with --oxipng-interlace 1, are decoded as a whole
+// frame instead.
+enum PngRows<'a> {
+    Streamed(png::Reader<&'a [u8]>),
+    Frame { pixels: Vec<u8>, line_size: usize, offset: usize },
+}
+
+impl<'a> PngRows<'a> {
+    fn new(mut reader : png::Reader<&'a [u8]>, info : &png::OutputInfo) -> FixResult<PngRows<'a>> {
+        if !reader.info().interlaced {
+            return Ok(PngRows::Streamed(reader));
+        }
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).map_err(png_decode_error)?;
+        Ok(PngRows::Frame { pixels, line_size: info.line_size, offset: 0 })
+    }
+
+    fn next_row(&mut self) -> FixResult<Option<&[u8]>> {
+        match self {
+            PngRows::Streamed(reader) => reader.next_row().map_err(png_decode_error),
+            PngRows::Frame { pixels, line_size, offset } => {
+                let row = pixels.get(*offset..*offset + *line_size);
+                *offset += *line_size;
+                Ok(row)
+            },
+        }
+    }
+}
+
+// Hash of the decoded pixels of a PNG, decoding one row at a time. The pixels are expanded the
+// same way the image crate does it, so the hash matches hash_image of the same pixels.
+fn hash_png_rows(data : &[u8]) -> FixResult<u64> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, reader) = decoder.read_info().map_err(png_decode_error)?;
+
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u32(info.width);
+    hasher.write_u32(info.height);
+    let mut rows = PngRows::new(reader, &info)?;
+    while let Some(row) = rows.next_row()? {
+        hasher.write(row);
+    }
+    Ok(hasher.finish())
+}
+
+// Compare the original pixels with the optimized PNG a row at a time, converting both to RGBA.
+// Returns the largest channel difference, or None if the images don't match.
+fn compare_tolerant(before : &image::DynamicImage, after_data : &[u8], tolerance : u8) -> FixResult<Option<u8>> {
+    let mut decoder = png::Decoder::new(after_data);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, reader) = decoder.read_info().map_err(png_decode_error)?;
+
+    if (info.width, info.height) != before.dimensions() || info.bit_depth != png::BitDepth::Eight {
+        return Ok(None);
+    }
+
+    let after_layout = match info.color_type {
+        png::ColorType::Grayscale => ChannelLayout::Luma,
+        png::ColorType::GrayscaleAlpha => ChannelLayout::LumaAlpha,
+        png::ColorType::RGB => ChannelLayout::Rgb,
+        png::ColorType::RGBA => ChannelLayout::Rgba,
+        // EXPAND always turns palettes into RGB(A)
+        png::ColorType::Indexed => return Ok(None),
+    };
+    let before_layout = image_layout(before);
+    let before_stride = info.width as usize * before_layout.channels();
+
+    let mut max_difference = 0;
+    let mut before_rows = pixel_bytes(before).chunks(before_stride);
+    let mut after_rows = PngRows::new(reader, &info)?;
+    while let Some(after_row) = after_rows.next_row()? {
+        let before_row = match before_rows.next() {
+            Some(before_row) => before_row,
+            None => return Ok(None),
+        };
+
+        let before_pixels = before_row.chunks(before_layout.channels());
+        let after_pixels = after_row.chunks(after_layout.channels());
+        for (before_pixel, after_pixel) in before_pixels.zip(after_pixels) {
+            let before_rgba = before_layout.to_rgba(before_pixel);
+            let after_rgba = after_layout.to_rgba(after_pixel);
+            for (before_channel, after_channel) in before_rgba.iter().zip(after_rgba.iter()) {
+                let difference = before_channel.abs_diff(*after_channel);
+                if difference > tolerance {
+                    return Ok(None);
+                }
+                max_difference = max_difference.max(difference);
+            }
+        }
+    }
+
+    Ok(Some(max_difference))
+}
+
+fn optimize(data : &[u8], fix_options : &FixOptions, strip_metadata : bool) -> FixResult<Vec<u8>> {
+    let settings = OptimizeSettings {
+        deinterlace: fix_options.deinterlace,
+        strip_metadata,
+        timeout: fix_options.timeout,
+        reproducible: fix_options.reproducible,
+    };
+    let optimized_data = match &fix_options.optimizer {
+        Some(optimizer) => optimizer.optimize(data, &settings)?,
+        None => Oxipng::default().optimize(data, &settings)?,
+    };
+    if fix_options.reproducible {
+        return Ok(chunks::canonicalize_chunks(&optimized_data)?);
+    }
+    Ok(optimized_data)
+}
+
+fn build_outcome(original_data : &[u8],
+                 optimized_data : &[u8],
+                 timings : FixTimings,
+                 verification : Verification,
+                 thumbnails : Option<Thumbnails>) -> FixResult<FixOutcome> {
+    let pixel_format = match parse_header(optimized_data) {
+        ParseResult::Valid(header) => header.pixel_format,
+        error_parse_result => return Err(FixError::InvalidPng(error_parse_result)),
+    };
+
+    // Images converted from another format had no chunks to begin with
+    let chunks_before = if original_data.starts_with(&EXPECTED_PNG_HEADER) {
+        chunk_types(original_data)?
+    } else {
+        Vec::new()
+    };
+    let chunks_after = chunk_types(optimized_data)?;
+    let (chunks_preserved, chunks_stripped) = chunks_before
+        .into_iter()
+        .partition(|chunk_type| chunks_after.contains(chunk_type));
+
+    Ok(FixOutcome {
+        size_change: SizeChange {
+            before: original_data.len() as u64,
+            after: optimized_data.len() as u64,
+        },
+        pixel_format,
+        chunks_preserved,
+        chunks_stripped,
+        timings,
+        verification,
+        thumbnails,
+    })
+}
+
+// Only recompress the image and strip unneeded chunks. The pixel data is never decoded, so
+// there is nothing to verify afterwards.
+fn optimize_image(original_data : &[u8], fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    let mut timings = FixTimings::default();
+
+    let start = Instant::now();
+    let optimized_data = optimize(original_data, fix_options, true)?;
+    timings.optimize = start.elapsed();
+
+    // Only decode the image if thumbnails were asked for
+    let thumbnails = if fix_options.thumbnails {
+        let original_image = image::load_from_memory(original_data)?;
+        Some(make_thumbnails(&original_image, &optimized_data)?)
+    } else {
+        None
+    };
+
+    let outcome = build_outcome(original_data, &optimized_data, timings, Verification::NotDecoded, thumbnails)?;
+    Ok((optimized_data, outcome))
+}
+
+// Greyscale is decoded as greyscale, so it's expanded to RGB/RGBA by hand. Some other formats
+// decode to BGR/BGRA, which is reordered. Everything else is already decoded to RGB/RGBA.
+pub fn to_truecolor(image : image::DynamicImage) -> image::DynamicImage {
+    match image {
+        image::DynamicImage::ImageLuma8(_) |
+        image::DynamicImage::ImageBgr8(_) => image::DynamicImage::ImageRgb8(image.to_rgb()),
+        image::DynamicImage::ImageLumaA8(_) |
+        image::DynamicImage::ImageBgra8(_) => image::DynamicImage::ImageRgba8(image.to_rgba()),
+        image => image,
+    }
+}
+
+// With drop_opaque_alpha, an RGBA image whose pixels are all opaque becomes RGB, which is about
+// a quarter smaller
+fn drop_alpha_if_opaque(image : image::DynamicImage, fix_options : &FixOptions) -> image::DynamicImage {
+    match image {
+        image::DynamicImage::ImageRgba8(ref buffer)
+            if fix_options.drop_opaque_alpha && buffer.chunks_exact(4).all(|pixel| pixel[3] == 255) => {
+            image::DynamicImage::ImageRgb8(image.to_rgb())
+        },
+        image => image,
+    }
+}
+
+// Decode a 16-bit image to 8 bits per channel, keeping the high byte of each channel
+fn decode_as_8bit(data : &[u8]) -> FixResult<image::DynamicImage> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().map_err(png_decode_error)?;
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buffer).map_err(png_decode_error)?;
+
+    let (width, height) = (info.width, info.height);
+    let image = match info.color_type {
+        png::ColorType::Grayscale => image::ImageBuffer::from_raw(width, height, buffer).map(image::DynamicImage::ImageLuma8),
+        png::ColorType::GrayscaleAlpha => {
+            image::ImageBuffer::from_raw(width, height, buffer).map(image::DynamicImage::ImageLumaA8)
+        },
+        png::ColorType::RGB => image::ImageBuffer::from_raw(width, height, buffer).map(image::DynamicImage::ImageRgb8),
+        png::ColorType::RGBA => image::ImageBuffer::from_raw(width, height, buffer).map(image::DynamicImage::ImageRgba8),
+        // Expanded to RGB/RGBA by the decoder
+        png::ColorType::Indexed => None,
+    };
+    image.ok_or_else(|| FixError::Decode(image::ImageError::FormatError("unexpected decoded image size".to_string())))
+}
+
+// Convert an image to RGB/RGBA format, then optimize it and check the pixels are unchanged
+fn convert_image(original_data : &[u8],
+                 header : &PngHeader,
+                 fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    let start = Instant::now();
+    // The image crate gets low bit depth palettes with partial tRNS chunks wrong
+    let image_before_optimizing = if header.pixel_format == PixelFormat::IndexedColor {
+        palette::expand_indexed(original_data)?
+    } else {
+        to_truecolor(image::load_from_memory(original_data)?)
+    };
+    convert_decoded_image(original_data, drop_alpha_if_opaque(image_before_optimizing, fix_options), start, fix_options)
+}
+
+// Same as convert_image for 16-bit images, except the pixels are compared after reducing them
+// to 8 bits per channel
+fn downconvert_image(original_data : &[u8], fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    let start = Instant::now();
+    let image_before_optimizing = to_truecolor(decode_as_8bit(original_data)?);
+    convert_decoded_image(original_data, drop_alpha_if_opaque(image_before_optimizing, fix_options), start, fix_options)
+}
+
+// Rewrite an RGBA image as RGB if every pixel is opaque. Otherwise it's only worth rewriting if
+// it's being deinterlaced.
+fn drop_opaque_alpha(original_data : &[u8],
+                     header : &PngHeader,
+                     fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    let start = Instant::now();
+    match drop_alpha_if_opaque(image::load_from_memory(original_data)?, fix_options) {
+        image @ image::DynamicImage::ImageRgb8(_) => convert_decoded_image(original_data, image, start, fix_options),
+        _ if header.interlaced && fix_options.deinterlace => optimize_image(original_data, fix_options),
+        _ => Err(FixError::NotOpaque),
+    }
+}
+
+// Decode an image in another format, like BMP, TGA or TIFF, and save it as an optimized
+// truecolor PNG with the same pixels
+pub fn convert_other_data(data : &[u8], fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    let start = Instant::now();
+    let image = to_truecolor(image::load_from_memory(data)?);
+    convert_decoded_image(data, image, start, fix_options)
+}
+
+// Encode the decoded image, which started decoding at start, then optimize and verify it
+fn convert_decoded_image(original_data : &[u8],
+                         image_before_optimizing : image::DynamicImage,
+                         start : Instant,
+                         fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    let mut timings = FixTimings::default();
+
+    //image "0.21.2" will save as RGBA32 format
+    let mut converted_data = Vec::new();
+    image_before_optimizing.write_to(&mut converted_data, image::ImageOutputFormat::PNG)?;
+    timings.convert = start.elapsed();
+
+    let start = Instant::now();
+    let optimized_data = optimize(&converted_data, fix_options, false)?;
+    timings.optimize = start.elapsed();
+
+    // Check the pixels are unchanged, without decoding the whole optimized image
+    let start = Instant::now();
+    let verification = match fix_options.verify {
+        VerifyMode::Exact => {
+            if hash_image(&image_before_optimizing) != hash_png_rows(&optimized_data)? {
+                return Err(FixError::VerificationFailed);
+            }
+            Verification::PixelsIdentical
+        },
+        VerifyMode::Tolerant { tolerance } => {
+            match compare_tolerant(&image_before_optimizing, &optimized_data, tolerance)? {
+                Some(max_difference) => Verification::WithinTolerance { max_difference },
+                None => return Err(FixError::VerificationFailed),
+            }
+        },
+    };
+    timings.verify = start.elapsed();
+
+    let thumbnails = if fix_options.thumbnails {
+        Some(make_thumbnails(&image_before_optimizing, &optimized_data)?)
+    } else {
+        None
+    };
+
+    let outcome = build_outcome(original_data, &optimized_data, timings, verification, thumbnails)?;
+    Ok((optimized_data, outcome))
+}
+
+// Scale an image down so neither side is larger than max_dimension, keeping its aspect ratio, and
+// return the optimized PNG with its header. Unlike fixing, this changes the pixels, so there's
+// nothing to verify.
+pub fn resize_to_fit(data : &[u8],
+                     header : &PngHeader,
+                     max_dimension : u32,
+                     fix_options : &FixOptions) -> FixResult<(Vec<u8>, PngHeader)> {
+    check_decode_limits(header, fix_options)?;
+    let image = if header.pixel_format == PixelFormat::IndexedColor {
+        palette::expand_indexed(data)?
+    } else if header.bit_depth == 16 {
+        to_truecolor(decode_as_8bit(data)?)
+    } else {
+        to_truecolor(image::load_from_memory(data)?)
+    };
+
+    let mut resized_data = Vec::new();
+    image.resize(max_dimension, max_dimension, image::FilterType::Lanczos3)
+        .write_to(&mut resized_data, image::ImageOutputFormat::PNG)?;
+    let optimized_data = optimize(&resized_data, fix_options, false)?;
+
+    match parse_header(&optimized_data) {
+        ParseResult::Valid(resized_header) => Ok((optimized_data, resized_header)),
+        error_parse_result => Err(FixError::InvalidPng(error_parse_result)),
+    }
+}
+
+// Fix a PNG held in memory, returning the new file contents. Uses the chunks to decide whether
+// the full convert/verify pipeline is needed.
+pub fn fix_data(data : &[u8],
+                header : &PngHeader,
+                summary : &ChunkSummary,
+                fix_options : &FixOptions) -> FixResult<(Vec<u8>, FixOutcome)> {
+    if summary.animated && !fix_options.force_apng {
+        return Err(FixError::Animated);
+    }
+
+    if needs_decoding(header, summary, fix_options) {
+        check_decode_limits(header, fix_options)?;
+    }
+
+    let (fixed_data, outcome) = if needs_downconversion(header, fix_options) {
+        downconvert_image(data, fix_options)?
+    } else if converts(header, summary, fix_options) {
+        convert_image(data, header, fix_options)?
+    } else if checks_alpha(header, fix_options) {
+        drop_opaque_alpha(data, header, fix_options)?
+    } else {
+        optimize_image(data, fix_options)?
+    };
+
+    if !fix_options.force {
+        let size_change = outcome.size_change;
+        if size_change.after > size_change.before {
+            return Err(FixError::WouldGrow(size_change));
+        }
+        if let Some(min_savings) = fix_options.min_savings {
+            if !min_savings.is_met(&size_change) {
+                return Err(FixError::BelowMinSavings(size_change));
+            }
+        }
+    }
+
+    Ok((fixed_data, outcome))
+}
+
+// Fix a PNG file in place. The file is only overwritten once the new image has been verified.
+pub fn fix(path : &Path, fix_options : &FixOptions) -> FixResult<FixOutcome> {
+    let original_data = fs::read(path)?;
+
+    let header = match parse_header(&original_data) {
+        ParseResult::Valid(header) => header,
+        error_parse_result => return Err(FixError::InvalidPng(error_parse_result)),
+    };
+    let summary = chunks::read_chunks(&mut Cursor::new(&original_data))?;
+
+    let (fixed_data, outcome) = fix_data(&original_data, &header, &summary, fix_options)?;
+    crate::atomic_write::write_atomic(path, &fixed_data, &Default::default())?;
+    Ok(outcome)
+}
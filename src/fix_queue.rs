@@ -0,0 +1,103 @@
+use log::info;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Files waiting for a fix thread. Scanning waits while the queue is full, so it can't get
+// arbitrarily far ahead.
+const QUEUE_SIZE: usize = 64;
+
+// How often the progress of both stages is printed
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Checked often, so the progress thread stops soon after the last file is done
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Progress {
+    scanned: AtomicUsize,
+    queued: AtomicUsize,
+    fixed: AtomicUsize,
+    done: AtomicBool,
+}
+
+// Stops the progress thread when dropped, so a panic in either stage can't leave the scope
+// waiting for it forever
+struct DoneOnDrop<'a>(&'a Progress);
+
+impl Drop for DoneOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.done.store(true, Ordering::Relaxed);
+    }
+}
+
+fn print_progress(progress : &Progress, num_paths : usize) {
+    let mut last_printed = Instant::now();
+    while !progress.done.load(Ordering::Relaxed) {
+        thread::sleep(PROGRESS_POLL_INTERVAL);
+        if last_printed.elapsed() < PROGRESS_INTERVAL {
+            continue;
+        }
+        let queued = progress.queued.load(Ordering::Relaxed);
+        let fixed = progress.fixed.load(Ordering::Relaxed);
+        info!("Scanned {} of {} files, {} fixed, {} waiting to be fixed",
+              progress.scanned.load(Ordering::Relaxed), num_paths, fixed, queued.saturating_sub(fixed));
+        last_printed = Instant::now();
+    }
+}
+
+// Handle the files in two stages, for --fix-jobs. The files are scanned on the current pool, and
+// the ones which needs_fixing picks out are queued for fix_pool, so a folder of mostly fine
+// images carries on at full speed instead of waiting behind the few being decoded and optimized.
+//
+// Both stages use handle_path, which gets the index of the path as well. The results are in no
+// particular order. If a fix thread panicked, its panic is returned instead.
+pub fn scan_then_fix<R, N, H>(paths : &[PathBuf], fix_pool : &ThreadPool, needs_fixing : N, handle_path : H)
+    -> thread::Result<Vec<R>>
+    where R: Send,
+          N: Fn(&Path) -> bool + Sync,
+          H: Fn((usize, &PathBuf)) -> R + Sync {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let progress = Progress::default();
+
+    thread::scope(|scope| {
+        let fixing = scope.spawn(|| fix_pool.install(|| {
+            receiver.into_iter()
+                .par_bridge()
+                .map(|indexed_path| {
+                    let result = handle_path(indexed_path);
+                    progress.fixed.fetch_add(1, Ordering::Relaxed);
+                    result
+                })
+                .collect::<Vec<_>>()
+        }));
+        scope.spawn(|| print_progress(&progress, paths.len()));
+        let _done = DoneOnDrop(&progress);
+
+        let mut results : Vec<R> = paths.iter()
+            .enumerate()
+            .par_bridge()
+            .filter_map(|(index, path)| {
+                let result = if needs_fixing(path) {
+                    progress.queued.fetch_add(1, Ordering::Relaxed);
+                    // Only fails if the fix threads panicked, which is reported when they're joined
+                    let _ = sender.send((index, path));
+                    None
+                } else {
+                    Some(handle_path((index, path)))
+                };
+                progress.scanned.fetch_add(1, Ordering::Relaxed);
+                result
+            })
+            .collect();
+        // Lets the fix threads finish once the queue is empty
+        drop(sender);
+
+        results.extend(fixing.join()?);
+        Ok(results)
+    })
+}
@@ -0,0 +1,73 @@
+use git2::{Delta, DiffOptions, Repository};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Files whose new version should be scanned
+fn is_added_or_modified(status : Delta) -> bool {
+    matches!(status, Delta::Added | Delta::Modified | Delta::Renamed | Delta::Copied | Delta::Typechange)
+}
+
+fn canonicalize(path : &Path) -> Result<PathBuf, git2::Error> {
+    fs::canonicalize(path).map_err(|e| git2::Error::from_str(&format!("can't resolve {}: {}", path.display(), e)))
+}
+
+// PNGs under scan_path which were added or modified since git_ref, or which differ from the
+// index if there's no ref, for --git-changed. Untracked PNGs count as added. Returns the paths
+// relative to scan_path, sorted.
+pub fn changed_pngs(scan_path : &Path, git_ref : Option<&str>) -> Result<Vec<PathBuf>, git2::Error> {
+    let repo = Repository::discover(scan_path)?;
+    // The paths in a diff are relative to the top of the working tree, which may be above scan_path
+    let workdir = canonicalize(repo.workdir().ok_or_else(|| git2::Error::from_str("the repository is bare"))?)?;
+    let canonical_scan_path = canonicalize(scan_path)?;
+
+    let mut diff_options = DiffOptions::new();
+    diff_options.include_untracked(true).recurse_untracked_dirs(true);
+    let diff = match git_ref {
+        Some(git_ref) => {
+            let tree = repo.revparse_single(git_ref)?.peel_to_tree()?;
+            repo.diff_tree_to_workdir_with_index(Some(&tree), Some(&mut diff_options))?
+        },
+        None => repo.diff_index_to_workdir(None, Some(&mut diff_options))?,
+    };
+
+    let mut rel_paths : Vec<PathBuf> = diff.deltas()
+        .filter(|delta| is_added_or_modified(delta.status()) || delta.status() == Delta::Untracked)
+        .filter_map(|delta| delta.new_file().path().map(|path| workdir.join(path)))
+        .filter_map(|path| path.strip_prefix(&canonical_scan_path).ok().map(Path::to_path_buf))
+        .filter(|rel_path| crate::is_png(rel_path))
+        .collect();
+    rel_paths.sort();
+    rel_paths.dedup();
+    Ok(rel_paths)
+}
+
+// Every PNG added or modified in the index, with its staged contents, which may differ from the
+// working tree. The paths are relative to the top of the working tree.
+pub fn staged_pngs(repo : &Repository) -> Result<Vec<(PathBuf, Vec<u8>)>, git2::Error> {
+    // Before the first commit everything in the index is new
+    let head_tree = match repo.head() {
+        Ok(head) => Some(head.peel_to_tree()?),
+        Err(_e) => None,
+    };
+    let diff = repo.diff_tree_to_index(head_tree.as_ref(), None, None)?;
+
+    let mut staged = Vec::new();
+    for delta in diff.deltas().filter(|delta| is_added_or_modified(delta.status())) {
+        let rel_path = match delta.new_file().path() {
+            Some(rel_path) if crate::is_png(rel_path) => rel_path.to_path_buf(),
+            _ => continue,
+        };
+        let blob = repo.find_blob(delta.new_file().id())?;
+        staged.push((rel_path, blob.content().to_vec()));
+    }
+    Ok(staged)
+}
+
+// Stage the working tree version of each file, like git add
+pub fn stage(repo : &Repository, rel_paths : &[PathBuf]) -> Result<(), git2::Error> {
+    let mut index = repo.index()?;
+    for rel_path in rel_paths {
+        index.add_path(rel_path)?;
+    }
+    index.write()
+}
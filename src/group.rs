@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use log::info;
+use crate::{FindingKind, ScanSummary};
+
+#[derive(Default)]
+struct DirectorySummary {
+    num_scanned: usize,
+    num_indexed: usize,
+    num_fixed: usize,
+    bytes_saved: i64,
+}
+
+fn directory_name(rel_path : &Path) -> String {
+    match rel_path.parent() {
+        Some(parent) if parent != Path::new("") => crate::slash_path(parent),
+        _ => String::from("."),
+    }
+}
+
+// Print a table of results for each folder containing PNGs, sorted by folder name
+pub fn print_directory_summary(summary : &ScanSummary) {
+    let mut directories : BTreeMap<String, DirectorySummary> = BTreeMap::new();
+
+    for scanned_file in &summary.scanned {
+        directories.entry(directory_name(&scanned_file.rel_path)).or_default().num_scanned += 1;
+    }
+    for finding in &summary.findings {
+        if let FindingKind::Indexed { .. } = finding.kind {
+            directories.entry(directory_name(&finding.rel_path)).or_default().num_indexed += 1;
+        }
+    }
+    for fixed_file in &summary.fixed {
+        let directory = directories.entry(directory_name(&fixed_file.rel_path)).or_default();
+        directory.num_fixed += 1;
+        directory.bytes_saved += fixed_file.size_change.saved();
+    }
+
+    info!("{:>8} {:>8} {:>8} {:>12}  Folder", "Scanned", "Indexed", "Fixed", "Saved");
+    for (name, directory) in &directories {
+        info!("{:>8} {:>8} {:>8} {:>10.1}KB  {}",
+              directory.num_scanned,
+              directory.num_indexed,
+              directory.num_fixed,
+              directory.bytes_saved as f32 / 1000f32,
+              name);
+    }
+}
@@ -0,0 +1,90 @@
+use git2::Repository;
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::{error, info};
+use crate::{FileResult, ScanOptions};
+
+fn needs_fixing(result : &FileResult) -> bool {
+    result.findings.iter().any(|kind| kind.is_disallowed())
+}
+
+// Fix the staged version of a PNG and write it over the working tree copy, returning true if it
+// needs staging again. Fails if it still needs fixing afterwards.
+fn fix_staged(workdir : &Path, rel_path : &Path, staged_data : &[u8], options : &ScanOptions) -> Result<bool, String> {
+    let (result, fixed_data) = crate::fix_archive_member(rel_path, staged_data, options);
+    let fixed_data = match fixed_data {
+        Some(fixed_data) => fixed_data,
+        None if needs_fixing(&result) => return Err(String::from("it couldn't be fixed")),
+        None => return Ok(false),
+    };
+
+    // Staging it again would stage the rest of the changes too
+    let path = workdir.join(rel_path);
+    if fs::read(&path).ok().as_deref() != Some(staged_data) {
+        return Err(String::from("it has changes which aren't staged, stage or stash them first"));
+    }
+    options.io_limiter.write(&path, &fixed_data).map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+// git's pre-commit hook, from hook pre-commit, run at the top of the working tree. The staged
+// version of every PNG in the commit is scanned. With --check the commit is stopped if any need
+// fixing, otherwise they're fixed, staged again and only stop the commit if they can't be fixed.
+// Returns the exit code, anything but 0 stops the commit.
+pub fn pre_commit(options : &ScanOptions) -> i32 {
+    let repo = match Repository::discover(".") {
+        Ok(repo) => repo,
+        Err(e) => {
+            error!("Error: not in a git repository: {}", e);
+            return crate::EXIT_ERRORS;
+        },
+    };
+    let staged = match crate::git::staged_pngs(&repo) {
+        Ok(staged) => staged,
+        Err(e) => {
+            error!("Error: can't read the staged files: {}", e);
+            return crate::EXIT_ERRORS;
+        },
+    };
+    let workdir = repo.workdir().unwrap_or_else(|| Path::new("."));
+
+    let mut num_blocking = 0;
+    let mut fixed : Vec<PathBuf> = Vec::new();
+    for (rel_path, staged_data) in &staged {
+        if options.check_only {
+            let result = crate::archive_member_result(rel_path, png_header_scanner::parse_bytes(staged_data), options);
+            if needs_fixing(&result) {
+                error!("Error: {} needs fixing", rel_path.display());
+                num_blocking += 1;
+            }
+            continue;
+        }
+        match fix_staged(workdir, rel_path, staged_data, options) {
+            Ok(true) => fixed.push(rel_path.clone()),
+            Ok(false) => {},
+            Err(e) => {
+                error!("Error: {} needs fixing, but {}", rel_path.display(), e);
+                num_blocking += 1;
+            },
+        }
+    }
+
+    if !fixed.is_empty() {
+        if let Err(e) = crate::git::stage(&repo, &fixed) {
+            error!("Error: can't stage the fixed PNGs: {}", e);
+            return crate::EXIT_ERRORS;
+        }
+        changed!("Fixed and staged {} PNGs", fixed.len());
+    }
+
+    if num_blocking == 0 {
+        info!("Checked {} staged PNGs", staged.len());
+        return crate::EXIT_NOTHING_TO_FIX;
+    }
+    error!("Error: stopping the commit, {} of {} staged PNGs need fixing", num_blocking, staged.len());
+    if options.check_only {
+        crate::EXIT_FIXED
+    } else {
+        crate::EXIT_ERRORS
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use crate::ScanSummary;
+
+fn escape(text : &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn thumbnail_img(png_data : &[u8]) -> String {
+    format!("<img src=\"data:image/png;base64,{}\">", base64::encode(png_data))
+}
+
+// Write an HTML page listing each fixed file, with thumbnails so the images can be visually
+// compared. The thumbnails are embedded, so the report is a single self contained file.
+pub fn write_report(output_path : &Path, summary : &ScanSummary) -> io::Result<()> {
+    let mut out = File::create(output_path)?;
+
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html><head><meta charset=\"utf-8\"><title>PNG Header Scanner report</title>")?;
+    writeln!(out, "<style>")?;
+    writeln!(out, "table {{ border-collapse: collapse; }}")?;
+    writeln!(out, "td, th {{ border: 1px solid #ccc; padding: 4px 8px; }}")?;
+    writeln!(out, "img {{ background: repeating-conic-gradient(#ddd 0% 25%, #fff 0% 50%) 50% / 16px 16px; }}")?;
+    writeln!(out, "</style></head><body>")?;
+    writeln!(out, "<h1>PNG Header Scanner report</h1>")?;
+    writeln!(out, "<p>Scanned {} PNGs, fixed {} indexed PNGs.</p>", summary.num_scanned(), summary.num_fixed())?;
+
+    writeln!(out, "<table>")?;
+    writeln!(out, "<tr><th>File</th><th>Dimensions</th><th>Before</th><th>After</th><th>Size change</th></tr>")?;
+    for fixed_file in &summary.fixed {
+        let (before_img, after_img) = match &fixed_file.thumbnails {
+            Some(thumbnails) => (thumbnail_img(&thumbnails.before), thumbnail_img(&thumbnails.after)),
+            None => (String::new(), String::new()),
+        };
+        writeln!(out, "<tr><td>{}</td><td>{}&times;{}</td><td>{}<br>{:.1}KB</td><td>{}<br>{:.1}KB</td><td>{:+.1}KB</td></tr>",
+                 escape(&crate::slash_path(&fixed_file.rel_path)),
+                 fixed_file.width,
+                 fixed_file.height,
+                 before_img,
+                 fixed_file.size_change.before as f32 / 1000f32,
+                 after_img,
+                 fixed_file.size_change.after as f32 / 1000f32,
+                 -fixed_file.size_change.saved() as f32 / 1000f32)?;
+    }
+    writeln!(out, "</table>")?;
+    writeln!(out, "</body></html>")?;
+
+    Ok(())
+}
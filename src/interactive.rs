@@ -0,0 +1,64 @@
+use png_header_scanner::PngHeader;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct PromptState {
+    // Answered fix all, so the rest are fixed without asking
+    fix_all: bool,
+    // Answered quit, so nothing else is fixed
+    quit: bool,
+}
+
+// Asks before each image is fixed, for --interactive. Only one question is asked at a time.
+#[derive(Default)]
+pub struct Prompt {
+    state: Mutex<PromptState>,
+}
+
+impl Prompt {
+    // Whether the image should be fixed. Quitting stops the run like Ctrl-C, so it can be resumed.
+    pub fn confirm_fix(&self, rel_path : &Path, header : &PngHeader, file_size : u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.quit || crate::is_interrupted() {
+            return false;
+        }
+        if state.fix_all {
+            return true;
+        }
+
+        let stdin = io::stdin();
+        loop {
+            print!("{}: {}x{}, {:?}, {}-bit, {:.1}KB. [f]ix, [s]kip, fix [a]ll or [q]uit? ",
+                   rel_path.display(),
+                   header.width,
+                   header.height,
+                   header.pixel_format,
+                   header.bit_depth,
+                   file_size as f32 / 1000f32);
+            io::stdout().flush().expect("Failed to write the prompt");
+
+            let mut answer = String::new();
+            // Nobody left to answer once stdin is closed
+            if stdin.lock().read_line(&mut answer).expect("Failed to read the answer") == 0 {
+                println!();
+                answer = "q".to_string();
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "f" | "fix" | "y" | "yes" => return true,
+                "s" | "skip" | "n" | "no" => return false,
+                "a" | "all" => {
+                    state.fix_all = true;
+                    return true;
+                },
+                "q" | "quit" => {
+                    state.quit = true;
+                    crate::interrupt();
+                    return false;
+                },
+                _ => println!("Please answer f, s, a or q"),
+            }
+        }
+    }
+}
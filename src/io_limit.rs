@@ -0,0 +1,229 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use log::warn;
+use png_header_scanner::atomic_write::{self, WriteOptions};
+use crate::journal::Journal;
+use crate::output_tree::OutputTree;
+use crate::staging::Staging;
+
+// Limits how many files are being read or written at the same time, independently of how many
+// worker threads there are. Network shares start throttling or failing when too many requests
+// are in flight at once.
+//
+// All reads and writes of scanned files go through here, so it is also the one place which
+// refuses writes when the files must not be modified.
+pub struct IoLimiter {
+    // None means unlimited
+    available: Option<Mutex<usize>>,
+    released: Condvar,
+    read_only: bool,
+    write_options: WriteOptions,
+    // Redirects every write into another folder, from --out
+    output_tree: Option<OutputTree>,
+    // Keeps the originals of changed files, so the run can be undone
+    journal: Option<Journal>,
+    // Holds every write until the end of the run, from --transactional
+    staging: Option<Staging>,
+}
+
+pub struct IoPermit<'a> {
+    limiter: &'a IoLimiter,
+}
+
+impl IoLimiter {
+    pub fn new(max_concurrent : Option<usize>, read_only : bool, write_options : WriteOptions) -> IoLimiter {
+        IoLimiter {
+            available: max_concurrent.map(Mutex::new),
+            released: Condvar::new(),
+            read_only,
+            write_options,
+            output_tree: None,
+            journal: None,
+            staging: None,
+        }
+    }
+
+    pub fn with_output_tree(self, output_tree : OutputTree) -> IoLimiter {
+        IoLimiter { output_tree: Some(output_tree), ..self }
+    }
+
+    pub fn with_journal(self, journal : Journal) -> IoLimiter {
+        IoLimiter { journal: Some(journal), ..self }
+    }
+
+    pub fn with_staging(self, staging : Staging) -> IoLimiter {
+        IoLimiter { staging: Some(staging), ..self }
+    }
+
+    pub fn is_staging(&self) -> bool {
+        self.staging.is_some()
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    // Where to read the latest version of a file, which may have been written to the output tree
+    pub fn current_path(&self, path : &Path) -> PathBuf {
+        match (&self.output_tree, &self.staging) {
+            (Some(output_tree), _) => output_tree.current_path(path),
+            (None, Some(staging)) => staging.current_path(path),
+            (None, None) => path.to_path_buf(),
+        }
+    }
+
+    // Block until fewer than the maximum number of file operations are running
+    pub fn acquire(&self) -> IoPermit<'_> {
+        if let Some(available) = &self.available {
+            let mut available = available.lock().unwrap();
+            while *available == 0 {
+                available = self.released.wait(available).unwrap();
+            }
+            *available -= 1;
+        }
+
+        IoPermit { limiter: self }
+    }
+
+    pub fn read(&self, path : &Path) -> io::Result<Vec<u8>> {
+        let _permit = self.acquire();
+        fs::read(self.current_path(path))
+    }
+
+    // Read-only files are refused by writes unless --force-readonly was given. Originals are
+    // never written with an output tree, so it doesn't matter there.
+    pub fn skips_read_only_file(&self, path : &Path) -> bool {
+        self.output_tree.is_none() && !self.write_options.force_readonly && atomic_write::is_read_only(path)
+    }
+
+    pub fn write(&self, path : &Path, data : &[u8]) -> io::Result<()> {
+        // Checked before opening the file, so no write is ever attempted
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("refusing to write {} in read-only mode", path.display())));
+        }
+
+        if let Some(output_tree) = &self.output_tree {
+            let _permit = self.acquire();
+            let output_path = output_tree.prepare_write(path)?;
+            // Copies of read-only originals are read-only too, but they're this tool's to replace
+            let write_options = WriteOptions { force_readonly: true, ..self.write_options };
+            return atomic_write::write_atomic(&output_path, data, &write_options);
+        }
+
+        if let Some(staging) = &self.staging {
+            let _permit = self.acquire();
+            return staging.write(path, data);
+        }
+
+        self.write_in_place(path, data)
+    }
+
+    fn write_in_place(&self, path : &Path, data : &[u8]) -> io::Result<()> {
+        let hard_links = atomic_write::hard_link_count(path);
+        if hard_links > 1 {
+            warn!("Warning: {} has {} hard links, rewriting it changes all of them", path.display(), hard_links);
+        }
+
+        let _permit = self.acquire();
+        if let Some(journal) = &self.journal {
+            journal.record_write(path, data)?;
+        }
+        atomic_write::write_atomic(path, data, &self.write_options)
+    }
+
+    pub fn remove_file(&self, path : &Path) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("refusing to remove {} in read-only mode", path.display())));
+        }
+
+        let _permit = self.acquire();
+        match (&self.output_tree, &self.staging) {
+            (Some(output_tree), _) => output_tree.remove(path),
+            (None, Some(staging)) => staging.remove(path),
+            (None, None) => self.remove_in_place(path),
+        }
+    }
+
+    fn remove_in_place(&self, path : &Path) -> io::Result<()> {
+        if let Some(journal) = &self.journal {
+            journal.record_remove(path)?;
+        }
+        fs::remove_file(path)
+    }
+
+    // Apply every change held back by --transactional, returning how many files were changed. If
+    // one fails, the ones before it stay applied, and can be undone from the journal.
+    pub fn commit_staged(&self) -> io::Result<usize> {
+        let staging = match &self.staging {
+            Some(staging) => staging,
+            None => return Ok(0),
+        };
+        let changes = staging.changes();
+        for (path, staged_path) in &changes {
+            match staged_path {
+                Some(staged_path) => self.write_in_place(path, &fs::read(staged_path)?)?,
+                None => {
+                    let _permit = self.acquire();
+                    self.remove_in_place(path)?
+                },
+            }
+        }
+        staging.clear()?;
+        Ok(changes.len())
+    }
+
+    // Drop every change held back by --transactional, leaving the folder as it was
+    pub fn discard_staged(&self) -> io::Result<()> {
+        match &self.staging {
+            Some(staging) => staging.clear(),
+            None => Ok(()),
+        }
+    }
+
+    // Copy a file which wasn't written across to the output tree, if there is one
+    pub fn mirror_if_unchanged(&self, path : &Path) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let _permit = self.acquire();
+        match &self.output_tree {
+            Some(output_tree) => output_tree.mirror_if_unchanged(path),
+            None => Ok(()),
+        }
+    }
+
+    // Rename when possible, falling back to copying when the destination is on another drive
+    pub fn move_file(&self, from : &Path, to : &Path) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("refusing to move {} in read-only mode", from.display())));
+        }
+
+        let _permit = self.acquire();
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Some(journal) = &self.journal {
+            journal.record_move(from, to)?;
+        }
+        if fs::rename(from, to).is_ok() {
+            return Ok(());
+        }
+        fs::copy(from, to)?;
+        fs::remove_file(from)
+    }
+}
+
+impl<'a> Drop for IoPermit<'a> {
+    fn drop(&mut self) {
+        if let Some(available) = &self.limiter.available {
+            *available.lock().unwrap() += 1;
+            self.limiter.released.notify_one();
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use png_header_scanner::atomic_write;
+
+// Kept at the root of the scanned folder, with a folder for each run which changed anything
+pub const JOURNAL_DIR_NAME: &str = ".png_header_scanner_journal";
+
+const ENTRIES_FILE_NAME: &str = "entries.jsonl";
+
+// Copies of the files as they were before the run, named by their hash. They don't have a .png
+// extension, so scanning the folder never picks them up.
+const ORIGINALS_DIR_NAME: &str = "originals";
+
+fn sha256_hex(data : &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// Records every change a run makes to the scanned files, with a copy of each original, so undo
+// can put them back later without a version control checkout. Nothing is written to the journal
+// until the first change.
+pub struct Journal {
+    scan_path: PathBuf,
+    run_id: String,
+    entries: Mutex<Option<File>>,
+}
+
+impl Journal {
+    // Runs are named after the time they started, so they sort in order
+    pub fn new(scan_path : &Path) -> Journal {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        Journal {
+            scan_path: scan_path.to_path_buf(),
+            run_id: format!("{}-{}", secs, std::process::id()),
+            entries: Mutex::new(None),
+        }
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    // Whether this run changed any files
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_none()
+    }
+
+    fn run_path(&self) -> PathBuf {
+        self.scan_path.join(JOURNAL_DIR_NAME).join(&self.run_id)
+    }
+
+    fn rel_path(&self, path : &Path) -> String {
+        crate::slash_path(path.strip_prefix(&self.scan_path).unwrap_or(path))
+    }
+
+    // Keep a copy of the file as it is now, returning its hash
+    fn back_up(&self, data : &[u8]) -> io::Result<String> {
+        let hash = sha256_hex(data);
+        let originals_path = self.run_path().join(ORIGINALS_DIR_NAME);
+        let backup_path = originals_path.join(&hash);
+        if !backup_path.exists() {
+            fs::create_dir_all(&originals_path)?;
+            atomic_write::write_atomic(&backup_path, data, &Default::default())?;
+        }
+        Ok(hash)
+    }
+
+    // Each entry is written and flushed before the change is made, so even a run which is killed
+    // can be undone
+    fn append(&self, entry : Value) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.is_none() {
+            fs::create_dir_all(self.run_path())?;
+            let entries_path = self.run_path().join(ENTRIES_FILE_NAME);
+            *entries = Some(OpenOptions::new().create(true).append(true).open(entries_path)?);
+        }
+        let file = entries.as_mut().unwrap();
+        writeln!(file, "{}", entry)?;
+        file.sync_data()
+    }
+
+    // Before path is replaced with data
+    pub fn record_write(&self, path : &Path, data : &[u8]) -> io::Result<()> {
+        let original = match fs::read(path) {
+            Ok(original_data) => Some(self.back_up(&original_data)?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        self.append(json!({
+            "action": "write",
+            "path": self.rel_path(path),
+            "original": original,
+            "written": sha256_hex(data),
+        }))
+    }
+
+    // Before path is deleted
+    pub fn record_remove(&self, path : &Path) -> io::Result<()> {
+        let original = self.back_up(&fs::read(path)?)?;
+        self.append(json!({
+            "action": "remove",
+            "path": self.rel_path(path),
+            "original": original,
+        }))
+    }
+
+    // Before from is moved to to, which may be outside the scanned folder
+    pub fn record_move(&self, from : &Path, to : &Path) -> io::Result<()> {
+        let to = fs::canonicalize(to.parent().unwrap_or(to))
+            .map(|parent| parent.join(to.file_name().unwrap_or_default()))
+            .unwrap_or_else(|_e| to.to_path_buf());
+        self.append(json!({
+            "action": "move",
+            "path": self.rel_path(from),
+            "to": to.to_string_lossy().into_owned(),
+            "written": sha256_hex(&fs::read(from)?),
+        }))
+    }
+}
+
+// The runs recorded under scan_path, oldest first
+fn list_runs(scan_path : &Path) -> io::Result<Vec<String>> {
+    let journal_path = scan_path.join(JOURNAL_DIR_NAME);
+    let mut runs : Vec<String> = match fs::read_dir(&journal_path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    runs.sort_by_key(|run_id| run_order(run_id));
+    Ok(runs)
+}
+
+// The start time, then the process id
+fn run_order(run_id : &str) -> (u64, u64) {
+    let (secs, process_id) = run_id.split_once('-').unwrap_or((run_id, "0"));
+    (secs.parse().unwrap_or(0), process_id.parse().unwrap_or(0))
+}
+
+fn file_hash(path : &Path) -> Option<String> {
+    fs::read(path).ok().map(|data| sha256_hex(&data))
+}
+
+// Undo one entry, returning false if the file was changed again since, so it's left alone
+fn undo_entry(scan_path : &Path, run_path : &Path, entry : &Value) -> io::Result<bool> {
+    let field = |name : &str| entry.get(name).and_then(|value| value.as_str());
+    let path = scan_path.join(field("path").unwrap_or_default());
+    let read_original = |hash : &str| fs::read(run_path.join(ORIGINALS_DIR_NAME).join(hash));
+
+    match field("action") {
+        Some("write") => {
+            if file_hash(&path).as_deref() != field("written") {
+                return Ok(false);
+            }
+            match field("original") {
+                Some(original) => atomic_write::write_atomic(&path, &read_original(original)?, &Default::default())?,
+                // It didn't exist before the run
+                None => fs::remove_file(&path)?,
+            }
+        },
+        Some("remove") => {
+            if path.exists() {
+                return Ok(false);
+            }
+            let original = read_original(field("original").unwrap_or_default())?;
+            atomic_write::write_atomic(&path, &original, &Default::default())?;
+        },
+        Some("move") => {
+            let to = PathBuf::from(field("to").unwrap_or_default());
+            if path.exists() || file_hash(&to).as_deref() != field("written") {
+                return Ok(false);
+            }
+            if fs::rename(&to, &path).is_err() {
+                fs::copy(&to, &path)?;
+                fs::remove_file(&to)?;
+            }
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown journal entry")),
+    }
+    Ok(true)
+}
+
+// Put back every file changed by the latest run under scan_path, or by every run since the one
+// named since, newest first. A run's journal is deleted once all of it is undone, and kept if any
+// of its files were changed again since, so nothing is overwritten. Returns the number of files
+// restored.
+pub fn undo(scan_path : &Path, since : Option<&str>) -> io::Result<usize> {
+    let runs = list_runs(scan_path)?;
+    let runs_to_undo : Vec<&String> = match since {
+        Some(since) => runs.iter().filter(|run_id| run_order(run_id) >= run_order(since)).collect(),
+        None => runs.last().into_iter().collect(),
+    };
+    if runs_to_undo.is_empty() {
+        info!("No runs to undo in [{}]", scan_path.display());
+        return Ok(0);
+    }
+
+    let mut num_restored = 0;
+    for run_id in runs_to_undo.into_iter().rev() {
+        let run_path = scan_path.join(JOURNAL_DIR_NAME).join(run_id);
+        let entries : Vec<Value> = BufReader::new(File::open(run_path.join(ENTRIES_FILE_NAME))?)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<io::Result<_>>()?;
+
+        let mut num_skipped = 0;
+        for entry in entries.iter().rev() {
+            let rel_path = entry.get("path").and_then(|path| path.as_str()).unwrap_or_default();
+            if undo_entry(scan_path, &run_path, entry)? {
+                changed!("Restored {}", rel_path);
+                num_restored += 1;
+            } else {
+                warn!("Warning: not restoring {}, it was changed after run {}", rel_path, run_id);
+                num_skipped += 1;
+            }
+        }
+
+        if num_skipped == 0 {
+            fs::remove_dir_all(&run_path)?;
+        } else {
+            warn!("Warning: kept the journal of run {} in [{}], {} files couldn't be restored",
+                  run_id, run_path.display(), num_skipped);
+        }
+    }
+    Ok(num_restored)
+}
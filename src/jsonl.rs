@@ -0,0 +1,40 @@
+use serde_json::{json, Value};
+use std::path::Path;
+use crate::FileResult;
+
+// The result for a single file, also what --daemon answers a fix request with
+pub fn file_result_json(rel_path : &Path, result : &FileResult) -> Value {
+    let findings : Vec<_> = result.findings.iter()
+        .map(|kind| json!({
+            "id": kind.id(),
+            "message": kind.description(),
+        }))
+        .collect();
+
+    json!({
+        "path": crate::slash_path(rel_path),
+        "findings": findings,
+        "fixed": result.fix_outcome.is_some(),
+        "size_before": result.fix_outcome.as_ref().map(|fix_outcome| fix_outcome.size_change.before),
+        "size_after": result.fix_outcome.as_ref().map(|fix_outcome| fix_outcome.size_change.after),
+        "palette": result.palette_report.map(|report| json!({
+            "entries": report.entries,
+            "used_entries": report.used_entries,
+            "duplicate_entries": report.duplicate_entries,
+            "greyscale": report.greyscale,
+            "transparent": report.transparent,
+        })),
+        "physical": result.physical.map(|physical| json!({
+            "pixels_per_unit_x": physical.pixels_per_unit_x,
+            "pixels_per_unit_y": physical.pixels_per_unit_y,
+            "unit": if physical.in_meters { "meter" } else { "unknown" },
+            "dpi": physical.dpi().map(|(dpi_x, dpi_y)| json!({ "x": dpi_x, "y": dpi_y })),
+            "pixel_aspect_ratio": physical.pixel_aspect_ratio(),
+        })),
+    })
+}
+
+// Print the result for a single file as one line of JSON on stdout
+pub fn print_file_result(rel_path : &Path, result : &FileResult) {
+    println!("{}", file_result_json(rel_path, result));
+}
@@ -0,0 +1,248 @@
+use std::fs::File;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+pub mod atomic_write;
+pub mod cgbi;
+pub mod chunks;
+pub mod export;
+pub mod ffi;
+pub mod fix;
+pub mod optimizer;
+#[cfg(feature = "fix")]
+pub mod palette;
+pub mod validate;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PixelFormat {
+    Greyscale,
+    TrueColor,
+    IndexedColor,
+    GreyscaleWithAlpha,
+    TrueColorWithAlpha,
+}
+
+// The IHDR fields this tool cares about
+#[derive(Debug, Clone, Copy)]
+pub struct PngHeader {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub pixel_format: PixelFormat,
+    pub interlaced: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ParseResult {
+    OpenFail,
+    ReadFail,
+    InvalidPngHeader,
+    InvalidIhdr,
+    InvalidPixelFormat,
+    // The bit depth isn't allowed for the color type
+    IllegalBitDepth,
+    Valid(PngHeader),
+    // Apple CgBI PNG, which standard decoders can't read until it's repaired
+    AppleCgbi(PngHeader),
+}
+
+pub const EXPECTED_PNG_HEADER: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+// The signature, then the IHDR chunk's size, type and data. That's all of the header unless
+// there's a CgBI chunk before IHDR.
+pub const HEADER_SIZE: usize = 33;
+
+//check PNG header ( 137 80 78 71 13 10 26 10)
+//skip CgBI chunk if there is one (Apple PNGs have it before IHDR)
+//check IHDR size (4 bytes, big endian)
+//cheeck IHDR type ("IHDR" string)
+//width 4 bytes
+//height 4 bytes
+//bit depth 1 byte
+//color type 1 byte <- pixel format
+//- greyscale = 0
+//- truecolor = 2
+//- indexed-color = 3
+//- greyscale with alpha = 4
+//- truecolor with alpha = 6
+//(bit depth must be allowed for the color type, see is_legal_bit_depth)
+//compression method 1 byte
+//filter method 1 byte
+//interlace method 1 byte
+//- none = 0
+//- adam7 = 1
+// Allowed combinations from the table in the PNG spec's IHDR section
+fn is_legal_bit_depth(pixel_format : PixelFormat, bit_depth : u8) -> bool {
+    match pixel_format {
+        PixelFormat::Greyscale => [1, 2, 4, 8, 16].contains(&bit_depth),
+        PixelFormat::IndexedColor => [1, 2, 4, 8].contains(&bit_depth),
+        PixelFormat::TrueColor |
+        PixelFormat::GreyscaleWithAlpha |
+        PixelFormat::TrueColorWithAlpha => [8, 16].contains(&bit_depth),
+    }
+}
+
+// Like read_exact, except that reaching the end first isn't an error. Returns how much was read.
+fn read_up_to<R: Read>(reader : &mut R, buffer : &mut [u8]) -> io::Result<usize> {
+    let mut length = 0;
+    while length < buffer.len() {
+        match reader.read(&mut buffer[length..]) {
+            Ok(0) => break,
+            Ok(read_length) => length += read_length,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(length)
+}
+
+// Read the header from a file, archive entry or anything else. Reads HEADER_SIZE bytes in one
+// go, instead of a read per field.
+pub fn read_header<R: Read>(reader : &mut R) -> ParseResult {
+    let mut buffer = [0u8; HEADER_SIZE];
+    let length = match read_up_to(reader, &mut buffer) {
+        Ok(length) => length,
+        Err(_e) => return ParseResult::ReadFail,
+    };
+
+    // The real IHDR chunk comes after the CgBI chunk, so keep reading from where the buffer ends
+    let data = &buffer[..length];
+    if data.get(12..16) == Some(&b"CgBI"[..]) {
+        return parse_header_from(&mut data.chain(reader));
+    }
+    parse_header(data)
+}
+
+// Parse the header at the start of a PNG held in memory
+pub fn parse_header(mut data : &[u8]) -> ParseResult {
+    parse_header_from(&mut data)
+}
+
+fn parse_header_from<R: Read>(reader : &mut R) -> ParseResult {
+    let ihdr_expected: &[u8] = "IHDR".as_bytes();
+
+    //Check png header
+    let mut png_header : [u8; 8] = [0; 8];
+    match reader.read_exact(&mut png_header) {
+        Ok(()) => {
+            if png_header != EXPECTED_PNG_HEADER {
+                return ParseResult::InvalidPngHeader;
+            }
+        },
+        Err(_e) => return ParseResult::ReadFail,
+    }
+
+    //Get ihdr size
+    let chunk_size : u32 = match reader.read_u32::<BigEndian>() {
+        Ok(size) => size,
+        Err(_e) => return ParseResult::ReadFail,
+    };
+
+    let mut ihdr : [u8; 4] = [0; 4];
+    if reader.read_exact(&mut ihdr).is_err() {
+        return ParseResult::ReadFail;
+    }
+
+    //Skip CgBI chunk and its crc, and read the real IHDR chunk header
+    let cgbi = &ihdr == b"CgBI";
+    if cgbi {
+        let chunk_and_crc_size = u64::from(chunk_size) + 4;
+        match io::copy(&mut reader.by_ref().take(chunk_and_crc_size), &mut io::sink()) {
+            Ok(skipped) if skipped == chunk_and_crc_size => {},
+            _ => return ParseResult::ReadFail,
+        }
+
+        if reader.read_u32::<BigEndian>().is_err() || reader.read_exact(&mut ihdr).is_err() {
+            return ParseResult::ReadFail;
+        }
+    }
+
+    // Check IHDR
+    if ihdr != ihdr_expected {
+        return ParseResult::InvalidIhdr;
+    }
+
+    //read image width
+    let width = match reader.read_u32::<BigEndian>() {
+        Ok(width) => width,
+        Err(_e) => return ParseResult::ReadFail,
+    };
+
+    //read image height
+    let height = match reader.read_u32::<BigEndian>() {
+        Ok(height) => height,
+        Err(_e) => return ParseResult::ReadFail,
+    };
+
+    //read bit depth
+    let bit_depth = match reader.read_u8() {
+        Ok(bit_depth) => bit_depth,
+        Err(_e) => return ParseResult::ReadFail,
+    };
+
+    //read pixel format
+    let pixel_format = match reader.read_u8() {
+        Ok(pixel_format_byte) => {
+            match pixel_format_byte {
+                0 => PixelFormat::Greyscale,
+                2 => PixelFormat::TrueColor,
+                3 => PixelFormat::IndexedColor,
+                4 => PixelFormat::GreyscaleWithAlpha,
+                6 => PixelFormat::TrueColorWithAlpha,
+                _ => return ParseResult::InvalidPixelFormat,
+            }
+        }
+        Err(_e) => return ParseResult::ReadFail,
+    };
+
+    if !is_legal_bit_depth(pixel_format, bit_depth) {
+        return ParseResult::IllegalBitDepth;
+    }
+
+    //skip compression and filter method
+    let mut methods : [u8; 2] = [0; 2];
+    if reader.read_exact(&mut methods).is_err() {
+        return ParseResult::ReadFail;
+    }
+
+    //read interlace method
+    let interlaced = match reader.read_u8() {
+        Ok(interlace_method) => interlace_method != 0,
+        Err(_e) => return ParseResult::ReadFail,
+    };
+
+    let header = PngHeader { width, height, bit_depth, pixel_format, interlaced };
+    if cgbi {
+        ParseResult::AppleCgbi(header)
+    } else {
+        ParseResult::Valid(header)
+    }
+}
+
+pub fn parse_one(filename : &Path) -> ParseResult {
+    let mut file = match File::open(filename) {
+        Ok(file) => file,
+        Err(_e) => return ParseResult::OpenFail,
+    };
+
+    read_header(&mut file)
+}
+
+// Parse a PNG held in memory: the signature and IHDR, then every chunk up to the image data.
+// This is the entry point for the fuzzer, so it must never panic on malformed input.
+#[doc(hidden)]
+pub fn parse_bytes(data : &[u8]) -> ParseResult {
+    let parse_result = parse_header(data);
+    match parse_result {
+        ParseResult::Valid(_) | ParseResult::AppleCgbi(_) => {
+            match chunks::read_chunks(&mut Cursor::new(data)) {
+                Ok(_summary) => parse_result,
+                Err(_e) => ParseResult::ReadFail,
+            }
+        },
+        error_parse_result => error_parse_result,
+    }
+}
@@ -0,0 +1,48 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use log::info;
+
+pub const LOCK_FILE_NAME: &str = ".png_header_scanner.lock";
+
+// Advisory lock on a scanned folder, so two instances never fix the same files at once. The
+// operating system releases it when the process exits, even if it crashes.
+pub struct ScanLock {
+    _file: File,
+}
+
+pub enum LockResult {
+    Locked(ScanLock),
+    // Held by another instance, which wrote its process id into the lock file
+    Busy(Option<u32>),
+}
+
+fn holder_process_id(lock_path : &Path) -> Option<u32> {
+    fs::read_to_string(lock_path).ok()?.trim().parse().ok()
+}
+
+fn locked(mut file : File) -> io::Result<LockResult> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "{}", process::id())?;
+    file.flush()?;
+    Ok(LockResult::Locked(ScanLock { _file: file }))
+}
+
+// Lock scan_path, waiting for another instance to finish if wait is set
+pub fn lock_folder(scan_path : &Path, wait : bool) -> io::Result<LockResult> {
+    let lock_path = scan_path.join(LOCK_FILE_NAME);
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+
+    match file.try_lock() {
+        Ok(()) => locked(file),
+        Err(TryLockError::WouldBlock) if wait => {
+            info!("Waiting for another instance to finish with [{}]...", scan_path.display());
+            file.lock()?;
+            locked(file)
+        },
+        Err(TryLockError::WouldBlock) => Ok(LockResult::Busy(holder_process_id(&lock_path))),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
@@ -0,0 +1,143 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Target of the messages about files being changed, which are still printed with --quiet
+pub const CHANGED: &str = "png_header_scanner::changed";
+
+// How many old logs --log-max-size keeps, as scan.log.1 (the newest) to scan.log.5
+const ROTATED_LOGS_KEPT: u32 = 5;
+
+// The --log-file, which gets every message up to info level whatever the console shows
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // Start a new file once it would grow past this
+    max_size: Option<u64>,
+}
+
+fn open_append(path : &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path : &Path, number : u32) -> PathBuf {
+    let mut rotated_path = OsString::from(path.as_os_str());
+    rotated_path.push(format!(".{}", number));
+    PathBuf::from(rotated_path)
+}
+
+// UTC time like 2020-01-31T12:00:00Z
+fn timestamp() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+impl LogFile {
+    pub fn open(path : &Path, max_size : Option<u64>) -> io::Result<LogFile> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { path: path.to_path_buf(), file, size, max_size })
+    }
+
+    // Shift the older logs up by one, dropping the oldest, and start an empty file
+    fn rotate(&mut self) -> io::Result<()> {
+        for number in (1..ROTATED_LOGS_KEPT).rev() {
+            let older_path = rotated_path(&self.path, number);
+            if older_path.exists() {
+                fs::rename(&older_path, rotated_path(&self.path, number + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line : &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.max_size.is_some_and(|max_size| self.size > 0 && self.size + length > max_size) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+}
+
+// Prints this program's messages as plain lines, on stdout unless it's taken by the report. While
+// --tui is up, warnings and errors go into its pane instead.
+struct StatusLogger {
+    quiet: bool,
+    log_file: Option<Mutex<LogFile>>,
+}
+
+impl StatusLogger {
+    fn shows_on_console(&self, metadata : &Metadata) -> bool {
+        !self.quiet || metadata.level() <= Level::Warn || metadata.target() == CHANGED
+    }
+}
+
+impl Log for StatusLogger {
+    fn enabled(&self, metadata : &Metadata) -> bool {
+        // Messages from the libraries would only be noise
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME")) &&
+            (self.shows_on_console(metadata) || (self.log_file.is_some() && metadata.level() <= Level::Info))
+    }
+
+    fn log(&self, record : &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if self.shows_on_console(record.metadata()) {
+            if crate::tui::is_active() {
+                crate::tui::show_message(record.level(), record.args().to_string());
+            } else if crate::STATUS_TO_STDERR.load(Ordering::Relaxed) {
+                eprintln!("{}", record.args());
+            } else {
+                println!("{}", record.args());
+            }
+        }
+
+        if let Some(log_file) = &self.log_file {
+            if record.level() <= Level::Info {
+                let line = format!("{} {:<5} {}", timestamp(), record.level(), record.args());
+                // There's nowhere left to report it if the log can't be written
+                let _ = log_file.lock().unwrap().write_line(&line);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Quiet leaves only changed files, warnings and errors. Each verbosity level adds more detail:
+// 1 for every file's header, 2 for every chunk. Neither changes what goes into the log file.
+pub fn init(quiet : bool, verbosity : u64, log_file : Option<LogFile>) {
+    let logger = StatusLogger { quiet, log_file: log_file.map(Mutex::new) };
+    log::set_boxed_logger(Box::new(logger)).expect("Failed to set up logging");
+    log::set_max_level(match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    });
+}
@@ -1,219 +1,3566 @@
 use std::fs::File;
-use byteorder::{BigEndian, ReadBytesExt};
-use std::io::Read;
+use std::io::{IsTerminal, Read};
 use walkdir::WalkDir;
-use std::path::Path;
-use std::ffi::OsStr;
-use std::collections::HashSet;
-use std::{fs, env};
+use log::{debug, error, info, log_enabled, trace, warn, Level};
+use std::path::{Path, PathBuf};
+use std::ffi::{OsStr, OsString};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use clap::{App, AppSettings, Arg, Shell, SubCommand, value_t};
 
-#[derive(Debug)]
-enum PixelFormat {
-    Greyscale,
-    TrueColor,
-    IndexedColor,
-    GreyscaleWithAlpha,
-    TrueColorWithAlpha,
+// When stdout is reserved for --jsonl output, human readable progress goes to stderr instead
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+// Set by Ctrl-C. Files which are already being handled are finished, but no new ones are started.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Exit codes, so scripts can tell what happened. Fix failures take priority over fixed files.
+// With --check and --estimate, EXIT_FIXED means there are files which would be fixed.
+const EXIT_NOTHING_TO_FIX: i32 = 0;
+const EXIT_FIXED: i32 = 1;
+const EXIT_ERRORS: i32 = 2;
+// Stopped as soon as a converted image didn't match the original
+const EXIT_VERIFICATION_MISMATCH: i32 = 3;
+// Invalid arguments, config file or policy file
+const EXIT_USAGE: i32 = 4;
+// Another instance is working on the same folder
+const EXIT_LOCKED: i32 = 5;
+const EXIT_INTERRUPTED: i32 = 130;
+
+const AFTER_HELP: &str = "EXIT CODES:
+    0      Nothing needed fixing
+    1      Files were fixed, or would be with --check or --estimate
+    2      Some files couldn't be read, or failed to fix
+    3      A converted image didn't match the original, and the scan was stopped, or manifest verify
+           found images whose pixels changed
+    4      Invalid arguments, config file or policy file
+    5      The folder is locked by another instance
+    130    Interrupted
+
+WRITING FILES:
+    Fixed files are written to a temp file which is renamed over the original, so an interrupted run never
+    leaves a half written PNG. Files with several hard links are overwritten in place instead, which isn't
+    atomic: the new contents are written next to the file first, and kept there if overwriting it fails.";
+
+fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// From Ctrl-C, or q in --tui
+fn interrupt() {
+    // A second Ctrl-C stops right away. Files are replaced atomically, so they're still intact.
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        tui::restore_terminal();
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    info!("Interrupted, finishing the files which are being handled...");
+}
+
+// A message about a file which was changed, which is still printed with --quiet
+macro_rules! changed {
+    ($($arg:tt)*) => {
+        log::info!(target: crate::logger::CHANGED, $($arg)*)
+    };
+}
+
+mod archive;
+mod bbcode;
+#[cfg(feature = "fix")]
+mod bench;
+mod cache;
+mod checkpoint;
+mod config;
+#[cfg(unix)]
+mod daemon;
+#[cfg(feature = "fix")]
+mod dedup;
+mod exec;
+mod failed_list;
+mod fix_queue;
+mod git;
+mod group;
+mod hook;
+mod html;
+mod interactive;
+mod io_limit;
+mod journal;
+mod jsonl;
+mod lock;
+mod logger;
+mod man;
+#[cfg(feature = "fix")]
+mod manifest;
+mod output_tree;
+mod pack;
+mod policy;
+#[cfg(feature = "fix")]
+mod quantize;
+mod quarantine;
+mod sarif;
+mod serve;
+mod staging;
+mod stats;
+mod tui;
+mod uring;
+mod watch;
+use png_header_scanner::{cgbi, chunks, export, fix, validate, parse_one, ParseResult, PixelFormat, PngHeader, EXPECTED_PNG_HEADER};
+use cache::{Cache, FileStamp};
+use checkpoint::Checkpoint;
+use chunks::{ChunkSummary, ColorSpaceProblem, PaletteReport, PhysicalDimensions};
+use export::ExportFormat;
+use fix::{FixError, FixOptions, FixOutcome, MinSavings, SizeChange, Thumbnails, VerifyMode};
+use io_limit::IoLimiter;
+use png_header_scanner::atomic_write::{self, WriteOptions};
+use png_header_scanner::optimizer::{ExternalOptimizer, Optimizer};
+#[cfg(feature = "fix")]
+use png_header_scanner::optimizer::Oxipng;
+use policy::Policy;
+use rayon::prelude::*;
+
+// Settings which apply to every file in a run
+struct ScanOptions {
+    // Only report problems, never modify any files
+    check_only: bool,
+    // Fix images in memory to report the size change, but never modify any files
+    estimate: bool,
+    // Also look inside files without a .png extension for PNG data
+    find_misnamed_pngs: bool,
+    // Print a JSON object to stdout for every file as soon as it's handled
+    json_lines: bool,
+    // Walk and check every chunk, not just the header
+    deep: bool,
+    // Describe the palette of every indexed image
+    palette_report: bool,
+    // Cut off anything after IEND
+    truncate_trailing: bool,
+    io_limiter: IoLimiter,
+    fix_options: FixOptions,
+    policy: Policy,
+    // Only handle these files instead of walking the folder, from --retry
+    retry_files: Option<Vec<PathBuf>>,
+    // Only handle the PNGs git says changed, from --git-changed
+    git_changed_files: Option<Vec<PathBuf>>,
+    order: FileOrder,
+    pixel_format_match: PixelFormatMatch,
+    dimensions: DimensionFilter,
+    // Texture dimension rules from --require-pot and --require-mult
+    dimension_policy: DimensionPolicy,
+    // Largest width or height allowed, from --max-dimension
+    max_dimension: Option<u32>,
+    // Scale down images over max_dimension instead of only reporting them
+    resize_over_limit: bool,
+    // Give every PNG this pHYs chunk, from --set-dpi
+    set_dpi: Option<DpiSetting>,
+    // Replace conflicting or bogus gAMA, sRGB and iCCP chunks with a single sRGB chunk
+    fix_gamma: bool,
+    // Only handle PNGs whose file size is in this range, in bytes
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    // Also scan dotfiles and dot folders, from --hidden
+    include_hidden: bool,
+    // Skip PNGs modified more recently than this, which may still be being written
+    min_age: Option<Duration>,
+    // Skip PNGs whose size or modification time changes over this long
+    stable_interval: Option<Duration>,
+    // Only handle the first, or a random selection of, this many PNGs
+    limit: Option<usize>,
+    sample: Option<usize>,
+    // Write a lossless copy in this format next to each PNG, instead of fixing the PNGs
+    convert_to: Option<ExportFormat>,
+    // Delete each PNG once its copy from --convert-to is written
+    replace_originals: bool,
+    // Replace BMP, TGA and TIFF images with optimized PNGs
+    convert_others: bool,
+    // Also check the PNGs inside zip files
+    scan_archives: bool,
+    // Fix the PNGs inside zip files too, rewriting the archives
+    fix_archives: bool,
+    // Stop the run when a fixed image doesn't match the original. The daemon keeps running and
+    // fails the request instead.
+    exit_on_mismatch: bool,
+    // Ask before fixing each image, from --interactive
+    prompt: Option<interactive::Prompt>,
+    // Run for each fixed file or finding, from --exec
+    exec: Option<exec::ExecCommand>,
+    // Headers read ahead through io_uring, from --io-uring
+    header_prefetch: Option<uring::HeaderPrefetch>,
+    // Separate threads for decoding and optimizing, from --fix-jobs
+    fix_pool: Option<rayon::ThreadPool>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FindingKind {
+    Indexed { fixed: bool },
+    Invalid(ParseResult),
+    // Has a .png extension, but contains another image format
+    WrongFormat(&'static str),
+    // Contains PNG data, but doesn't have a .png extension
+    MissingPngExtension,
+    // Indexed image whose palette would fit in a smaller bit depth
+    OversizedBitDepth { bit_depth: u8, palette_entries: usize },
+    // Adam7 interlaced image, which the engine's loader can't read
+    Interlaced { fixed: bool },
+    // Skipped because decoding it would go over --max-pixels or --max-decode-mem
+    TooLarge { pixels: u64, decode_mem: u64 },
+    // Left unchanged because the fixed file would be larger
+    WouldGrow(SizeChange),
+    // Left unchanged because the fixed file wouldn't save --min-savings
+    BelowMinSavings(SizeChange),
+    // Bytes after the IEND chunk
+    TrailingData { bytes: u64, removed: bool },
+    // Found by --deep validation
+    CorruptChunk(validate::ChunkError),
+    // Apple CgBI PNG, which standard decoders can't read
+    AppleCgbi { repaired: bool },
+    // APNG, which would be flattened to its first frame by fixing it
+    Animated,
+    // Chunk which the --policy file doesn't allow
+    ForbiddenChunk { chunk_type: [u8; 4], stripped: bool },
+    // Skipped because the file is read-only and --force-readonly wasn't given
+    ReadOnly,
+    // Fixing failed with this kind of error, see fix_error_kind
+    FixFailed(&'static str),
+    // Non-indexed image selected by --match
+    MatchedPixelFormat { pixel_format: PixelFormat, bit_depth: u8, fixed: bool },
+    // 16 bits per channel, only reported with --downconvert-16bit
+    SixteenBit { fixed: bool },
+    // RGBA with every pixel opaque, only found by decoding with --drop-opaque-alpha
+    OpaqueAlpha { fixed: bool },
+    // Copied to another format by --convert-to
+    Exported { format: ExportFormat, written: bool },
+    // BMP, TGA or TIFF image found by --convert-others
+    OtherFormat { format: &'static str, converted: bool },
+    // Width or height isn't a power of two, with --require-pot
+    NotPowerOfTwo { width: u32, height: u32 },
+    // Width or height isn't a multiple of --require-mult
+    NotMultipleOf { multiple: u32, width: u32, height: u32 },
+    // Wider or taller than --max-dimension, and scaled down to fit with --resize-over-limit
+    OverMaxDimension { width: u32, height: u32, max_dimension: u32, resized: bool },
+    // pHYs chunk with a different resolution each way, replaced or removed by --set-dpi
+    InconsistentDpi { physical: PhysicalDimensions, normalized: bool },
+    // gAMA, sRGB or iCCP chunks which viewers would render differently, replaced by --fix-gamma
+    ColorSpace { problem: ColorSpaceProblem, fixed: bool },
 }
 
+// Which pixel formats need fixing, from --match. Empty lists match anything.
 #[derive(Debug)]
-enum ParseResult {
-    OpenFail,
-    ReadFail,
-    InvalidPngHeader,
-    InvalidIhdr,
-    InvalidPixelFormat,
-    Valid(PixelFormat),
-}
-
-const EXPECTED_PNG_HEADER: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
-
-//check PNG header ( 137 80 78 71 13 10 26 10)
-//check IHDR size (4 bytes, big endian)
-//cheeck IHDR type ("IHDR" string)
-//width 4 bytes
-//height 4 bytes
-//bit depth 1 byte
-//color type 1 byte <- pixel format
-//- greyscale = 0
-//- truecolor = 2
-//- indexed-color = 3
-//- greyscale with alpha = 4
-//- truecolor with alpha = 6
-//filter method 1 byte
-//interlace method 1 byte
-fn parse_one(filename : &Path) -> ParseResult {
-    let ihdr_expected: &[u8] = "IHDR".as_bytes();
-
-    let mut file = match File::open(filename) {
-        Ok(file) => file,
-        Err(_e) => return ParseResult::OpenFail,
-    };
-
-    //Check png header
-    let mut png_header : [u8; 8] = [0; 8];
-    match file.read_exact(&mut png_header) {
-        Ok(()) => {
-            if png_header != EXPECTED_PNG_HEADER {
-                return ParseResult::InvalidPngHeader;
+struct PixelFormatMatch {
+    pixel_formats: Vec<PixelFormat>,
+    bit_depths: Vec<u8>,
+}
+
+impl Default for PixelFormatMatch {
+    fn default() -> PixelFormatMatch {
+        PixelFormatMatch { pixel_formats: vec![PixelFormat::IndexedColor], bit_depths: Vec::new() }
+    }
+}
+
+impl PixelFormatMatch {
+    fn matches(&self, header : &PngHeader) -> bool {
+        (self.pixel_formats.is_empty() || self.pixel_formats.contains(&header.pixel_format)) &&
+            (self.bit_depths.is_empty() || self.bit_depths.contains(&header.bit_depth))
+    }
+}
+
+fn pixel_format_from_color_type(color_type : u8) -> Option<PixelFormat> {
+    match color_type {
+        0 => Some(PixelFormat::Greyscale),
+        2 => Some(PixelFormat::TrueColor),
+        3 => Some(PixelFormat::IndexedColor),
+        4 => Some(PixelFormat::GreyscaleWithAlpha),
+        6 => Some(PixelFormat::TrueColorWithAlpha),
+        _ => None,
+    }
+}
+
+// Criteria like color-type=3,0 or bit-depth=1,2,4. Criteria which aren't given keep their
+// default, so color types stay indexed only unless color-type is given.
+fn parse_pixel_format_match<'a>(criteria : impl Iterator<Item = &'a str>) -> Result<PixelFormatMatch, String> {
+    let mut pixel_format_match = PixelFormatMatch::default();
+    for criterion in criteria {
+        let (name, values) = criterion.split_once('=')
+            .ok_or_else(|| format!("'{}' should look like color-type=3,0 or bit-depth=1,2,4", criterion))?;
+        let numbers = values.split(',')
+            .map(|value| value.trim().parse::<u8>().map_err(|_e| format!("'{}' is not a number", value)))
+            .collect::<Result<Vec<u8>, String>>()?;
+
+        match name {
+            "color-type" => {
+                pixel_format_match.pixel_formats = numbers.iter()
+                    .map(|&color_type| pixel_format_from_color_type(color_type)
+                        .ok_or_else(|| format!("{} is not a PNG color type", color_type)))
+                    .collect::<Result<_, String>>()?;
+            },
+            "bit-depth" => pixel_format_match.bit_depths = numbers,
+            _ => return Err(format!("unknown criterion '{}', expected color-type or bit-depth", name)),
+        }
+    }
+    Ok(pixel_format_match)
+}
+
+fn is_match_criterion(value : String) -> Result<(), String> {
+    parse_pixel_format_match(std::iter::once(value.as_str())).map(|_| ())
+}
+
+// Only images with dimensions in this range are fixed, from --min-width etc.
+#[derive(Debug, Default)]
+struct DimensionFilter {
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+}
+
+impl DimensionFilter {
+    fn contains(&self, header : &PngHeader) -> bool {
+        self.min_width.is_none_or(|min_width| header.width >= min_width) &&
+            self.min_height.is_none_or(|min_height| header.height >= min_height) &&
+            self.max_width.is_none_or(|max_width| header.width <= max_width) &&
+            self.max_height.is_none_or(|max_height| header.height <= max_height)
+    }
+}
+
+// Dimensions every texture must have, from --require-pot and --require-mult. Checked on every PNG,
+// whether or not it's fixed.
+#[derive(Debug, Default)]
+struct DimensionPolicy {
+    power_of_two: bool,
+    multiple: Option<u32>,
+}
+
+impl DimensionPolicy {
+    fn check(&self, header : &PngHeader) -> Vec<FindingKind> {
+        let (width, height) = (header.width, header.height);
+        let mut findings = Vec::new();
+        if self.power_of_two && !(width.is_power_of_two() && height.is_power_of_two()) {
+            findings.push(FindingKind::NotPowerOfTwo { width, height });
+        }
+        if let Some(multiple) = self.multiple {
+            if width % multiple != 0 || height % multiple != 0 {
+                findings.push(FindingKind::NotMultipleOf { multiple, width, height });
             }
-        },
-        Err(_e) => return ParseResult::ReadFail,
+        }
+        findings
+    }
+}
+
+// What --set-dpi does to the pHYs chunk of every PNG
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DpiSetting {
+    Dpi(u32),
+    Strip,
+}
+
+fn parse_dpi_setting(value : &str) -> Option<DpiSetting> {
+    match value {
+        "strip" => Some(DpiSetting::Strip),
+        _ => value.parse::<u32>().ok().filter(|&dpi| dpi > 0).map(DpiSetting::Dpi),
     }
+}
 
-    //Get ihdr size
-    let _ihdr_size : u32 = match file.read_u32::<BigEndian>() {
-        Ok(size) => size,
-        Err(_e) => return ParseResult::ReadFail,
-    };
+fn is_dpi_setting(value : String) -> Result<(), String> {
+    match parse_dpi_setting(&value) {
+        Some(_) => Ok(()),
+        None => Err(String::from("must be a DPI greater than 0, or strip")),
+    }
+}
 
-    // Check IHDR
-    let mut ihdr : [u8; 4] = [0; 4];
-    match file.read_exact(&mut ihdr) {
-        Ok(()) => {
-           if ihdr != ihdr_expected {
-               return ParseResult::InvalidIhdr;
-           }
+// Like 72 DPI, or a 2:1 pixel aspect ratio if the unit isn't known
+fn physical_text(physical : &PhysicalDimensions) -> String {
+    match physical.dpi() {
+        Some((dpi_x, _)) if physical.is_square() => format!("{:.0} DPI", dpi_x),
+        Some((dpi_x, dpi_y)) => format!("{:.0}x{:.0} DPI", dpi_x, dpi_y),
+        None => match physical.pixel_aspect_ratio() {
+            Some(aspect_ratio) => format!("a pixel aspect ratio of {:.3}", aspect_ratio),
+            None => String::from("an invalid pHYs chunk"),
         },
-        Err(_e) => return ParseResult::ReadFail,
-    };
+    }
+}
 
-    //read image width
-    if file.read_u32::<BigEndian>().is_err() {
-        return ParseResult::ReadFail;
+impl ScanOptions {
+    fn modifies_files(&self) -> bool {
+        !self.check_only && !self.estimate
     }
+}
 
+impl FindingKind {
+    // A fixed image which didn't match the original, which stops the run outside the daemon
+    fn is_verification_mismatch(&self) -> bool {
+        matches!(self, FindingKind::FixFailed(kind) if *kind == fix_error_kind(&FixError::VerificationFailed))
+    }
 
-    //read image height
-    if file.read_u32::<BigEndian>().is_err() {
-        return ParseResult::ReadFail;
+    // Problems which are left in place and make --check fail
+    fn is_disallowed(&self) -> bool {
+        match self {
+            FindingKind::Indexed { fixed } |
+            FindingKind::MatchedPixelFormat { fixed, .. } |
+            FindingKind::SixteenBit { fixed } => !fixed,
+            FindingKind::OpaqueAlpha { fixed } => !fixed,
+            FindingKind::ForbiddenChunk { stripped, .. } => !stripped,
+            FindingKind::AppleCgbi { repaired } => !repaired,
+            FindingKind::OverMaxDimension { resized, .. } => !resized,
+            FindingKind::Invalid(_) |
+            FindingKind::WrongFormat(_) |
+            FindingKind::CorruptChunk(_) |
+            FindingKind::FixFailed(_) |
+            FindingKind::NotPowerOfTwo { .. } |
+            FindingKind::NotMultipleOf { .. } => true,
+            FindingKind::MissingPngExtension |
+            FindingKind::OversizedBitDepth { .. } |
+            FindingKind::InconsistentDpi { .. } |
+            FindingKind::ColorSpace { .. } |
+            FindingKind::Interlaced { .. } |
+            FindingKind::TrailingData { .. } |
+            FindingKind::TooLarge { .. } |
+            FindingKind::WouldGrow(_) |
+            FindingKind::BelowMinSavings(_) |
+            FindingKind::ReadOnly |
+            FindingKind::Exported { .. } |
+            FindingKind::OtherFormat { .. } |
+            FindingKind::Animated => false,
+        }
     }
 
-    //read bit depth
-    if file.read_u8().is_err() {
-        return ParseResult::ReadFail;
+    // Stable identifier for machine readable reports
+    fn id(&self) -> &'static str {
+        match self {
+            FindingKind::Indexed { .. } => "indexed-png",
+            FindingKind::Invalid(_) => "invalid-png",
+            FindingKind::WrongFormat(_) | FindingKind::MissingPngExtension => "extension-mismatch",
+            FindingKind::OversizedBitDepth { .. } => "oversized-bit-depth",
+            FindingKind::Interlaced { .. } => "interlaced-png",
+            FindingKind::TooLarge { .. } => "too-large",
+            FindingKind::WouldGrow(_) => "would-grow",
+            FindingKind::BelowMinSavings(_) => "below-min-savings",
+            FindingKind::TrailingData { .. } => "trailing-data",
+            FindingKind::CorruptChunk(_) => "corrupt-chunk",
+            FindingKind::AppleCgbi { .. } => "apple-cgbi",
+            FindingKind::Animated => "animated-png",
+            FindingKind::ForbiddenChunk { .. } => "forbidden-chunk",
+            FindingKind::ReadOnly => "read-only",
+            FindingKind::FixFailed(_) => "fix-failed",
+            FindingKind::MatchedPixelFormat { .. } => "matched-pixel-format",
+            FindingKind::SixteenBit { .. } => "16-bit-png",
+            FindingKind::OpaqueAlpha { .. } => "opaque-alpha",
+            FindingKind::Exported { .. } => "exported",
+            FindingKind::OtherFormat { .. } => "other-format",
+            FindingKind::NotPowerOfTwo { .. } => "not-power-of-two",
+            FindingKind::NotMultipleOf { .. } => "not-multiple-of",
+            FindingKind::OverMaxDimension { .. } => "over-max-dimension",
+            FindingKind::InconsistentDpi { .. } => "inconsistent-dpi",
+            FindingKind::ColorSpace { .. } => "color-space-conflict",
+        }
     }
 
-    //read pixel format
-    return match file.read_u8() {
-        Ok(pixel_format_byte) => {
-            match pixel_format_byte {
-                0 => ParseResult::Valid(PixelFormat::Greyscale),
-                2 => ParseResult::Valid(PixelFormat::TrueColor),
-                3 => ParseResult::Valid(PixelFormat::IndexedColor),
-                4 => ParseResult::Valid(PixelFormat::GreyscaleWithAlpha),
-                6 => ParseResult::Valid(PixelFormat::TrueColorWithAlpha),
-                _ => ParseResult::InvalidPixelFormat,
-            }
+    fn description(&self) -> String {
+        match self {
+            FindingKind::Indexed { fixed: true } => "Indexed PNG was converted to RGB/RGBA".to_string(),
+            FindingKind::Indexed { fixed: false } => "PNG uses indexed color".to_string(),
+            FindingKind::Invalid(parse_result) => format!("PNG header is invalid ({:?})", parse_result),
+            FindingKind::WrongFormat(format) => format!("File has a .png extension but is a {} image", format),
+            FindingKind::MissingPngExtension => "File is a PNG image but doesn't have a .png extension".to_string(),
+            FindingKind::OversizedBitDepth { bit_depth, palette_entries } => {
+                format!("Indexed PNG uses {}-bit depth but its palette only has {} entries, so it could be stored at {}-bit",
+                        bit_depth, palette_entries, min_palette_bit_depth(*palette_entries))
+            },
+            FindingKind::Interlaced { fixed: true } => "Interlaced PNG was rewritten as non-interlaced".to_string(),
+            FindingKind::Interlaced { fixed: false } => "PNG is Adam7 interlaced".to_string(),
+            FindingKind::TooLarge { pixels, decode_mem } => {
+                format!("PNG has {} pixels and would need about {}MB to decode, so it was skipped",
+                        pixels, decode_mem / 1_000_000)
+            },
+            FindingKind::WouldGrow(size_change) => {
+                format!("Skipped: would grow from {} to {} bytes", size_change.before, size_change.after)
+            },
+            FindingKind::BelowMinSavings(size_change) => {
+                format!("Skipped: would only save {} of {} bytes", size_change.saved(), size_change.before)
+            },
+            FindingKind::TrailingData { bytes, removed: true } => format!("Removed {} bytes of data after IEND", bytes),
+            FindingKind::TrailingData { bytes, removed: false } => format!("PNG has {} bytes of data after IEND", bytes),
+            FindingKind::CorruptChunk(chunk_error) => format!("PNG is corrupt: {}", chunk_error),
+            FindingKind::AppleCgbi { repaired: true } => "Apple CgBI PNG was repaired to a standard PNG".to_string(),
+            FindingKind::AppleCgbi { repaired: false } => "PNG is in Apple's CgBI format".to_string(),
+            FindingKind::Animated => "PNG is animated (APNG)".to_string(),
+            FindingKind::ForbiddenChunk { chunk_type, stripped: true } => {
+                format!("Forbidden {} chunk was stripped", String::from_utf8_lossy(chunk_type))
+            },
+            FindingKind::ForbiddenChunk { chunk_type, stripped: false } => {
+                format!("PNG contains a forbidden {} chunk", String::from_utf8_lossy(chunk_type))
+            },
+            FindingKind::ReadOnly => "Skipped: file is read-only".to_string(),
+            FindingKind::FixFailed(error_kind) => format!("Fixing failed with a {} error", error_kind),
+            FindingKind::MatchedPixelFormat { pixel_format, bit_depth, fixed: true } => {
+                format!("{:?} PNG with {}-bit depth was converted to RGB/RGBA", pixel_format, bit_depth)
+            },
+            FindingKind::MatchedPixelFormat { pixel_format, bit_depth, fixed: false } => {
+                format!("PNG is {:?} with {}-bit depth, which --match selects", pixel_format, bit_depth)
+            },
+            FindingKind::SixteenBit { fixed: true } => "16-bit PNG was converted to 8-bit RGB/RGBA".to_string(),
+            FindingKind::SixteenBit { fixed: false } => "PNG uses 16 bits per channel".to_string(),
+            FindingKind::OpaqueAlpha { fixed: true } => "Fully opaque RGBA PNG was converted to RGB".to_string(),
+            FindingKind::OpaqueAlpha { fixed: false } => "RGBA PNG is fully opaque and could be RGB".to_string(),
+            FindingKind::Exported { format, written: true } => format!("PNG was converted to lossless {}", format.name()),
+            FindingKind::Exported { format, written: false } => {
+                format!("PNG would be converted to lossless {}", format.name())
+            },
+            FindingKind::OtherFormat { format, converted: true } => format!("{} image was converted to PNG", format),
+            FindingKind::OtherFormat { format, converted: false } => {
+                format!("File is a {} image, which --convert-others would convert to PNG", format)
+            },
+            FindingKind::NotPowerOfTwo { width, height } => {
+                format!("PNG is {}x{}, which isn't a power of two in both dimensions", width, height)
+            },
+            FindingKind::NotMultipleOf { multiple, width, height } => {
+                format!("PNG is {}x{}, which isn't a multiple of {} in both dimensions", width, height, multiple)
+            },
+            FindingKind::OverMaxDimension { width, height, max_dimension, resized: true } => {
+                format!("PNG was scaled down from {}x{} to fit in {} pixels", width, height, max_dimension)
+            },
+            FindingKind::OverMaxDimension { width, height, max_dimension, resized: false } => {
+                format!("PNG is {}x{}, which is over the maximum of {} pixels", width, height, max_dimension)
+            },
+            FindingKind::InconsistentDpi { physical, normalized: true } => {
+                format!("PNG had {}, which --set-dpi replaced", physical_text(physical))
+            },
+            FindingKind::InconsistentDpi { physical, normalized: false } => {
+                format!("PNG has {}, so its pixels aren't square", physical_text(physical))
+            },
+            FindingKind::ColorSpace { problem, fixed: true } => {
+                format!("PNG {}, so it was declared as sRGB with a single sRGB chunk", problem)
+            },
+            FindingKind::ColorSpace { problem, fixed: false } => format!("PNG {}", problem),
         }
-        Err(_e) => ParseResult::ReadFail,
     }
 }
 
-// Convert an image to RGB/RGBA format, then optimize it
-fn fix_image(path : &Path) {
-    let image_size_before = fs::metadata(path).expect("Can't get image size").len() as f32;
+struct Finding {
+    rel_path: PathBuf,
+    kind: FindingKind,
+}
+
+struct FixedFile {
+    rel_path: PathBuf,
+    width: u32,
+    height: u32,
+    size_change: SizeChange,
+    thumbnails: Option<Thumbnails>,
+}
+
+// A .png file which was looked at. The header is missing if it couldn't be parsed.
+struct ScannedFile {
+    rel_path: PathBuf,
+    header: Option<PngHeader>,
+}
+
+// Everything found while handling a single file
+#[derive(Default)]
+struct FileResult {
+    header: Option<PngHeader>,
+    findings: Vec<FindingKind>,
+    fix_outcome: Option<FixOutcome>,
+    // From --palette-report, taken before the image is fixed
+    palette_report: Option<PaletteReport>,
+    // From the pHYs chunk, before --set-dpi changes it
+    physical: Option<PhysicalDimensions>,
+    // PNGs inside the file if it's an archive, with paths like archive.zip!inner/path.png
+    archive_members: Vec<(PathBuf, FileResult)>,
+}
+
+#[derive(Default)]
+struct ScanSummary {
+    scanned: Vec<ScannedFile>,
+    findings: Vec<Finding>,
+    fixed: Vec<FixedFile>,
+}
+
+impl ScanSummary {
+    fn num_scanned(&self) -> usize {
+        self.scanned.len()
+    }
 
-    print!("Converting to RGB/RGBA...");
-    let image_before_optimizing = image::open(path).expect("Failed to open image!");
+    fn headers(&self) -> impl Iterator<Item = &PngHeader> {
+        self.scanned.iter().filter_map(|scanned_file| scanned_file.header.as_ref())
+    }
 
+    fn num_fixed(&self) -> usize {
+        self.fixed.len()
+    }
 
-    //image "0.21.2" will save as RGBA32 format
-    image_before_optimizing.save(path).expect("Failed to save image!");
+    fn total_size_change(&self) -> SizeChange {
+        SizeChange {
+            before: self.fixed.iter().map(|file| file.size_change.before).sum(),
+            after: self.fixed.iter().map(|file| file.size_change.after).sum(),
+        }
+    }
 
-    print!(" Optimizing...");
+    fn disallowed(&self) -> Vec<&Finding> {
+        self.findings.iter().filter(|finding| finding.kind.is_disallowed()).collect()
+    }
 
-    let inpath = oxipng::InFile::Path(path.to_path_buf());
-    let outpath = oxipng::OutFile::Path(None);
+    fn any_failed(&self) -> bool {
+        self.findings.iter().any(|finding| matches!(finding.kind, FindingKind::FixFailed(_)))
+    }
 
-    oxipng::optimize(&inpath,
-                     &outpath,
-                     &oxipng::Options {
-                        alphas: HashSet::new(), //Disable Alpha optimizations
-                         color_type_reduction: false,
-                         ..Default::default()
-                     })
-        .expect("Optimize failed!");
+    // Files which failed to fix, or couldn't be opened or read to the end
+    fn any_errors(&self) -> bool {
+        self.any_failed() || self.findings.iter().any(|finding| match &finding.kind {
+            FindingKind::Invalid(parse_result) => matches!(parse_result, ParseResult::OpenFail | ParseResult::ReadFail),
+            FindingKind::CorruptChunk(chunk_error) => matches!(chunk_error.kind, validate::ChunkErrorKind::Truncated),
+            _ => false,
+        })
+    }
 
-    print!("Optimized.");
-    println!();
+    // See the EXIT CODES in AFTER_HELP. With --check nothing is fixed, so it's whether anything would need to be.
+    fn exit_code(&self, check_only : bool) -> i32 {
+        let any_to_fix = if check_only { !self.disallowed().is_empty() } else { self.num_fixed() > 0 };
+        if self.any_errors() {
+            EXIT_ERRORS
+        } else if any_to_fix {
+            EXIT_FIXED
+        } else {
+            EXIT_NOTHING_TO_FIX
+        }
+    }
+}
 
-    let image_pixel_data_after_optimizing = image::open(path)
-        .expect("Failed to open optimized image!")
-        .raw_pixels();
+// Identify common image formats from the first few bytes of a file
+fn detect_format(filename : &Path) -> Option<&'static str> {
+    let mut header : [u8; 12] = [0; 12];
+    let mut file = File::open(filename).ok()?;
+    file.read_exact(&mut header).ok()?;
 
-    // Check the images are 100% identical
-    if image_before_optimizing.raw_pixels() != image_pixel_data_after_optimizing {
-        println!("---------------------------------------------");
-        println!("ERROR: optimized image wasn't identical to original image");
-        println!("---------------------------------------------");
-        std::process::exit(-1);
+    if header[..8] == EXPECTED_PNG_HEADER {
+        Some("PNG")
+    } else if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("JPEG")
+    } else if header.starts_with(b"GIF8") {
+        Some("GIF")
+    } else if header.starts_with(b"BM") {
+        Some("BMP")
+    } else if header.starts_with(b"RIFF") && &header[8..12] == b"WEBP" {
+        Some("WebP")
+    } else {
+        None
     }
+}
 
+// Smallest bit depth which can still index every entry of a palette
+fn min_palette_bit_depth(palette_entries : usize) -> u8 {
+    match palette_entries {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
 
-    let image_size_after = fs::metadata(path).expect("Can't get image size").len() as f32;
-    println!("-------------------------------");
-    println!("Size Change: [{:+}KB / {:3.0}%]",
-             (image_size_after - image_size_before) / 1000f32,
-             image_size_after / image_size_before * 100f32);
-    println!("-------------------------------");
+fn check_palette_bit_depth(header : &PngHeader, summary : &ChunkSummary) -> Option<FindingKind> {
+    let palette_entries = summary.palette_entries()?;
+    if min_palette_bit_depth(palette_entries) < header.bit_depth {
+        Some(FindingKind::OversizedBitDepth { bit_depth: header.bit_depth, palette_entries })
+    } else {
+        None
+    }
 }
 
-fn handle_one_file(path : &Path, rel_path : &Path) -> bool {
-    return match parse_one(path) {
-        ParseResult::Valid(pixel_format) => {
-            match pixel_format {
-                PixelFormat::IndexedColor => {
-                    println!("{} is indexed!", rel_path.display());
-                    fix_image(path);
-                    true
-                }
-                _ => false,
+fn handle_one_file(path : &Path, rel_path : &Path, options : &ScanOptions) -> FileResult {
+    let mut result = FileResult::default();
+
+    // Leave read-only files completely alone, rather than failing halfway through fixing them
+    if options.modifies_files() && options.io_limiter.skips_read_only_file(path) {
+        info!("Skipping read-only file {}", rel_path.display());
+        let parse_result = {
+            let _permit = options.io_limiter.acquire();
+            parse_one(path)
+        };
+        if let ParseResult::Valid(header) | ParseResult::AppleCgbi(header) = parse_result {
+            result.header = Some(header);
+        }
+        result.findings.push(FindingKind::ReadOnly);
+        return result;
+    }
+
+    let header = match parse_file(path, rel_path, options, &mut result) {
+        Some(header) => header,
+        None => return result,
+    };
+    // A repaired CgBI image may have been written to the output tree
+    let path = &options.io_limiter.current_path(path);
+    debug!("{}: {}x{}, {:?}, {}-bit{}",
+           rel_path.display(),
+           header.width,
+           header.height,
+           header.pixel_format,
+           header.bit_depth,
+           if header.interlaced { ", interlaced" } else { "" });
+
+    if header.interlaced {
+        info!("{} is interlaced!", rel_path.display());
+    }
+
+    let header = check_max_dimension(path, rel_path, &header, options, &mut result).unwrap_or(header);
+    for finding in options.dimension_policy.check(&header) {
+        error!("Error: {}: {}", rel_path.display(), finding.description());
+        result.findings.push(finding);
+    }
+
+    let chunk_summary = {
+        let _permit = options.io_limiter.acquire();
+        chunks::read_chunk_summary(path)
+    };
+
+    let summary = match chunk_summary {
+        Ok(summary) => summary,
+        Err(_e) => {
+            error!("Error {:?}: {}", ParseResult::ReadFail, rel_path.display());
+            result.findings.push(FindingKind::Invalid(ParseResult::ReadFail));
+            return result;
+        }
+    };
+
+    if log_enabled!(Level::Trace) {
+        log_chunks(path, options);
+    }
+
+    if let Some(physical) = summary.physical {
+        debug!("{}: {}", rel_path.display(), physical_text(&physical));
+        result.physical = Some(physical);
+    }
+
+    if summary.animated {
+        info!("{} is animated!", rel_path.display());
+        result.findings.push(FindingKind::Animated);
+    }
+
+    if options.palette_report && header.pixel_format == PixelFormat::IndexedColor {
+        result.palette_report = report_palette(path, rel_path, &header, &summary, options);
+    }
+
+    check_trailing_data(path, rel_path, options, &mut result);
+
+    // Stripping only copies chunks, so do it before the image is possibly converted
+    check_chunk_policy(path, rel_path, options, &mut result);
+
+    match options.convert_to {
+        Some(format) => export_file(path, rel_path, &header, &summary, format, options, &mut result),
+        None => fix_file(path, rel_path, &header, &summary, options, &mut result),
+    }
+
+    // Converting an image always writes it out non-interlaced
+    if header.interlaced && options.convert_to.is_none() {
+        result.findings.push(FindingKind::Interlaced { fixed: result.fix_outcome.is_some() && !options.estimate });
+    }
+
+    // Fixing may have dropped the pHYs and color space chunks, so they're changed on the fixed image
+    if options.convert_to.is_none() {
+        check_dpi(path, rel_path, summary.physical, options, &mut result);
+        check_color_space(path, rel_path, &summary, options, &mut result);
+    }
+
+    result
+}
+
+// Report images wider or taller than --max-dimension, which is known from the header alone, and
+// scale them down with --resize-over-limit. Returns the header of the resized image.
+fn check_max_dimension(path : &Path,
+                       rel_path : &Path,
+                       header : &PngHeader,
+                       options : &ScanOptions,
+                       result : &mut FileResult) -> Option<PngHeader> {
+    let max_dimension = options.max_dimension?;
+    if header.width <= max_dimension && header.height <= max_dimension {
+        return None;
+    }
+
+    let resized_header = if options.resize_over_limit && options.modifies_files() {
+        match resize_image(path, rel_path, header, max_dimension, options) {
+            Ok(resized_header) => resized_header,
+            Err(e) => {
+                error!("Error: failed to resize {}: {}", rel_path.display(), e);
+                result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+                None
+            },
+        }
+    } else {
+        error!("Error: {} is {}x{}, over the maximum of {}",
+               rel_path.display(), header.width, header.height, max_dimension);
+        None
+    };
+    result.findings.push(FindingKind::OverMaxDimension {
+        width: header.width,
+        height: header.height,
+        max_dimension,
+        resized: resized_header.is_some(),
+    });
+    resized_header
+}
+
+// Fails if the file can't be read or written. Images which can't be resized are only logged.
+fn resize_image(path : &Path,
+                rel_path : &Path,
+                header : &PngHeader,
+                max_dimension : u32,
+                options : &ScanOptions) -> std::io::Result<Option<PngHeader>> {
+    let original_data = options.io_limiter.read(path)?;
+
+    // Only the first frame would be kept
+    let animated = chunks::read_chunks(&mut std::io::Cursor::new(&original_data)).is_ok_and(|summary| summary.animated);
+    if animated && !options.fix_options.force_apng {
+        warn!("Warning: not resizing {}: {}", rel_path.display(), FixError::Animated);
+        return Ok(None);
+    }
+
+    match fix::resize_to_fit(&original_data, header, max_dimension, &options.fix_options) {
+        Ok((resized_data, resized_header)) => {
+            options.io_limiter.write(path, &resized_data)?;
+            changed!("Resized {} from {}x{} to {}x{}",
+                     rel_path.display(), header.width, header.height, resized_header.width, resized_header.height);
+            Ok(Some(resized_header))
+        },
+        Err(e) => {
+            error!("Failed to resize {}: {}", rel_path.display(), e);
+            Ok(None)
+        },
+    }
+}
+
+// Which palette entries the pixels use, if the image can be decoded
+#[cfg(feature = "fix")]
+fn used_palette_entries(path : &Path, rel_path : &Path, header : &PngHeader, options : &ScanOptions)
+                        -> Option<Vec<bool>> {
+    if let Err(e) = fix::check_decode_limits(header, &options.fix_options) {
+        warn!("Warning: not counting the used palette entries of {}: {}", rel_path.display(), e);
+        return None;
+    }
+    let data = options.io_limiter.read(path).ok()?;
+    png_header_scanner::palette::used_entries(&data)
+        .map_err(|e| warn!("Warning: can't decode {} to count the used palette entries: {}", rel_path.display(), e))
+        .ok()
+}
+
+// Counting the used entries needs the decoder
+#[cfg(not(feature = "fix"))]
+fn used_palette_entries(_path : &Path, _rel_path : &Path, _header : &PngHeader, _options : &ScanOptions)
+                        -> Option<Vec<bool>> {
+    None
+}
+
+// Describe the palette for --palette-report
+fn report_palette(path : &Path,
+                  rel_path : &Path,
+                  header : &PngHeader,
+                  summary : &ChunkSummary,
+                  options : &ScanOptions) -> Option<PaletteReport> {
+    let used = used_palette_entries(path, rel_path, header, options);
+    let report = summary.palette_report(used.as_deref())?;
+    let used_text = match report.used_entries {
+        Some(used_entries) => format!(", {} used", used_entries),
+        None => String::new(),
+    };
+    let greyscale_text = match (report.greyscale, report.transparent) {
+        (true, false) => ", could be greyscale",
+        (true, true) => ", could be greyscale with alpha",
+        (false, _) => "",
+    };
+    info!("{}: palette of {} entries{}, {} duplicates{}",
+          rel_path.display(), report.entries, used_text, report.duplicate_entries, greyscale_text);
+    Some(report)
+}
+
+// Every chunk of the file, for -vv
+fn log_chunks(path : &Path, options : &ScanOptions) {
+    let chunk_list = {
+        let _permit = options.io_limiter.acquire();
+        chunks::read_chunk_list(path)
+    };
+    // Unreadable chunks are already reported as an invalid file
+    for chunk in chunk_list.unwrap_or_default() {
+        let chunk_type = String::from_utf8_lossy(&chunk.chunk_type);
+        match chunk.keyword {
+            Some(keyword) => trace!("    {} chunk, {} bytes, keyword {}", chunk_type, chunk.length, keyword),
+            None => trace!("    {} chunk, {} bytes", chunk_type, chunk.length),
+        }
+    }
+}
+
+fn repair_cgbi(path : &Path, rel_path : &Path, header : &PngHeader, options : &ScanOptions) -> bool {
+    // Repairing decodes all the pixels too
+    if let Err(e) = fix::check_decode_limits(header, &options.fix_options) {
+        warn!("Warning: not repairing {}: {}", rel_path.display(), e);
+        return false;
+    }
+
+    let repaired = options.io_limiter.read(path)
+        .and_then(|original_data| cgbi::repair(&original_data, header))
+        .and_then(|repaired_data| options.io_limiter.write(path, &repaired_data));
+    match repaired {
+        Ok(()) => {
+            changed!("Repaired {}", rel_path.display());
+            true
+        },
+        Err(e) => {
+            error!("Failed to repair {}: {}", rel_path.display(), e);
+            false
+        },
+    }
+}
+
+// Parse the header, recording a finding if it's invalid
+fn parse_file(path : &Path, rel_path : &Path, options : &ScanOptions, result : &mut FileResult) -> Option<PngHeader> {
+    let prefetched = options.header_prefetch.as_ref().and_then(|header_prefetch| header_prefetch.take(path));
+    let parse_result = prefetched.unwrap_or_else(|| {
+        let _permit = options.io_limiter.acquire();
+        parse_one(path)
+    });
+
+    if options.deep {
+        if let ParseResult::Valid(_) | ParseResult::AppleCgbi(_) = parse_result {
+            let validation = {
+                let _permit = options.io_limiter.acquire();
+                validate::validate_file(path)
+            };
+
+            // Don't touch corrupt files any further
+            if let Err(chunk_error) = validation {
+                error!("Error {}: {}", chunk_error, rel_path.display());
+                result.findings.push(FindingKind::CorruptChunk(chunk_error));
+                return None;
             }
+        }
+    }
+
+    match parse_result {
+        ParseResult::Valid(header) => {
+            result.header = Some(header);
+            Some(header)
         },
+        ParseResult::AppleCgbi(header) => {
+            info!("{} is an Apple CgBI PNG!", rel_path.display());
+            result.header = Some(header);
 
+            // Once repaired, it can go through the normal checks and fixes
+            let repaired = options.modifies_files() && repair_cgbi(path, rel_path, &header, options);
+            result.findings.push(FindingKind::AppleCgbi { repaired });
+            if repaired {
+                Some(header)
+            } else {
+                None
+            }
+        },
         error_parse_result => {
-            println!("Error {:?}: {}", error_parse_result, rel_path.display());
-            false
+            error!("Error {:?}: {}", error_parse_result, rel_path.display());
+            let format = {
+                let _permit = options.io_limiter.acquire();
+                detect_format(path)
+            };
+            result.findings.push(match (error_parse_result, format) {
+                (ParseResult::InvalidPngHeader, Some(format)) => FindingKind::WrongFormat(format),
+                _ => FindingKind::Invalid(error_parse_result),
+            });
+            None
         }
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("First argument must be path to folder to be processed.");
-        return;
+// Report pHYs chunks with non-square pixels, and give every PNG the same pHYs chunk with --set-dpi
+fn check_dpi(path : &Path,
+             rel_path : &Path,
+             physical : Option<PhysicalDimensions>,
+             options : &ScanOptions,
+             result : &mut FileResult) {
+    let mut normalize = options.set_dpi.is_some() && options.modifies_files();
+    if normalize {
+        if let Err(e) = set_dpi(path, rel_path, options) {
+            error!("Error: failed to set the DPI of {}: {}", rel_path.display(), e);
+            result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+            normalize = false;
+        }
+    }
+
+    if let Some(physical) = physical.filter(|physical| !physical.is_square()) {
+        if !normalize {
+            warn!("Warning: {} has {}", rel_path.display(), physical_text(&physical));
+        }
+        result.findings.push(FindingKind::InconsistentDpi { physical, normalized: normalize });
     }
+}
 
-    let target_extension = OsStr::new("png");
-    let scan_path = Path::new(&args[1]);
+// Fails if the file or its chunks can't be read, or it can't be written, leaving the file as it was
+fn set_dpi(path : &Path, rel_path : &Path, options : &ScanOptions) -> std::io::Result<()> {
+    let original_data = options.io_limiter.read(path)?;
+    let physical = match options.set_dpi {
+        Some(DpiSetting::Dpi(dpi)) => Some(PhysicalDimensions::from_dpi(dpi)),
+        _ => None,
+    };
+    let summary = chunks::read_chunks(&mut std::io::Cursor::new(&original_data))?;
+    if summary.physical == physical {
+        return Ok(());
+    }
 
-    println!("Scanning [{}]", scan_path.display());
+    let physical_data = physical.map(|physical| physical.to_bytes());
+    let new_data = chunks::replace_chunk(&original_data, b"pHYs", physical_data.as_deref())?;
+    options.io_limiter.write(path, &new_data)?;
+    match physical {
+        Some(physical) => changed!("Set {} to {}", rel_path.display(), physical_text(&physical)),
+        None => changed!("Removed the pHYs chunk from {}", rel_path.display()),
+    }
+    Ok(())
+}
 
-    let mut num_fixed = 0;
-    for entry in WalkDir::new(scan_path) {
-        let entry = entry.expect("File I/O Error?");
-        let path = entry.path();
-        let rel_path = path.strip_prefix(scan_path).unwrap();
+// Report gAMA, sRGB and iCCP chunks which contradict each other or are bogus, and replace them with
+// a single sRGB chunk with --fix-gamma
+fn check_color_space(path : &Path,
+                     rel_path : &Path,
+                     summary : &ChunkSummary,
+                     options : &ScanOptions,
+                     result : &mut FileResult) {
+    let problem = match summary.color_space_problem() {
+        Some(problem) => problem,
+        None => return,
+    };
 
-        // Skip non-files
-        if !path.is_file() {
-            continue;
+    let mut fix = options.fix_gamma && options.modifies_files();
+    if fix {
+        if let Err(e) = normalize_color_space(path, rel_path, problem, options) {
+            error!("Error: failed to fix the color space of {}: {}", rel_path.display(), e);
+            result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+            fix = false;
         }
+    } else {
+        warn!("Warning: {} {}", rel_path.display(), problem);
+    }
 
-        // Only process files with .png extension
-        match path.extension() {
-            Some(ext) => {
-                if ext == target_extension {
-                    if handle_one_file(path, rel_path) {
-                        num_fixed += 1;
-                    }
-                }
+    result.findings.push(FindingKind::ColorSpace { problem, fixed: fix });
+}
+
+// Fails if the file or its chunks can't be read, or it can't be written, leaving the file as it was
+fn normalize_color_space(path : &Path,
+                         rel_path : &Path,
+                         problem : ColorSpaceProblem,
+                         options : &ScanOptions) -> std::io::Result<()> {
+    let original_data = options.io_limiter.read(path)?;
+    // Checked again on the data which is rewritten, as converting the image drops these chunks
+    let current_summary = chunks::read_chunks(&mut std::io::Cursor::new(&original_data))?;
+    if current_summary.color_space_problem().is_some() {
+        let normalized_data = chunks::normalize_srgb(&original_data, current_summary.normalized_srgb_intent())?;
+        options.io_limiter.write(path, &normalized_data)?;
+        changed!("Declared {} as sRGB, it {}", rel_path.display(), problem);
+    }
+    Ok(())
+}
+
+// Report data after IEND, and cut it off with --truncate-trailing
+fn check_trailing_data(path : &Path, rel_path : &Path, options : &ScanOptions, result : &mut FileResult) {
+    let trailing_data_length = {
+        let _permit = options.io_limiter.acquire();
+        chunks::read_trailing_data_length(path)
+    };
+
+    let bytes = match trailing_data_length {
+        Ok(0) => return,
+        Ok(bytes) => bytes,
+        // No IEND, so there's no trailing data either. Truncated files are reported by --deep.
+        Err(_e) => return,
+    };
+
+    let mut truncate = options.truncate_trailing && options.modifies_files();
+    if truncate {
+        match truncate_trailing_data(path, options) {
+            Ok(()) => changed!("Removed {} bytes after IEND from {}", bytes, rel_path.display()),
+            Err(e) => {
+                error!("Error: failed to remove the data after IEND from {}: {}", rel_path.display(), e);
+                result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+                truncate = false;
             },
-            None => continue,
         }
+    } else {
+        info!("{} has {} bytes after IEND", rel_path.display(), bytes);
+    }
+
+    result.findings.push(FindingKind::TrailingData { bytes, removed: truncate });
+}
+
+// Fails if the file can't be read or written, or it no longer has an IEND, leaving the file as it was
+fn truncate_trailing_data(path : &Path, options : &ScanOptions) -> std::io::Result<()> {
+    let original_data = options.io_limiter.read(path)?;
+    // Measured again on the data which is rewritten, in case the file changed meanwhile
+    let trailing = chunks::trailing_data_length(&mut std::io::Cursor::new(&original_data))?;
+    let end = original_data.len() - trailing as usize;
+    options.io_limiter.write(path, &original_data[..end])
+}
+
+// Report chunks forbidden by the policy, and strip them if the policy says so
+fn check_chunk_policy(path : &Path, rel_path : &Path, options : &ScanOptions, result : &mut FileResult) {
+    let policy = &options.policy;
+    if policy.forbidden_chunks.is_empty() {
+        return;
+    }
+
+    let chunk_list = {
+        let _permit = options.io_limiter.acquire();
+        chunks::read_chunk_list(path)
+    };
+
+    let forbidden : Vec<_> = match chunk_list {
+        Ok(chunk_list) => chunk_list.into_iter().filter(|chunk| policy.is_forbidden(chunk)).collect(),
+        Err(_e) => {
+            error!("Error {:?}: {}", ParseResult::ReadFail, rel_path.display());
+            result.findings.push(FindingKind::Invalid(ParseResult::ReadFail));
+            return;
+        }
+    };
+
+    if forbidden.is_empty() {
+        return;
+    }
+
+    let strip = policy.strip_forbidden_chunks && options.modifies_files();
+    if strip {
+        if let Err(e) = strip_forbidden_chunks(path, options) {
+            error!("Error: failed to strip chunks from {}: {}", rel_path.display(), e);
+            result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+            return;
+        }
+        changed!("Stripped {} forbidden chunks from {}", forbidden.len(), rel_path.display());
+    }
+
+    for chunk in forbidden {
+        if !strip {
+            info!("{} has a forbidden {} chunk", rel_path.display(), String::from_utf8_lossy(&chunk.chunk_type));
+        }
+        result.findings.push(FindingKind::ForbiddenChunk { chunk_type: chunk.chunk_type, stripped: strip });
+    }
+}
+
+// Fails if the file or its chunks can't be read, or it can't be written, leaving the file as it was
+fn strip_forbidden_chunks(path : &Path, options : &ScanOptions) -> std::io::Result<()> {
+    let original_data = options.io_limiter.read(path)?;
+    let stripped_data = chunks::filter_chunks(&original_data, |chunk| !options.policy.is_forbidden(chunk))?;
+    options.io_limiter.write(path, &stripped_data)
+}
+
+fn size_change_text(size_change : SizeChange) -> String {
+    let image_size_before = size_change.before as f32;
+    let image_size_after = size_change.after as f32;
+    format!("[{:+}KB / {:3.0}%]",
+            (image_size_after - image_size_before) / 1000f32,
+            image_size_after / image_size_before * 100f32)
+}
+
+fn fix_description(verification : fix::Verification) -> String {
+    match verification {
+        fix::Verification::PixelsIdentical => String::from("converted to RGB/RGBA and optimized"),
+        fix::Verification::WithinTolerance { max_difference } => {
+            format!("converted to RGB/RGBA and optimized (max channel difference {})", max_difference)
+        },
+        fix::Verification::NotDecoded => String::from("no conversion needed, optimized"),
+    }
+}
+
+// Files which were changed are still printed with --quiet, but an estimate doesn't change them
+fn report_fixed(rel_path : &Path, description : &str, size_change : SizeChange, options : &ScanOptions) {
+    if options.estimate {
+        info!("Would fix {}: {} {}", rel_path.display(), description, size_change_text(size_change));
+    } else {
+        changed!("Fixed {}: {} {}", rel_path.display(), description, size_change_text(size_change));
+    }
+}
+
+fn fix_image(path : &Path,
+             rel_path : &Path,
+             header : &PngHeader,
+             summary : &ChunkSummary,
+             fix_options : &FixOptions,
+             options : &ScanOptions) -> fix::FixResult<FixOutcome> {
+    let original_data = options.io_limiter.read(path)?;
+
+    debug!("Fixing {}...", rel_path.display());
+    let (fixed_data, outcome) = fix::fix_data(&original_data, header, summary, fix_options)?;
+
+    if !options.estimate {
+        options.io_limiter.write(path, &fixed_data)?;
+    }
+
+    report_fixed(rel_path, &fix_description(outcome.verification), outcome.size_change, options);
+    Ok(outcome)
+}
+
+// Whether an RGBA image is decoded to see if it can drop its alpha channel
+fn checks_opaque_alpha(header : &PngHeader, options : &ScanOptions) -> bool {
+    options.fix_options.drop_opaque_alpha && header.pixel_format == PixelFormat::TrueColorWithAlpha
+}
+
+fn is_selected_for_fixing(header : &PngHeader, options : &ScanOptions) -> bool {
+    // Only matching images need fixing, unless interlaced, 16-bit or RGBA images should be rewritten too
+    let matched = options.pixel_format_match.matches(header);
+    let deinterlace = header.interlaced && options.fix_options.deinterlace;
+    let downconvert = header.bit_depth == 16 && options.fix_options.downconvert_16bit;
+    let drop_alpha = checks_opaque_alpha(header, options) && header.bit_depth == 8;
+
+    // Images outside the dimension filters are fine as they are, e.g. small indexed icons
+    (matched || deinterlace || downconvert || drop_alpha) && options.dimensions.contains(header)
+}
+
+// Whether a file has to go to the fix threads with --fix-jobs, judging by its header. Anything
+// else is handled while scanning, so this only has to be right about which files are slow.
+fn needs_fix_stage(path : &Path, scan_path : &Path, options : &ScanOptions, cache : Option<&Cache>) -> bool {
+    if !is_png(path) {
+        return (options.fix_archives && archive::is_archive(path)) ||
+            (options.convert_others && other_format(path).is_some());
+    }
+    if is_cached(path, scan_path, cache) {
+        return false;
+    }
+
+    let parse_result = {
+        let _permit = options.io_limiter.acquire();
+        parse_one(path)
+    };
+    match parse_result {
+        ParseResult::Valid(header) => options.convert_to.is_some() || is_selected_for_fixing(&header, options),
+        // Repairing decodes the whole image
+        ParseResult::AppleCgbi(_) => true,
+        _ => false,
+    }
+}
+
+// Fix the image if needed, or only record the finding in check mode
+fn fix_file(path : &Path,
+            rel_path : &Path,
+            header : &PngHeader,
+            summary : &ChunkSummary,
+            options : &ScanOptions,
+            result : &mut FileResult) {
+    if !is_selected_for_fixing(header, options) {
+        return;
+    }
+
+    // Indexed images by default, or whichever pixel formats --match selects
+    let matched = options.pixel_format_match.matches(header);
+    let indexed = matched && header.pixel_format == PixelFormat::IndexedColor;
+    let downconvert = header.bit_depth == 16 && options.fix_options.downconvert_16bit;
+
+    if indexed {
+        info!("{} is indexed!", rel_path.display());
+
+        if let Some(finding) = check_palette_bit_depth(header, summary) {
+            warn!("Warning: {}: {}", rel_path.display(), finding.description());
+            result.findings.push(finding);
+        }
+    } else if matched {
+        info!("{} is {:?} with {}-bit depth!", rel_path.display(), header.pixel_format, header.bit_depth);
+    } else if downconvert {
+        info!("{} is 16-bit!", rel_path.display());
+    }
+
+    // Converting would only keep the first frame
+    let skip_animated = summary.animated && !options.fix_options.force_apng;
+    if skip_animated && !options.check_only {
+        info!("Skipping {}, use --force-apng to fix animated PNGs anyway", rel_path.display());
+    }
+
+    let confirmed = || match &options.prompt {
+        Some(prompt) => {
+            let file_size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+            let confirmed = prompt.confirm_fix(rel_path, header, file_size);
+            if !confirmed {
+                info!("Skipped {}", rel_path.display());
+            }
+            confirmed
+        },
+        None => true,
+    };
+
+    if !options.check_only && !skip_animated && confirmed() {
+        let fix_options = FixOptions { convert_any: matched && !indexed, ..options.fix_options.clone() };
+        match fix_image(path, rel_path, header, summary, &fix_options, options) {
+            Ok(outcome) => result.fix_outcome = Some(outcome),
+            Err(FixError::TooLarge { pixels, decode_mem }) => {
+                warn!("Warning: skipping {}, it's too large to decode safely", rel_path.display());
+                result.findings.push(FindingKind::TooLarge { pixels, decode_mem });
+            },
+            Err(FixError::WouldGrow(size_change)) => {
+                info!("Skipped {}: would grow by {}KB, use --force to write it anyway",
+                      rel_path.display(),
+                      -size_change.saved() as f32 / 1000f32);
+                result.findings.push(FindingKind::WouldGrow(size_change));
+            },
+            Err(FixError::BelowMinSavings(size_change)) => {
+                info!("Skipped {}: would only save {}KB", rel_path.display(), size_change.saved() as f32 / 1000f32);
+                result.findings.push(FindingKind::BelowMinSavings(size_change));
+            },
+            Err(FixError::VerificationFailed) if options.exit_on_mismatch => {
+                error!("---------------------------------------------");
+                error!("ERROR: optimized image wasn't identical to original image ({})", rel_path.display());
+                error!("---------------------------------------------");
+                std::process::exit(EXIT_VERIFICATION_MISMATCH);
+            },
+            // Most RGBA images really use their alpha channel, so this isn't worth mentioning
+            Err(FixError::NotOpaque) => debug!("{} isn't fully opaque", rel_path.display()),
+            Err(e) => {
+                error!("Error: failed to fix {}: {}", rel_path.display(), e);
+                result.findings.push(FindingKind::FixFailed(fix_error_kind(&e)));
+            },
+        }
+    }
+
+    // An estimate leaves the file unfixed, even though it was converted in memory
+    let fixed = result.fix_outcome.is_some() && !options.estimate;
+    if indexed {
+        result.findings.push(FindingKind::Indexed { fixed });
+    } else if matched {
+        result.findings.push(FindingKind::MatchedPixelFormat {
+            pixel_format: header.pixel_format,
+            bit_depth: header.bit_depth,
+            fixed,
+        });
+    }
+    if downconvert {
+        result.findings.push(FindingKind::SixteenBit { fixed });
+    }
+    // The alpha channel is kept whenever a pixel isn't opaque
+    let dropped_alpha = result.fix_outcome.as_ref()
+        .is_some_and(|outcome| outcome.pixel_format != PixelFormat::TrueColorWithAlpha);
+    if checks_opaque_alpha(header, options) && dropped_alpha {
+        result.findings.push(FindingKind::OpaqueAlpha { fixed });
+    }
+}
+
+// Write a lossless copy of the image in another format, and delete the PNG if replacing
+fn export_file(path : &Path,
+               rel_path : &Path,
+               header : &PngHeader,
+               summary : &ChunkSummary,
+               format : ExportFormat,
+               options : &ScanOptions,
+               result : &mut FileResult) {
+    if !options.dimensions.contains(header) {
+        return;
+    }
+
+    if options.check_only {
+        result.findings.push(FindingKind::Exported { format, written: false });
+        return;
+    }
+
+    let exported = options.io_limiter.read(path).map_err(FixError::from).and_then(|original_data| {
+        debug!("Converting {} to {}...", rel_path.display(), format.name());
+        export::export_data(&original_data, header, summary, format, &options.fix_options)
+    });
+    let (exported_data, outcome) = match exported {
+        Ok(exported) => exported,
+        Err(FixError::TooLarge { pixels, decode_mem }) => {
+            warn!("Warning: skipping {}, it's too large to decode safely", rel_path.display());
+            result.findings.push(FindingKind::TooLarge { pixels, decode_mem });
+            return;
+        },
+        Err(FixError::VerificationFailed) if options.exit_on_mismatch => {
+            error!("---------------------------------------------");
+            error!("ERROR: {} image wasn't identical to original image ({})", format.name(), rel_path.display());
+            error!("---------------------------------------------");
+            std::process::exit(EXIT_VERIFICATION_MISMATCH);
+        },
+        Err(e) => {
+            error!("Error: failed to convert {}: {}", rel_path.display(), e);
+            result.findings.push(FindingKind::FixFailed(fix_error_kind(&e)));
+            return;
+        },
+    };
+
+    if !options.estimate {
+        let exported_path = path.with_extension(format.extension());
+        if let Err(e) = options.io_limiter.write(&exported_path, &exported_data) {
+            error!("Error: failed to write the {} copy of {}: {}", format.name(), rel_path.display(), e);
+            result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+            return;
+        }
+        // The copy was written, so it's still reported as exported
+        if options.replace_originals {
+            if let Err(e) = options.io_limiter.remove_file(path) {
+                error!("Error: failed to remove {} after converting it: {}", rel_path.display(), e);
+                result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+            }
+        }
+    }
+
+    report_fixed(rel_path, &format!("converted to {} and verified", format.name()), outcome.size_change, options);
+    result.fix_outcome = Some(outcome);
+    result.findings.push(FindingKind::Exported { format, written: !options.estimate });
+}
+
+// Short name for the kind of error, as written to the --failed-list file
+fn fix_error_kind(error : &FixError) -> &'static str {
+    match error {
+        FixError::Io(_) => "io",
+        FixError::InvalidPng(_) => "invalid-png",
+        FixError::Animated => "animated",
+        #[cfg(feature = "fix")]
+        FixError::Decode(_) => "decode",
+        #[cfg(feature = "fix")]
+        FixError::Optimize(_) => "optimize",
+        FixError::ExternalOptimizer(_) => "optimize",
+        FixError::TimedOut(_) => "timeout",
+        FixError::VerificationFailed => "verification",
+        FixError::TooLarge { .. } => "too-large",
+        FixError::WouldGrow(_) => "would-grow",
+        FixError::BelowMinSavings(_) => "below-min-savings",
+        FixError::Unsupported(_) => "unsupported",
+        FixError::NotOpaque => "not-opaque",
+    }
+}
+
+fn is_png(path : &Path) -> bool {
+    match path.extension() {
+        Some(ext) => ext == OsStr::new("png"),
+        None => false,
+    }
+}
+
+// Left behind by macOS on other filesystems. The ._ files hold the metadata of the file they're
+// named after, so ._icon.png looks like a PNG but isn't one.
+const MACOS_METADATA_NAMES: [&str; 6] = [".DS_Store", "__MACOSX", ".Spotlight-V100", ".Trashes", ".fseventsd",
+                                         ".TemporaryItems"];
+
+fn is_apple_metadata(file_name : &OsStr) -> bool {
+    let file_name = file_name.to_string_lossy();
+    file_name.starts_with("._") || MACOS_METADATA_NAMES.contains(&file_name.as_ref())
+}
+
+fn is_apple_metadata_path(path : &Path) -> bool {
+    path.components().any(|component| is_apple_metadata(component.as_os_str()))
+}
+
+// Files and folders which are left out of scans. The macOS metadata always is, and other
+// dotfiles and dot folders are unless --hidden is given.
+fn is_skipped_name(file_name : &OsStr, include_hidden : bool) -> bool {
+    is_apple_metadata(file_name) || (!include_hidden && file_name.to_string_lossy().starts_with('.'))
+}
+
+// Relative path with '/' separators on all platforms, as used in zip files and reports
+fn slash_path(rel_path : &Path) -> String {
+    rel_path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Raster formats which --convert-others turns into PNGs, by extension
+fn other_format(path : &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "bmp" => Some("BMP"),
+        "tga" => Some("TGA"),
+        "tif" | "tiff" => Some("TIFF"),
+        _ => None,
+    }
+}
+
+// Save a BMP/TGA/TIFF image as an optimized PNG next to it, then delete the original
+fn convert_other_file(path : &Path, rel_path : &Path, format : &'static str, options : &ScanOptions) -> FileResult {
+    let mut result = FileResult::default();
+    info!("{} is a {} image!", rel_path.display(), format);
+
+    let png_path = path.with_extension("png");
+    if png_path.exists() {
+        warn!("Warning: not converting {}, {} already exists", rel_path.display(), png_path.display());
+        result.findings.push(FindingKind::OtherFormat { format, converted: false });
+        return result;
+    }
+
+    if !options.check_only {
+        let converted = options.io_limiter.read(path).map_err(FixError::from).and_then(|original_data| {
+            debug!("Converting {} to PNG...", rel_path.display());
+            let (png_data, outcome) = fix::convert_other_data(&original_data, &options.fix_options)?;
+            if !options.estimate {
+                options.io_limiter.write(&png_path, &png_data)?;
+            }
+            Ok((png_data, outcome))
+        });
+        match converted {
+            Ok((png_data, outcome)) => {
+                // The PNG was written, so it's still reported as converted
+                if !options.estimate {
+                    if let Err(e) = options.io_limiter.remove_file(path) {
+                        error!("Error: failed to remove {} after converting it: {}", rel_path.display(), e);
+                        result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+                    }
+                }
+                report_fixed(rel_path, "converted to PNG and optimized", outcome.size_change, options);
+                if let ParseResult::Valid(header) = png_header_scanner::parse_bytes(&png_data) {
+                    result.header = Some(header);
+                }
+                result.fix_outcome = Some(outcome);
+            },
+            Err(FixError::VerificationFailed) if options.exit_on_mismatch => {
+                error!("---------------------------------------------");
+                error!("ERROR: converted image wasn't identical to original image ({})", rel_path.display());
+                error!("---------------------------------------------");
+                std::process::exit(EXIT_VERIFICATION_MISMATCH);
+            },
+            Err(e) => {
+                error!("Error: failed to convert {}: {}", rel_path.display(), e);
+                result.findings.push(FindingKind::FixFailed(fix_error_kind(&e)));
+            },
+        }
+    }
+
+    let converted = result.fix_outcome.is_some() && !options.estimate;
+    result.findings.push(FindingKind::OtherFormat { format, converted });
+    result
+}
+
+// Findings for a PNG inside an archive. They can only be reported, fixing them would mean
+// rewriting the archive.
+fn archive_member_result(member_path : &Path, parse_result : ParseResult, options : &ScanOptions) -> FileResult {
+    let header = match parse_result {
+        ParseResult::Valid(header) => header,
+        ParseResult::AppleCgbi(header) => {
+            info!("{} is an Apple CgBI PNG!", member_path.display());
+            let findings = vec![FindingKind::AppleCgbi { repaired: false }];
+            return FileResult { header: Some(header), findings, ..Default::default() };
+        },
+        error_parse_result => {
+            error!("Error {:?}: {}", error_parse_result, member_path.display());
+            return FileResult { findings: vec![FindingKind::Invalid(error_parse_result)], ..Default::default() };
+        },
+    };
+
+    let mut findings = Vec::new();
+    if options.pixel_format_match.matches(&header) && options.dimensions.contains(&header) {
+        if header.pixel_format == PixelFormat::IndexedColor {
+            info!("{} is indexed!", member_path.display());
+            findings.push(FindingKind::Indexed { fixed: false });
+        } else {
+            findings.push(FindingKind::MatchedPixelFormat {
+                pixel_format: header.pixel_format,
+                bit_depth: header.bit_depth,
+                fixed: false,
+            });
+        }
+    }
+    if header.interlaced {
+        findings.push(FindingKind::Interlaced { fixed: false });
+    }
+    if let Some(max_dimension) = options.max_dimension {
+        if header.width > max_dimension || header.height > max_dimension {
+            let (width, height) = (header.width, header.height);
+            findings.push(FindingKind::OverMaxDimension { width, height, max_dimension, resized: false });
+        }
+    }
+    findings.extend(options.dimension_policy.check(&header));
+    FileResult { header: Some(header), findings, ..Default::default() }
+}
+
+// Fix a PNG from an archive in memory, returning its new contents if it was fixed. A
+// verification mismatch stops the run, as it does for files on disk, unless exit_on_mismatch is off.
+fn fix_archive_member(member_path : &Path, data : &[u8], options : &ScanOptions) -> (FileResult, Option<Vec<u8>>) {
+    match try_fix_archive_member(member_path, data, options) {
+        Ok(fixed) => fixed,
+        Err(e) if !options.exit_on_mismatch => {
+            error!("Error: failed to fix {}: {}", member_path.display(), e);
+            let mut result = archive_member_result(member_path, png_header_scanner::parse_header(data), options);
+            result.findings.push(FindingKind::FixFailed(fix_error_kind(&e)));
+            (result, None)
+        },
+        Err(_e) => {
+            error!("---------------------------------------------");
+            error!("ERROR: optimized image wasn't identical to original image ({})", member_path.display());
+            error!("---------------------------------------------");
+            std::process::exit(EXIT_VERIFICATION_MISMATCH);
+        },
+    }
+}
+
+// As fix_archive_member, but a verification mismatch is returned as FixError::VerificationFailed
+// for callers which must keep running, like the HTTP server
+fn try_fix_archive_member(member_path : &Path, data : &[u8], options : &ScanOptions)
+    -> fix::FixResult<(FileResult, Option<Vec<u8>>)> {
+    let parse_result = png_header_scanner::parse_header(data);
+    let mut result = archive_member_result(member_path, parse_result, options);
+
+    let header = match parse_result {
+        ParseResult::Valid(header) => header,
+        _ => return Ok((result, None)),
+    };
+    let matched = result.findings.iter()
+        .any(|kind| matches!(kind, FindingKind::Indexed { .. } | FindingKind::MatchedPixelFormat { .. }));
+    if !matched {
+        return Ok((result, None));
+    }
+
+    let summary = match chunks::read_chunks(&mut std::io::Cursor::new(data)) {
+        Ok(summary) => summary,
+        Err(_e) => {
+            error!("Error {:?}: {}", ParseResult::ReadFail, member_path.display());
+            result.findings.push(FindingKind::Invalid(ParseResult::ReadFail));
+            return Ok((result, None));
+        },
+    };
+
+    debug!("Fixing {}...", member_path.display());
+    let fix_options = FixOptions {
+        convert_any: header.pixel_format != PixelFormat::IndexedColor,
+        ..options.fix_options.clone()
+    };
+    match fix::fix_data(data, &header, &summary, &fix_options) {
+        Ok((fixed_data, outcome)) => {
+            report_fixed(member_path, &fix_description(outcome.verification), outcome.size_change, options);
+
+            // An estimate leaves the archive unchanged, even though it was fixed in memory
+            let fixed = !options.estimate;
+            for kind in &mut result.findings {
+                match kind {
+                    FindingKind::Indexed { fixed: entry_fixed } |
+                    FindingKind::MatchedPixelFormat { fixed: entry_fixed, .. } |
+                    FindingKind::Interlaced { fixed: entry_fixed } => *entry_fixed = fixed,
+                    _ => {},
+                }
+            }
+            result.fix_outcome = Some(outcome);
+            Ok((result, Some(fixed_data)))
+        },
+        Err(FixError::VerificationFailed) => Err(FixError::VerificationFailed),
+        Err(e) => {
+            info!("Skipped {}: {}", member_path.display(), e);
+            result.findings.push(match e {
+                FixError::Animated => FindingKind::Animated,
+                FixError::TooLarge { pixels, decode_mem } => FindingKind::TooLarge { pixels, decode_mem },
+                FixError::WouldGrow(size_change) => FindingKind::WouldGrow(size_change),
+                FixError::BelowMinSavings(size_change) => FindingKind::BelowMinSavings(size_change),
+                e => FindingKind::FixFailed(fix_error_kind(&e)),
+            });
+            Ok((result, None))
+        },
+    }
+}
+
+// Fix the PNGs inside a zip file and write the archive back with the fixed entries
+fn fix_archive_file(path : &Path, rel_path : &Path, options : &ScanOptions) -> FileResult {
+    let mut result = FileResult::default();
+    let rewritten = {
+        let _permit = options.io_limiter.acquire();
+        archive::rewrite_archive(path, |entry_name, data| {
+            let member_path = archive::member_path(rel_path, entry_name);
+            let (member_result, fixed_data) = fix_archive_member(&member_path, data, options);
+            result.archive_members.push((member_path, member_result));
+            fixed_data
+        })
+    };
+
+    match rewritten {
+        Ok(Some(archive_data)) => {
+            if !options.estimate {
+                match options.io_limiter.write(path, &archive_data) {
+                    Ok(()) => changed!("Rewrote {}", rel_path.display()),
+                    // The members are still reported, as left unfixed
+                    Err(e) => {
+                        error!("Error: failed to write {}: {}", rel_path.display(), e);
+                        result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+                        for (_, member_result) in &mut result.archive_members {
+                            member_result.fix_outcome = None;
+                            for kind in &mut member_result.findings {
+                                match kind {
+                                    FindingKind::Indexed { fixed } |
+                                    FindingKind::MatchedPixelFormat { fixed, .. } |
+                                    FindingKind::Interlaced { fixed } => *fixed = false,
+                                    _ => {},
+                                }
+                            }
+                        }
+                    },
+                }
+            }
+        },
+        Ok(None) => {},
+        Err(e) => {
+            warn!("Warning: can't rewrite {} as a zip file: {}", rel_path.display(), e);
+            return FileResult::default();
+        },
+    }
+    result
+}
+
+fn scan_archive_file(path : &Path, rel_path : &Path, options : &ScanOptions) -> FileResult {
+    // Read-only archives are only scanned
+    if options.fix_archives && !options.check_only && !options.io_limiter.skips_read_only_file(path) {
+        return fix_archive_file(path, rel_path, options);
+    }
+
+    let headers = {
+        let _permit = options.io_limiter.acquire();
+        archive::scan_archive(path)
+    };
+
+    let mut result = FileResult::default();
+    match headers {
+        Ok(headers) => {
+            for (entry_name, parse_result) in headers {
+                let member_path = archive::member_path(rel_path, &entry_name);
+                let member_result = archive_member_result(&member_path, parse_result, options);
+                result.archive_members.push((member_path, member_result));
+            }
+        },
+        // Other formats can share the extension, like non-zip .pak files
+        Err(e) => warn!("Warning: can't read {} as a zip file: {}", rel_path.display(), e),
+    }
+    result
+}
+
+fn scan_one_file(path : &Path, rel_path : &Path, options : &ScanOptions) -> FileResult {
+    // Only process files with .png extension
+    if is_png(path) {
+        return handle_one_file(path, rel_path, options);
+    }
+
+    if options.scan_archives && archive::is_archive(path) {
+        return scan_archive_file(path, rel_path, options);
+    }
+
+    if options.convert_others {
+        if let Some(format) = other_format(path) {
+            return convert_other_file(path, rel_path, format, options);
+        }
+    }
+
+    if options.find_misnamed_pngs {
+        let format = {
+            let _permit = options.io_limiter.acquire();
+            detect_format(path)
+        };
+        if format == Some("PNG") {
+            return FileResult { findings: vec![FindingKind::MissingPngExtension], ..Default::default() };
+        }
+    }
+
+    FileResult::default()
+}
+
+// (device, inode) pair which is the same for all hard links to a file
+#[cfg(unix)]
+fn file_id(path : &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_path : &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// Files this tool keeps in the folder it works on, which aren't part of the folder's contents
+fn is_state_file(path : &Path) -> bool {
+    let state_file_names = [lock::LOCK_FILE_NAME, cache::CACHE_FILE_NAME, checkpoint::CHECKPOINT_FILE_NAME];
+    path.file_name().is_some_and(|file_name| state_file_names.iter().any(|name| file_name == OsStr::new(name))) ||
+        atomic_write::is_temp_file(path) ||
+        path.components().any(|component| is_state_dir(component.as_os_str()))
+}
+
+// Folders this tool keeps in the folder it works on, which hold copies of the scanned files
+fn is_state_dir(dir_name : &OsStr) -> bool {
+    dir_name == OsStr::new(journal::JOURNAL_DIR_NAME) || dir_name == OsStr::new(staging::STAGING_DIR_NAME)
+}
+
+// Scan the file unless the cache says it was clean and hasn't changed since
+fn scan_one_file_cached(path : &Path, rel_path : &Path, options : &ScanOptions, cache : Option<&Cache>)
+                        -> (FileResult, Option<FileStamp>) {
+    if cache.is_none() || !is_png(path) {
+        return (scan_one_file(path, rel_path, options), None);
+    }
+
+    // Taken before scanning, so a change while it's being scanned invalidates the entry
+    let stamp = cache::file_stamp(path);
+    if let (Some(cache), Some(stamp)) = (cache, stamp) {
+        if let Some(header) = cache.lookup(rel_path, stamp) {
+            return (FileResult { header: Some(header), ..Default::default() }, Some(stamp));
+        }
+    }
+    (scan_one_file(path, rel_path, options), stamp)
+}
+
+// Whether the cache says the file was clean and hasn't changed since, so it won't be read at all
+fn is_cached(path : &Path, scan_path : &Path, cache : Option<&Cache>) -> bool {
+    cache.is_some_and(|cache| {
+        let rel_path = path.strip_prefix(scan_path).unwrap();
+        cache::file_stamp(path).and_then(|stamp| cache.lookup(rel_path, stamp)).is_some()
+    })
+}
+
+// Read the headers of a batch's PNGs through io_uring, except those the cache will skip anyway
+fn prefetch_headers(header_prefetch : &uring::HeaderPrefetch,
+                    paths : &[PathBuf],
+                    scan_path : &Path,
+                    cache : Option<&Cache>) {
+    let png_paths : Vec<&Path> = paths.iter()
+        .map(PathBuf::as_path)
+        .filter(|path| is_png(path) && !is_cached(path, scan_path, cache))
+        .collect();
+    if let Err(e) = header_prefetch.fill(&png_paths) {
+        warn!("Warning: failed to read headers through io_uring, reading them normally: {}", e);
+    }
+}
+
+fn is_size_in_range(path : &Path, options : &ScanOptions) -> bool {
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        // Let the scan report the error
+        Err(_e) => return true,
+    };
+    options.min_size.is_none_or(|min_size| size >= min_size) &&
+        options.max_size.is_none_or(|max_size| size <= max_size)
+}
+
+// Size and modification time, which change while something is still writing the file
+fn size_and_modified(path : &Path) -> Option<(u64, SystemTime)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+// How long until the file was last modified at least --min-age ago. A modification time in the
+// future counts as just now.
+fn time_until_old_enough(path : &Path, options : &ScanOptions) -> Duration {
+    let min_age = match options.min_age {
+        Some(min_age) => min_age,
+        None => return Duration::ZERO,
+    };
+    match size_and_modified(path) {
+        Some((_, modified)) => min_age.saturating_sub(modified.elapsed().unwrap_or(Duration::ZERO)),
+        // Let the scan report the error
+        None => Duration::ZERO,
+    }
+}
+
+// Leave out PNGs which are still being written, from --min-age and --stable-interval. The sizes
+// are all checked over the same interval, so it's only waited for once.
+fn retain_settled(paths : &mut Vec<PathBuf>, options : &ScanOptions) {
+    if let Some(min_age) = options.min_age {
+        let num_paths = paths.len();
+        paths.retain(|path| !is_png(path) || time_until_old_enough(path, options).is_zero());
+        if paths.len() < num_paths {
+            info!("Skipping {} PNGs modified in the last {:?}", num_paths - paths.len(), min_age);
+        }
+    }
+
+    if let Some(stable_interval) = options.stable_interval {
+        let before : Vec<Option<(u64, SystemTime)>> = paths.iter()
+            .map(|path| if is_png(path) { size_and_modified(path) } else { None })
+            .collect();
+        std::thread::sleep(stable_interval);
+        let num_paths = paths.len();
+        let mut before = before.into_iter();
+        paths.retain(|path| {
+            let before = before.next().unwrap();
+            !is_png(path) || before.is_none() || before == size_and_modified(path)
+        });
+        if paths.len() < num_paths {
+            info!("Skipping {} PNGs which are still changing", num_paths - paths.len());
+        }
+    }
+}
+
+// Wait until a file which was just created or modified is old enough for --min-age, and check it
+// stays the same for --stable-interval. Returns false if it's still changing, in which case the
+// watcher sees it again once it's written.
+fn wait_until_settled(path : &Path, options : &ScanOptions) -> bool {
+    std::thread::sleep(time_until_old_enough(path, options));
+    if !time_until_old_enough(path, options).is_zero() {
+        return false;
+    }
+    match options.stable_interval {
+        Some(stable_interval) => {
+            let before = size_and_modified(path);
+            std::thread::sleep(stable_interval);
+            before.is_some() && before == size_and_modified(path)
+        },
+        None => true,
+    }
+}
+
+// Order in which files are handled, from --order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum FileOrder {
+    // As the folder is walked
+    #[default]
+    Walk,
+    Name,
+    SizeDesc,
+    SizeAsc,
+    ModifiedDesc,
+    ModifiedAsc,
+}
+
+fn sort_paths(paths : &mut [PathBuf], order : FileOrder) {
+    let size = |path : &PathBuf| fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+    let modified = |path : &PathBuf| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+
+    match order {
+        FileOrder::Walk => {},
+        FileOrder::Name => paths.sort(),
+        FileOrder::SizeDesc => paths.sort_by_cached_key(|path| std::cmp::Reverse(size(path))),
+        FileOrder::SizeAsc => paths.sort_by_cached_key(size),
+        FileOrder::ModifiedDesc => paths.sort_by_cached_key(|path| std::cmp::Reverse(modified(path))),
+        FileOrder::ModifiedAsc => paths.sort_by_cached_key(modified),
+    }
+}
+
+// Keep a random selection of count paths, in their original order. It doesn't need to be a
+// good random number generator, just different between runs.
+fn random_sample(paths : &mut Vec<PathBuf>, count : usize) {
+    if count >= paths.len() {
+        return;
+    }
+
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut state = seed | 1;
+    let mut next_random = || {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    // Partial Fisher-Yates shuffle of indices, so the first count are a uniform sample
+    let mut indices : Vec<usize> = (0..paths.len()).collect();
+    for i in 0..count {
+        let j = i + (next_random() % (indices.len() - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    let mut chosen = indices[..count].to_vec();
+    chosen.sort_unstable();
+
+    let mut sampled = Vec::with_capacity(count);
+    for index in chosen {
+        sampled.push(std::mem::take(&mut paths[index]));
+    }
+    *paths = sampled;
+}
+
+// Every file under scan_path which still needs to be handled
+fn walk_folder(scan_path : &Path,
+               include_hidden : bool,
+               mut cache : Option<&mut Cache>,
+               checkpoint : Option<&Checkpoint>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    // First path seen for each file, so hard links to it are only handled once
+    let mut seen_files : HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut num_skipped = 0;
+    let walker = WalkDir::new(scan_path).into_iter().filter_entry(|entry| {
+        // The folder itself is scanned even if it's hidden
+        if entry.depth() == 0 {
+            return true;
+        }
+        // The copies in them would otherwise be scanned, and possibly fixed, too
+        if is_state_dir(entry.file_name()) {
+            return false;
+        }
+        // Hidden too, but leftover temp files have to be seen to be cleaned up below
+        if is_state_file(entry.path()) {
+            return true;
+        }
+        let skipped = is_skipped_name(entry.file_name(), include_hidden);
+        if skipped {
+            num_skipped += 1;
+        }
+        !skipped
+    });
+    for entry in walker {
+        let entry = entry.expect("File I/O Error?");
+
+        // Skip non-files
+        if !entry.path().is_file() {
+            continue;
+        }
+
+        if let Some(checkpoint) = checkpoint {
+            let rel_path = entry.path().strip_prefix(scan_path).unwrap();
+            if checkpoint.is_done(rel_path) {
+                if let Some(cache) = cache.as_deref_mut() {
+                    cache.carry_over(rel_path);
+                }
+                continue;
+            }
+            // Left behind by a fix which was interrupted, the original file is still intact
+            if checkpoint.is_resumed() && atomic_write::is_temp_file(entry.path()) {
+                info!("Removing unfinished temp file {}", rel_path.display());
+                fs::remove_file(entry.path()).expect("Failed to remove temp file");
+                continue;
+            }
+        }
+
+        if let Some(file_id) = file_id(entry.path()) {
+            let rel_path = entry.path().strip_prefix(scan_path).unwrap().to_path_buf();
+            if let Some(first_rel_path) = seen_files.get(&file_id) {
+                info!("Skipping {}: hard link to {}", rel_path.display(), first_rel_path.display());
+                continue;
+            }
+            seen_files.insert(file_id, rel_path);
+        }
+        paths.push(entry.into_path());
+    }
+    if num_skipped > 0 {
+        info!("Skipping {} hidden or macOS metadata files and folders, use --hidden to include the hidden ones",
+              num_skipped);
+    }
+    paths
+}
+
+// Running totals for --tui, counting the PNGs inside archives too
+fn report_to_tui(rel_path : &Path, result : &FileResult) {
+    let (mut fixed, mut failed, mut bytes_saved) = (0, 0, 0);
+    let member_results = result.archive_members.iter().map(|(_, member_result)| member_result);
+    for result in std::iter::once(result).chain(member_results) {
+        if let Some(fix_outcome) = &result.fix_outcome {
+            fixed += 1;
+            bytes_saved += fix_outcome.size_change.saved();
+        }
+        failed += result.findings.iter().filter(|kind| matches!(kind, FindingKind::FixFailed(_))).count();
+    }
+    tui::file_finished(rel_path, fixed, failed, bytes_saved);
+}
+
+// Handle every file under scan_path, spread over the current thread pool
+fn scan_folder(scan_path : &Path,
+               options : &ScanOptions,
+               mut cache : Option<&mut Cache>,
+               checkpoint : Option<&Checkpoint>) -> ScanSummary {
+    let mut paths : Vec<PathBuf> = match options.retry_files.as_ref().or(options.git_changed_files.as_ref()) {
+        Some(rel_paths) => rel_paths.iter()
+            .map(|rel_path| scan_path.join(rel_path))
+            .filter(|path| path.is_file())
+            .collect(),
+        None => walk_folder(scan_path, options.include_hidden, cache.as_deref_mut(), checkpoint),
+    };
+
+    if options.min_size.is_some() || options.max_size.is_some() {
+        let num_paths = paths.len();
+        paths.retain(|path| !is_png(path) || is_size_in_range(path, options));
+        if paths.len() < num_paths {
+            info!("Skipping {} PNGs outside the --min-size/--max-size range", num_paths - paths.len());
+        }
+    }
+    retain_settled(&mut paths, options);
+    sort_paths(&mut paths, options.order);
+
+    let paths = if options.limit.is_some() || options.sample.is_some() {
+        let mut png_paths : Vec<PathBuf> = paths.into_iter().filter(|path| is_png(path)).collect();
+        let num_pngs = png_paths.len();
+        if let Some(sample) = options.sample {
+            random_sample(&mut png_paths, sample);
+        }
+        if let Some(limit) = options.limit {
+            png_paths.truncate(limit);
+        }
+        info!("Handling {} of {} PNGs", png_paths.len(), num_pngs);
+        png_paths
+    } else {
+        paths
+    };
+
+    // Bridged, so the threads take files in the sorted order instead of each starting on its own
+    // slice of the list
+    tui::set_total(paths.len());
+    let cache_for_lookup = cache.as_deref();
+    let handle_path = |(index, path) : (usize, &PathBuf)| {
+        tui::wait_while_paused();
+        let rel_path = path.strip_prefix(scan_path).unwrap();
+        if is_interrupted() || tui::skip_file(rel_path) {
+            return (index, None);
+        }
+
+        tui::file_started(rel_path);
+        let (mut result, stamp) = scan_one_file_cached(path, rel_path, options, cache_for_lookup);
+        exec::run_for_result(path, rel_path, &result, options);
+        if !is_state_file(path) {
+            if let Err(e) = options.io_limiter.mirror_if_unchanged(path) {
+                error!("Error: failed to copy {} to the output folder: {}", rel_path.display(), e);
+                result.findings.push(FindingKind::FixFailed(fix_error_kind(&FixError::from(e))));
+            }
+        }
+        if options.json_lines {
+            jsonl::print_file_result(rel_path, &result);
+            for (member_path, member_result) in &result.archive_members {
+                jsonl::print_file_result(member_path, member_result);
+            }
+        }
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.record(rel_path).expect("Failed to write checkpoint");
+        }
+        if tui::is_active() {
+            report_to_tui(rel_path, &result);
+        }
+        (index, Some((result, stamp)))
+    };
+
+    let mut results = match &options.fix_pool {
+        Some(fix_pool) if !options.check_only => {
+            let needs_fixing = |path : &Path| needs_fix_stage(path, scan_path, options, cache_for_lookup);
+            fix_queue::scan_then_fix(&paths, fix_pool, needs_fixing, handle_path)
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        },
+        _ => {
+            // With --io-uring the headers of each batch are read ahead together before its files are handled
+            let batch_size = match options.header_prefetch {
+                Some(_) => uring::PREFETCH_BATCH_SIZE,
+                None => paths.len().max(1),
+            };
+            let mut results = Vec::with_capacity(paths.len());
+            for (batch_index, batch) in paths.chunks(batch_size).enumerate() {
+                if let Some(header_prefetch) = &options.header_prefetch {
+                    if !is_interrupted() {
+                        prefetch_headers(header_prefetch, batch, scan_path, cache_for_lookup);
+                    }
+                }
+                let first_index = batch_index * batch_size;
+                let batch_results : Vec<_> = batch.iter()
+                    .enumerate()
+                    .map(|(index, path)| (first_index + index, path))
+                    .par_bridge()
+                    .map(&handle_path)
+                    .collect();
+                results.extend(batch_results);
+            }
+            results
+        },
+    };
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut summary = ScanSummary::default();
+    for (path, (_, result)) in paths.iter().zip(results) {
+        let rel_path = path.strip_prefix(scan_path).unwrap();
+        let (result, stamp) = match result {
+            Some(result) => result,
+            None => {
+                // Not handled this time, so it's still as clean as it was
+                if let Some(cache) = cache.as_deref_mut() {
+                    cache.carry_over(rel_path);
+                }
+                continue;
+            },
+        };
+
+        // Only files with nothing to report are skipped next time
+        if let (Some(cache), Some(stamp), Some(header)) = (cache.as_deref_mut(), stamp, result.header) {
+            if result.findings.is_empty() && result.fix_outcome.is_none() {
+                cache.insert(rel_path, stamp, header);
+            }
+        }
+
+        if is_png(path) {
+            summary.scanned.push(ScannedFile { rel_path: rel_path.to_path_buf(), header: result.header });
+        }
+        for (member_path, member_result) in result.archive_members {
+            summary.scanned.push(ScannedFile { rel_path: member_path.clone(), header: member_result.header });
+            for kind in member_result.findings {
+                summary.findings.push(Finding { rel_path: member_path.clone(), kind });
+            }
+            if let (Some(header), Some(fix_outcome)) = (member_result.header, member_result.fix_outcome) {
+                summary.fixed.push(FixedFile {
+                    rel_path: member_path,
+                    width: header.width,
+                    height: header.height,
+                    size_change: fix_outcome.size_change,
+                    thumbnails: fix_outcome.thumbnails,
+                });
+            }
+        }
+        for kind in result.findings {
+            summary.findings.push(Finding { rel_path: rel_path.to_path_buf(), kind });
+        }
+        if let (Some(header), Some(fix_outcome)) = (result.header, result.fix_outcome) {
+            summary.fixed.push(FixedFile {
+                rel_path: rel_path.to_path_buf(),
+                width: header.width,
+                height: header.height,
+                size_change: fix_outcome.size_change,
+                thumbnails: fix_outcome.thumbnails,
+            });
+        }
+    }
+
+    summary
+}
+
+// Another instance working on the same folder could overwrite files while they're being fixed
+fn lock_folder_or_exit(scan_path : &Path, wait : bool) -> lock::ScanLock {
+    match lock::lock_folder(scan_path, wait).expect("Failed to create lock file") {
+        lock::LockResult::Locked(scan_lock) => scan_lock,
+        lock::LockResult::Busy(process_id) => {
+            match process_id {
+                Some(process_id) => {
+                    eprintln!("[{}] is already being processed by process {}", scan_path.display(), process_id)
+                },
+                None => eprintln!("[{}] is already being processed by another instance", scan_path.display()),
+            }
+            eprintln!("Use --wait-lock to wait for it to finish");
+            std::process::exit(EXIT_LOCKED);
+        },
+    }
+}
+
+// Everything which changes what a scan reports or fixes, so a cache is only reused with the same settings
+fn cache_settings(options : &ScanOptions) -> String {
+    format!("{} deep={} truncate_trailing={} convert_to={:?} convert_others={} palette_report={} \
+             max_dimension={:?} resize_over_limit={} set_dpi={:?} fix_gamma={} {:?} {:?} {:?} {:?} {:?}",
+            env!("CARGO_PKG_VERSION"), options.deep, options.truncate_trailing,
+            options.convert_to, options.convert_others, options.palette_report,
+            options.max_dimension, options.resize_over_limit, options.set_dpi, options.fix_gamma,
+            options.dimension_policy, options.fix_options, options.policy, options.pixel_format_match,
+            options.dimensions)
+}
+
+// A number with an optional K/M/G suffix (powers of 1000), like 500M
+fn parse_size(value : &str) -> Option<u64> {
+    let (number, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1_000),
+        'M' => (&value[..value.len() - 1], 1_000_000),
+        'G' => (&value[..value.len() - 1], 1_000_000_000),
+        _ => (value, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// Either a percentage like 5% or a size like 2K
+fn parse_min_savings(value : &str) -> Option<MinSavings> {
+    if let Some(percent) = value.strip_suffix('%') {
+        let percent = percent.parse::<f64>().ok()?;
+        if percent >= 0f64 { Some(MinSavings::Percent(percent)) } else { None }
+    } else {
+        parse_size(value).map(MinSavings::Bytes)
+    }
+}
+
+fn is_min_savings(value : String) -> Result<(), String> {
+    match parse_min_savings(&value) {
+        Some(_) => Ok(()),
+        None => Err(String::from("must be a percentage like 5%, or a size like 2K")),
+    }
+}
+
+// A number of seconds, or a number followed by ms, s, m or h, like 90s or 2m
+fn parse_duration(value : &str) -> Option<Duration> {
+    let (number, multiplier_ms) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 1)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1_000)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60_000)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 3_600_000)
+    } else {
+        (value, 1_000)
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier_ms).map(Duration::from_millis)
+}
+
+fn is_duration(value : String) -> Result<(), String> {
+    match parse_duration(&value) {
+        Some(duration) if duration > Duration::from_secs(0) => Ok(()),
+        _ => Err(String::from("must be a time greater than 0, like 60s, 2m or 500ms")),
+    }
+}
+
+fn is_command(value : String) -> Result<(), String> {
+    if value.trim().is_empty() {
+        Err(String::from("must be a command to run"))
+    } else {
+        Ok(())
+    }
+}
+
+fn is_size(value : String) -> Result<(), String> {
+    match parse_size(&value) {
+        Some(_) => Ok(()),
+        None => Err(String::from("must be a number, optionally followed by K, M or G")),
+    }
+}
+
+// Value of an optional argument, exiting with clap's error if it isn't a valid number
+fn optional_value<T: std::str::FromStr>(matches : &clap::ArgMatches, name : &str) -> Option<T> {
+    if matches.is_present(name) {
+        Some(value_t!(matches, name, T).unwrap_or_else(|e| exit_usage_error(e)))
+    } else {
+        None
+    }
+}
+
+fn is_positive_number(value : String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(number) if number > 0 => Ok(()),
+        _ => Err(String::from("must be a number greater than 0")),
+    }
+}
+
+// Comma separated numbers or ranges between min and max, like 0,5 or 1-9
+fn parse_number_list(value : &str, min : u8, max : u8) -> Option<HashSet<u8>> {
+    let mut numbers = HashSet::new();
+    for item in value.split(',') {
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (first.trim().parse::<u8>().ok()?, last.trim().parse::<u8>().ok()?),
+            None => {
+                let number = item.trim().parse::<u8>().ok()?;
+                (number, number)
+            },
+        };
+        if first < min || last > max || first > last {
+            return None;
+        }
+        numbers.extend(first..=last);
+    }
+    Some(numbers)
+}
+
+fn number_list_validator(value : &str, min : u8, max : u8) -> Result<(), String> {
+    match parse_number_list(value, min, max) {
+        Some(_) => Ok(()),
+        None => Err(format!("must be a list of numbers from {0} to {1}, like {0},{1} or {0}-{1}", min, max)),
+    }
+}
+
+fn is_filter_list(value : String) -> Result<(), String> {
+    number_list_validator(&value, 0, 5)
+}
+
+fn is_compression_list(value : String) -> Result<(), String> {
+    number_list_validator(&value, 1, 9)
+}
+
+fn is_strategy_list(value : String) -> Result<(), String> {
+    number_list_validator(&value, 0, 3)
+}
+
+// The built-in optimizer with the --oxipng-* settings
+#[cfg(feature = "fix")]
+fn oxipng_optimizer(matches : &clap::ArgMatches) -> Option<Arc<dyn Optimizer>> {
+    // Already checked by the validators
+    Some(Arc::new(Oxipng {
+        level: value_t!(matches, "oxipng-level", u8).unwrap_or_else(|e| exit_usage_error(e)),
+        filters: matches.value_of("oxipng-filters").and_then(|value| parse_number_list(value, 0, 5)),
+        compression: matches.value_of("oxipng-zc").and_then(|value| parse_number_list(value, 1, 9)),
+        strategies: matches.value_of("oxipng-zs").and_then(|value| parse_number_list(value, 0, 3)),
+        window: match matches.value_of("oxipng-zw") {
+            Some("256") => Some(8),
+            Some("512") => Some(9),
+            Some("1k") => Some(10),
+            Some("2k") => Some(11),
+            Some("4k") => Some(12),
+            Some("8k") => Some(13),
+            Some("16k") => Some(14),
+            Some("32k") => Some(15),
+            _ => None,
+        },
+        zopfli: matches.is_present("oxipng-zopfli"),
+        interlace: optional_value(matches, "oxipng-interlace"),
+        alpha: matches.is_present("oxipng-alpha"),
+        bit_depth_reduction: !matches.is_present("oxipng-nb"),
+        palette_reduction: !matches.is_present("oxipng-np"),
+        idat_recoding: !matches.is_present("oxipng-nz"),
+    }))
+}
+
+// Nothing to optimize with, this build can't fix images
+#[cfg(not(feature = "fix"))]
+fn oxipng_optimizer(_matches : &clap::ArgMatches) -> Option<Arc<dyn Optimizer>> {
+    None
+}
+
+// Parse a pngquant style quality range like 65-90
+#[cfg(feature = "fix")]
+fn parse_quality(value : &str) -> Result<(u8, u8), String> {
+    let error = || format!("expected a quality range like 65-90, got {}", value);
+    let (min, max) = value.split_once('-').ok_or_else(error)?;
+    let min : u8 = min.trim().parse().map_err(|_| error())?;
+    let max : u8 = max.trim().parse().map_err(|_| error())?;
+    if min > max || max > 100 {
+        return Err(error());
+    }
+    Ok((min, max))
+}
+
+#[cfg(feature = "fix")]
+fn is_quality(value : String) -> Result<(), String> {
+    parse_quality(&value).map(|_| ())
+}
+
+#[cfg(feature = "fix")]
+fn manifest_arg() -> Arg<'static, 'static> {
+    Arg::with_name("manifest")
+        .long("manifest")
+        .value_name("FILE")
+        .help("Manifest file, instead of pngscanner-manifest.json in the folder")
+}
+
+// Subcommands which decode images, so they need the fix feature
+#[cfg(feature = "fix")]
+fn with_image_subcommands(app : App<'static, 'static>) -> App<'static, 'static> {
+    app
+        .subcommand(SubCommand::with_name("bench")
+            .about("Optimizes a sample of PNGs with each oxipng level and zopfli, and prints the time and savings \
+                    of each, to help choose --oxipng-level. The files aren't changed.")
+            .arg(Arg::with_name("PATH")
+                .help("PNG file, or folder to take the sample from")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("sample")
+                .long("sample")
+                .value_name("N")
+                .default_value("20")
+                .validator(is_positive_number)
+                .help("Number of PNGs to optimize, picked at random from the folder"))
+            .arg(Arg::with_name("no-zopfli")
+                .long("no-zopfli")
+                .help("Leave out zopfli, which is much slower than the oxipng levels")))
+        .subcommand(SubCommand::with_name("dedup")
+            .about("Finds PNGs with identical pixels, even if the files differ")
+            .arg(Arg::with_name("PATH")
+                .help("Path to folder to be searched")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("replace")
+                .long("replace")
+                .value_name("MODE")
+                .possible_values(&["hard-link", "copy"])
+                .help("Replace the duplicates with hard links to, or copies of, the smallest file of each group"))
+            .arg(Arg::with_name("wait-lock")
+                .long("wait-lock")
+                .help("If another instance is working on the folder, wait for it instead of exiting")))
+        .subcommand(SubCommand::with_name("manifest")
+            .about("Records the pixels of every PNG, to check later that optimizing them didn't change how they look")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("create")
+                .about("Writes the header and a hash of the decoded pixels of every PNG to the manifest")
+                .arg(Arg::with_name("PATH")
+                    .help("Path to folder to be recorded")
+                    .required(true)
+                    .index(1))
+                .arg(manifest_arg()))
+            .subcommand(SubCommand::with_name("verify")
+                .about("Decodes every PNG in the manifest again, and reports any whose pixels changed or which \
+                        are missing")
+                .arg(Arg::with_name("PATH")
+                    .help("Path to folder to be checked")
+                    .required(true)
+                    .index(1))
+                .arg(manifest_arg())))
+        .subcommand(SubCommand::with_name("quantize")
+            .about("Converts truecolor PNGs to palette images, when that keeps enough quality and saves space")
+            .arg(Arg::with_name("PATH")
+                .help("Path to folder to be processed")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("quality")
+                .long("quality")
+                .value_name("MIN-MAX")
+                .default_value("65-90")
+                .validator(is_quality)
+                .help("Quality range from 0 to 100, like pngquant. Images which can't reach MIN are left alone"))
+            .arg(Arg::with_name("no-dither")
+                .long("no-dither")
+                .help("Map pixels to the nearest palette color instead of dithering"))
+            .arg(Arg::with_name("wait-lock")
+                .long("wait-lock")
+                .help("If another instance is working on the folder, wait for it instead of exiting")))
+}
+
+#[cfg(not(feature = "fix"))]
+fn with_image_subcommands(app : App<'static, 'static>) -> App<'static, 'static> {
+    app
+}
+
+const ABOUT: &str = "Finds indexed PNGs and converts them to RGB/RGBA";
+
+fn cli() -> App<'static, 'static> {
+    let app = App::new("png_header_scanner")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(ABOUT)
+        .after_help(AFTER_HELP)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        // For packaging, so it's left out of --help
+        .subcommand(SubCommand::with_name("completions")
+            .about("Prints a shell completion script")
+            .setting(AppSettings::Hidden)
+            .arg(Arg::with_name("SHELL")
+                .possible_values(&Shell::variants())
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("undo")
+            .about("Restores the files changed by the last run on a folder, from the originals its journal kept. \
+                    Files which were changed again since are left alone.")
+            .arg(Arg::with_name("PATH")
+                .help("Folder the run was on")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("since")
+                .long("since")
+                .value_name("RUN_ID")
+                .help("Undo every run from this one onwards, newest first, instead of only the last run"))
+            .arg(Arg::with_name("wait-lock")
+                .long("wait-lock")
+                .help("If another instance is working on the folder, wait for it instead of exiting")))
+        .subcommand(SubCommand::with_name("hook")
+            .about("Runs as a git hook, using the options given before hook")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("pre-commit")
+                .about("Scans the staged PNGs and fixes and stages them again, or stops the commit if they can't be \
+                        fixed. With --check the commit is stopped if any need fixing. Call it from \
+                        .git/hooks/pre-commit.")))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Answers HTTP requests to analyze or fix PNGs, using the options given before serve. \
+                    POST a PNG to /analyze for its header and findings as JSON, or to /fix for the fixed PNG.")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDRESS")
+                .default_value("127.0.0.1:8080")
+                .help("Address and port to listen on. Use 0.0.0.0:PORT to accept requests from other machines."))
+            .arg(Arg::with_name("root")
+                .long("root")
+                .value_name("DIR")
+                .help("Also allow GET /analyze?path=FILE and /fix?path=FILE for files inside DIR. \
+                       The files are only read, /fix returns the fixed copy.")))
+        .arg(Arg::with_name("PATH")
+            .help("Path to folder to be processed")
+            .required_unless_one(&["generate-man", "daemon"])
+            .index(1))
+        .arg(Arg::with_name("generate-man")
+            .long("generate-man")
+            .help("Print a man page and exit"))
+        .arg(Arg::with_name("watch")
+            .long("watch")
+            .help("After scanning, keep running and fix PNGs as they are added or modified"))
+        .arg(Arg::with_name("pack")
+            .long("pack")
+            .value_name("ZIP")
+            .conflicts_with("watch")
+            .help("After scanning, write all PNGs under the folder into a reproducible zip file"))
+        .arg(Arg::with_name("check")
+            .long("check")
+            .conflicts_with_all(&["watch", "pack"])
+            .help("Only scan, and exit with an error if any indexed or invalid PNGs are found"))
+        .arg(Arg::with_name("estimate")
+            .long("estimate")
+            .conflicts_with_all(&["check", "watch", "pack", "quarantine"])
+            .help("Fix images in memory to report how much they would shrink, without changing any files"))
+        .arg(Arg::with_name("out")
+            .long("out")
+            .value_name("DIR")
+            .conflicts_with_all(&["check", "estimate", "assert-read-only", "quarantine", "watch"])
+            .help("Write fixed files into a mirrored folder, copying the unchanged ones, and leave PATH untouched"))
+        .arg(Arg::with_name("transactional")
+            .long("transactional")
+            .conflicts_with_all(&["check", "estimate", "assert-read-only", "out", "quarantine", "watch", "resume",
+                                  "daemon"])
+            .help("Hold back every change until the end of the run, and only apply them if every file fixed, \
+                   so a failure leaves PATH untouched"))
+        .arg(Arg::with_name("symlink-unchanged")
+            .long("symlink-unchanged")
+            .requires("out")
+            .help("Symlink unchanged files from the --out folder to the originals instead of copying them"))
+        .arg(Arg::with_name("convert-to")
+            .long("convert-to")
+            .value_name("FORMAT")
+            .possible_values(&["webp", "jxl"])
+            .help("Instead of fixing PNGs, write a lossless WebP or JPEG XL copy next to each one"))
+        .arg(Arg::with_name("replace-originals")
+            .long("replace-originals")
+            .requires("convert-to")
+            .help("Delete each PNG once its --convert-to copy has been written and verified"))
+        .arg(Arg::with_name("scan-archives")
+            .long("scan-archives")
+            .help("Also report the PNGs inside .zip, .pak and .pk3 files, without extracting them"))
+        .arg(Arg::with_name("fix-archives")
+            .long("fix-archives")
+            .requires("scan-archives")
+            .help("Also fix the PNGs inside archives, rewriting each archive with the fixed entries"))
+        .arg(Arg::with_name("convert-others")
+            .long("convert-others")
+            .help("Also replace BMP, TGA and TIFF images with optimized truecolor PNGs"))
+        .arg(Arg::with_name("optimizer")
+            .long("optimizer")
+            .value_name("PROGRAM")
+            .default_value("oxipng")
+            .help("Optimizer to use: the built-in oxipng, or another program like zopflipng. It must keep the color type, \
+                   e.g. zopflipng --keepcolortype"))
+        .arg(Arg::with_name("optimizer-args")
+            .long("optimizer-args")
+            .value_name("ARGS")
+            .allow_hyphen_values(true)
+            .help("Arguments for an external --optimizer. {input} and {output} are replaced with file paths, \
+                   otherwise the input and output paths are added at the end"))
+        .arg(Arg::with_name("reproducible")
+            .long("reproducible")
+            .help("Write byte for byte the same PNG for the same input on every run and machine: tIME chunks are \
+                   stripped, the other chunks are sorted and oxipng tries every setting on one thread per image. \
+                   Slower, and only works with the built-in optimizer."))
+        .arg(Arg::with_name("exec")
+            .long("exec")
+            .value_name("COMMAND")
+            .allow_hyphen_values(true)
+            .validator(is_command)
+            .conflicts_with("transactional")
+            .help("Run a command for every file which is fixed, like \"touch {path}.stamp\". {path}, {rel_path}, \
+                   {finding}, {description}, {size_before} and {size_after} are replaced in its arguments."))
+        .arg(Arg::with_name("exec-for")
+            .long("exec-for")
+            .value_name("WHEN")
+            .possible_values(&["fixed", "finding"])
+            .default_value("fixed")
+            .requires("exec")
+            .help("Run --exec for every fixed file, or for every finding whether or not it was fixed"))
+        .arg(Arg::with_name("timeout-per-file")
+            .long("timeout-per-file")
+            .value_name("TIME")
+            .validator(is_duration)
+            .help("Give up optimizing a file after this long, e.g. 60s, keeping the original. \
+                   It's reported as failed with a timeout error."))
+        .arg(Arg::with_name("oxipng-level")
+            .long("oxipng-level")
+            .value_name("LEVEL")
+            .possible_values(&["0", "1", "2", "3", "4", "5", "6"])
+            .default_value("2")
+            .help("oxipng optimization level. Higher levels try more settings and take longer."))
+        .arg(Arg::with_name("oxipng-filters")
+            .long("oxipng-filters")
+            .value_name("LIST")
+            .validator(is_filter_list)
+            .help("PNG filter strategies for oxipng to try, 0-5, e.g. 0,5 or 0-5"))
+        .arg(Arg::with_name("oxipng-zc")
+            .long("oxipng-zc")
+            .value_name("LIST")
+            .validator(is_compression_list)
+            .help("zlib compression levels for oxipng to try, 1-9"))
+        .arg(Arg::with_name("oxipng-zs")
+            .long("oxipng-zs")
+            .value_name("LIST")
+            .validator(is_strategy_list)
+            .help("zlib compression strategies for oxipng to try, 0-3"))
+        .arg(Arg::with_name("oxipng-zw")
+            .long("oxipng-zw")
+            .value_name("SIZE")
+            .possible_values(&["256", "512", "1k", "2k", "4k", "8k", "16k", "32k"])
+            .help("zlib window size for oxipng"))
+        .arg(Arg::with_name("oxipng-zopfli")
+            .long("oxipng-zopfli")
+            .conflicts_with_all(&["oxipng-zc", "oxipng-zs", "oxipng-zw"])
+            .help("Compress with zopfli instead of zlib. Usually a little smaller, but much slower."))
+        .arg(Arg::with_name("oxipng-interlace")
+            .long("oxipng-interlace")
+            .value_name("TYPE")
+            .possible_values(&["0", "1"])
+            .conflicts_with("deinterlace")
+            .help("Write optimized images non-interlaced (0) or Adam7 interlaced (1), instead of keeping \
+                   their interlacing"))
+        .arg(Arg::with_name("oxipng-alpha")
+            .long("oxipng-alpha")
+            .help("Let oxipng change the color of fully transparent pixels to compress them better. \
+                   The pixels then differ from the original, so --verify exact rejects those images."))
+        .arg(Arg::with_name("oxipng-nb")
+            .long("oxipng-nb")
+            .help("Don't let oxipng reduce the bit depth"))
+        .arg(Arg::with_name("oxipng-np")
+            .long("oxipng-np")
+            .help("Don't let oxipng reduce the palette"))
+        .arg(Arg::with_name("oxipng-nz")
+            .long("oxipng-nz")
+            .help("Don't let oxipng recompress the image data unless a reduction was made"))
+        .arg(Arg::with_name("deinterlace")
+            .long("deinterlace")
+            .help("Also rewrite non-indexed Adam7 interlaced PNGs as non-interlaced"))
+        .arg(Arg::with_name("min-savings")
+            .long("min-savings")
+            .value_name("SIZE")
+            .validator(is_min_savings)
+            .help("Only rewrite files if it saves at least this much, in bytes (e.g. 2K) or percent (e.g. 5%)"))
+        .arg(Arg::with_name("force")
+            .long("force")
+            .help("Write fixed images even if they are larger than the original, or save less than --min-savings"))
+        .arg(Arg::with_name("force-apng")
+            .long("force-apng")
+            .help("Also fix animated PNGs. Only the first frame is kept!"))
+        .arg(Arg::with_name("max-pixels")
+            .long("max-pixels")
+            .value_name("N")
+            .validator(is_size)
+            .help("Skip images with more pixels than this instead of decoding them, e.g. 100M"))
+        .arg(Arg::with_name("max-decode-mem")
+            .long("max-decode-mem")
+            .value_name("BYTES")
+            .validator(is_size)
+            .help("Skip images which would need more memory than this to decode, e.g. 2G"))
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .value_name("MODE")
+            .possible_values(&["exact", "tolerant"])
+            .default_value("exact")
+            .help("How converted images are checked against the original. tolerant allows small \
+                   per-channel differences and treats RGB and opaque RGBA as equal."))
+        .arg(Arg::with_name("verify-tolerance")
+            .long("verify-tolerance")
+            .value_name("N")
+            .default_value("0")
+            .help("Largest allowed difference per channel (0-255) with --verify tolerant"))
+        .arg(Arg::with_name("preserve-times")
+            .long("preserve-times")
+            .help("Keep the modified time of rewritten files, so build systems don't see them as changed"))
+        .arg(Arg::with_name("force-readonly")
+            .long("force-readonly")
+            .help("Rewrite read-only files too, keeping them read-only. Otherwise they're skipped."))
+        .arg(Arg::with_name("wait-lock")
+            .long("wait-lock")
+            .help("If another instance is working on the folder, wait for it instead of exiting"))
+        .arg(Arg::with_name("quarantine")
+            .long("quarantine")
+            .value_name("DIR")
+            .conflicts_with_all(&["check", "assert-read-only"])
+            .help("Move PNGs with an invalid header or truncated data into DIR, keeping their relative paths, \
+                   and list them in a manifest there"))
+        .arg(Arg::with_name("hidden")
+            .long("hidden")
+            .help("Also scan dotfiles and files in dot folders. macOS metadata like ._ files is always skipped."))
+        .arg(Arg::with_name("min-size")
+            .long("min-size")
+            .value_name("SIZE")
+            .validator(is_size)
+            .help("Skip PNGs smaller than this, like 4K, where fixing isn't worth it"))
+        .arg(Arg::with_name("max-size")
+            .long("max-size")
+            .value_name("SIZE")
+            .validator(is_size)
+            .help("Skip PNGs larger than this, like 200M, which need handling by hand"))
+        .arg(Arg::with_name("min-age")
+            .long("min-age")
+            .value_name("TIME")
+            .validator(is_duration)
+            .help("Skip PNGs modified less than this long ago, like 30s, which may still be being written. \
+                   With --watch, new files are handled once they're this old."))
+        .arg(Arg::with_name("stable-interval")
+            .long("stable-interval")
+            .value_name("TIME")
+            .validator(is_duration)
+            .help("Skip PNGs whose size or modification time changes over this long, like 2s"))
+        .arg(Arg::with_name("downconvert-16bit")
+            .long("downconvert-16bit")
+            .help("Report 16-bit PNGs, and rewrite them as 8-bit RGB/RGBA"))
+        .arg(Arg::with_name("drop-opaque-alpha")
+            .long("drop-opaque-alpha")
+            .help("Rewrite RGBA PNGs whose pixels are all opaque as RGB before optimizing. \
+                   Every RGBA PNG has to be decoded to check, so this is slower."))
+        .arg(Arg::with_name("match")
+            .long("match")
+            .value_name("CRITERION")
+            .multiple(true)
+            .use_delimiter(false)
+            .validator(is_match_criterion)
+            .help("Pixel formats to report and convert to RGB/RGBA, instead of only indexed images. \
+                   For example --match color-type=3,0 bit-depth=1,2,4"))
+        .arg(Arg::with_name("min-width")
+            .long("min-width")
+            .value_name("PIXELS")
+            .validator(is_positive_number)
+            .help("Only fix images at least this wide"))
+        .arg(Arg::with_name("min-height")
+            .long("min-height")
+            .value_name("PIXELS")
+            .validator(is_positive_number)
+            .help("Only fix images at least this tall"))
+        .arg(Arg::with_name("max-width")
+            .long("max-width")
+            .value_name("PIXELS")
+            .validator(is_positive_number)
+            .help("Only fix images at most this wide"))
+        .arg(Arg::with_name("max-height")
+            .long("max-height")
+            .value_name("PIXELS")
+            .validator(is_positive_number)
+            .help("Only fix images at most this tall"))
+        .arg(Arg::with_name("require-pot")
+            .long("require-pot")
+            .help("Report every PNG whose width or height isn't a power of two, and fail --check if there are any"))
+        .arg(Arg::with_name("require-mult")
+            .long("require-mult")
+            .value_name("PIXELS")
+            .validator(is_positive_number)
+            .help("Report every PNG whose width or height isn't a multiple of this, like 4 for block compressed \
+                   textures, and fail --check if there are any"))
+        .arg(Arg::with_name("max-dimension")
+            .long("max-dimension")
+            .value_name("PIXELS")
+            .validator(is_positive_number)
+            .help("Report every PNG wider or taller than this, like the target platform's texture size limit, \
+                   and fail --check if there are any"))
+        .arg(Arg::with_name("resize-over-limit")
+            .long("resize-over-limit")
+            .requires("max-dimension")
+            .help("Scale down PNGs over --max-dimension to fit, keeping their aspect ratio. This changes the pixels, \
+                   so unlike fixing it can't be verified."))
+        .arg(Arg::with_name("fix-gamma")
+            .long("fix-gamma")
+            .help("Replace gAMA, sRGB and iCCP chunks which contradict each other, or have bogus values, with a \
+                   single sRGB chunk, so every viewer shows the image the same"))
+        .arg(Arg::with_name("set-dpi")
+            .long("set-dpi")
+            .value_name("DPI")
+            .validator(is_dpi_setting)
+            .help("Give every PNG a pHYs chunk with this resolution, replacing any other, or remove the pHYs chunks \
+                   with --set-dpi strip"))
+        .arg(Arg::with_name("order")
+            .long("order")
+            .value_name("ORDER")
+            .possible_values(&["walk", "name", "size-desc", "size-asc", "mtime-desc", "mtime-asc"])
+            .default_value("walk")
+            .help("Order to handle files in. With size-desc, a run which is stopped early has the biggest savings."))
+        .arg(Arg::with_name("limit")
+            .long("limit")
+            .value_name("N")
+            .validator(is_positive_number)
+            .help("Stop after the first N PNGs, for a quick look at a huge folder"))
+        .arg(Arg::with_name("sample")
+            .long("sample")
+            .value_name("N")
+            .validator(is_positive_number)
+            .conflicts_with("limit")
+            .help("Only handle N randomly chosen PNGs, to estimate what a full scan would find"))
+        .arg(Arg::with_name("failed-list")
+            .long("failed-list")
+            .value_name("FILE")
+            .default_value("failed.txt")
+            .help("Where to write the list of files which failed to fix, and why"))
+        .arg(Arg::with_name("retry")
+            .long("retry")
+            .value_name("FILE")
+            .conflicts_with("resume")
+            .help("Only handle the files listed in a failed list from an earlier run, instead of the whole folder"))
+        .arg(Arg::with_name("git-changed")
+            .long("git-changed")
+            .value_name("REF")
+            .min_values(0)
+            // Otherwise PATH would be taken as the ref
+            .require_equals(true)
+            .conflicts_with_all(&["retry", "resume", "watch"])
+            .help("Only handle PNGs which are added or modified since a git commit or branch, given as \
+                   --git-changed=REF, or which differ from the index if no ref is given. Untracked PNGs count too."))
+        .arg(Arg::with_name("resume")
+            .long("resume")
+            .conflicts_with("assert-read-only")
+            .help("Continue an interrupted run, skipping the files it already handled"))
+        .arg(Arg::with_name("no-cache")
+            .long("no-cache")
+            .help("Scan every file, instead of skipping files which were clean and haven't changed since the last run"))
+        .arg(Arg::with_name("no-journal")
+            .long("no-journal")
+            .help("Don't keep copies of the files which are changed, which undo needs to restore them"))
+        .arg(Arg::with_name("clear-cache")
+            .long("clear-cache")
+            .conflicts_with("assert-read-only")
+            .help("Forget which files were clean in earlier runs"))
+        .arg(Arg::with_name("assert-read-only")
+            .long("assert-read-only")
+            .conflicts_with_all(&["watch", "pack"])
+            .help("Like --check, but also refuse any write to the scanned files. \
+                   For auditing mounted images, protected installs or archives."))
+        .arg(Arg::with_name("deep")
+            .long("deep")
+            .help("Check every chunk's CRC and the chunk order, instead of only the header"))
+        .arg(Arg::with_name("palette-report")
+            .long("palette-report")
+            .help("For every indexed PNG, print the palette size, how many entries are used, duplicate colors, \
+                   and whether it could be greyscale without any loss"))
+        .arg(Arg::with_name("truncate-trailing")
+            .long("truncate-trailing")
+            .help("Remove any data after the end of the PNG (the IEND chunk)"))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .help("TOML file with default settings, instead of the pngscanner.toml in the current folder or \
+                   above it"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .value_name("NAME")
+            .help("Apply the settings of the config file's [profile.NAME] section"))
+        .arg(Arg::with_name("policy")
+            .long("policy")
+            .value_name("FILE")
+            .help("JSON file with project rules, such as chunks which must not be shipped"))
+        .arg(Arg::with_name("sarif")
+            .long("sarif")
+            .value_name("FILE")
+            .help("Write all findings to a SARIF file, including PNGs with the wrong extension"))
+        .arg(Arg::with_name("report-bbcode")
+            .long("report-bbcode")
+            .value_name("FILE")
+            .help("Write a summary of fixed files and savings in BBCode, ready to paste into forum posts"))
+        .arg(Arg::with_name("html")
+            .long("html")
+            .value_name("FILE")
+            .help("Write an HTML report of fixed files with before/after thumbnails"))
+        .arg(Arg::with_name("jsonl")
+            .long("jsonl")
+            .help("Print a JSON object per file to stdout as soon as it's handled. Other output goes to stderr."))
+        .arg(Arg::with_name("group-by-dir")
+            .long("group-by-dir")
+            .help("After scanning, print how many files were scanned, indexed and fixed in each folder"))
+        .arg(Arg::with_name("daemon")
+            .long("daemon")
+            .value_name("SOCKET")
+            .conflicts_with_all(&["PATH", "watch", "tui", "interactive", "jsonl", "out", "retry", "resume"])
+            .help("Keep running, and take JSON-RPC 2.0 requests on the Unix socket SOCKET, one JSON object per \
+                   line. The methods are scan (params: path, fix), fix (params: path), status and shutdown. \
+                   The other options apply to every request."))
+        .arg(Arg::with_name("interactive")
+            .long("interactive")
+            .short("i")
+            .conflicts_with_all(&["check", "estimate", "assert-read-only", "jsonl", "tui", "watch"])
+            .help("Show the path, size and dimensions of each image which needs fixing, and ask whether to fix it, \
+                   skip it, fix all the rest or quit. Implies --jobs 1."))
+        .arg(Arg::with_name("tui")
+            .long("tui")
+            .conflicts_with_all(&["jsonl", "watch", "quiet", "verbose"])
+            .help("Show a live view of the files being handled, the totals so far and any errors. \
+                   Keys: p pauses, s skips the rest of the current folder, q stops like Ctrl-C."))
+        .arg(Arg::with_name("quiet")
+            .long("quiet")
+            .short("q")
+            .conflicts_with("verbose")
+            .help("Only print files which were changed, warnings and errors"))
+        .arg(Arg::with_name("verbose")
+            .long("verbose")
+            .short("v")
+            .multiple(true)
+            .help("Also print the header of every file. Given twice, also print every chunk."))
+        .arg(Arg::with_name("log-file")
+            .long("log-file")
+            .value_name("FILE")
+            .help("Append a timestamped line for every file skipped, fixed or failed to FILE, whatever \
+                   --quiet or --verbose show"))
+        .arg(Arg::with_name("log-max-size")
+            .long("log-max-size")
+            .value_name("SIZE")
+            .requires("log-file")
+            .validator(is_size)
+            .help("Start a new --log-file once it reaches SIZE, e.g. 10M. The last 5 are kept as FILE.1 to FILE.5."))
+        .arg(Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .value_name("N")
+            .default_value("1")
+            .help("Number of files to process in parallel. 0 uses one thread per CPU."))
+        .arg(Arg::with_name("fix-jobs")
+            .long("fix-jobs")
+            .value_name("N")
+            .validator(is_positive_number)
+            .conflicts_with_all(&["check", "assert-read-only", "interactive"])
+            .help("Decode and optimize images on a separate pool of N threads, while the --jobs threads carry on \
+                   scanning. Scanning pauses while too many images are waiting to be fixed."))
+        .arg(Arg::with_name("io-concurrency")
+            .long("io-concurrency")
+            .value_name("N")
+            .validator(is_positive_number)
+            .help("Maximum number of files being read or written at once, regardless of --jobs. \
+                   Useful on network shares which throttle many simultaneous requests."))
+        .arg(Arg::with_name("io-uring")
+            .long("io-uring")
+            .help("Read the headers of many files at once through io_uring, which is much faster for millions \
+                   of files. Linux only, and only with --check or --assert-read-only. --io-concurrency sets how \
+                   many reads are in flight."));
+    with_image_subcommands(app)
+}
+
+#[cfg(unix)]
+fn run_daemon(socket_path : &Path,
+              options : ScanOptions,
+              thread_pool : &rayon::ThreadPool,
+              matches : &clap::ArgMatches) {
+    let use_cache = !matches.is_present("no-cache");
+    // The caches are written into the scanned folders
+    let save_caches = !matches.is_present("assert-read-only");
+    if let Err(e) = daemon::run(socket_path, options, thread_pool, use_cache, save_caches) {
+        error!("Error: the daemon stopped: {}", e);
+        std::process::exit(EXIT_ERRORS);
+    }
+}
+
+#[cfg(not(unix))]
+fn run_daemon(_socket_path : &Path,
+              _options : ScanOptions,
+              _thread_pool : &rayon::ThreadPool,
+              _matches : &clap::ArgMatches) {
+    eprintln!("--daemon needs Unix domain sockets, which aren't supported on this platform");
+    std::process::exit(EXIT_USAGE);
+}
+
+// manifest create and manifest verify
+#[cfg(feature = "fix")]
+fn run_manifest(manifest_matches : &clap::ArgMatches) {
+    let (command, command_matches) = match manifest_matches.subcommand() {
+        (command, Some(command_matches)) => (command, command_matches),
+        // Required by clap
+        _ => unreachable!(),
+    };
+    let scan_path = Path::new(command_matches.value_of_os("PATH").unwrap());
+    let manifest_path = match command_matches.value_of_os("manifest") {
+        Some(manifest_path) => PathBuf::from(manifest_path),
+        None => scan_path.join(manifest::MANIFEST_FILE_NAME),
+    };
+
+    if command == "create" {
+        if let Err(e) = manifest::create(scan_path, &manifest_path) {
+            error!("Error: can't write the manifest [{}]: {}", manifest_path.display(), e);
+            std::process::exit(EXIT_ERRORS);
+        }
+        return;
+    }
+    match manifest::verify(scan_path, &manifest_path) {
+        Ok(true) => {},
+        Ok(false) => std::process::exit(EXIT_VERIFICATION_MISMATCH),
+        Err(e) => {
+            error!("Error: can't read the manifest [{}]: {}", manifest_path.display(), e);
+            std::process::exit(EXIT_ERRORS);
+        },
+    }
+}
+
+// clap itself exits with 1 for invalid arguments, which would look like files were fixed
+fn exit_usage_error(e : clap::Error) -> ! {
+    if e.use_stderr() {
+        eprintln!("{}", e.message);
+        std::process::exit(EXIT_USAGE);
+    }
+    // --help and --version
+    e.exit()
+}
+
+fn parse_args(args : Vec<OsString>) -> clap::ArgMatches<'static> {
+    cli().get_matches_from_safe(args).unwrap_or_else(|e| exit_usage_error(e))
+}
+
+// Parse the arguments again with the settings from the config file added, returning the config
+// file's path if there was one. Subcommands have their own arguments, so it only applies to scans.
+fn apply_config(args : Vec<OsString>,
+                matches : clap::ArgMatches<'static>) -> (clap::ArgMatches<'static>, Option<PathBuf>) {
+    if matches.subcommand_name().is_some() {
+        return (matches, None);
+    }
+
+    let config_path = match matches.value_of_os("config") {
+        Some(config_path) => PathBuf::from(config_path),
+        None => match std::env::current_dir().ok().and_then(|dir| config::find_config(&dir)) {
+            Some(config_path) => config_path,
+            None if matches.is_present("profile") => {
+                eprintln!("--profile needs a {} file, or --config", config::CONFIG_FILE_NAME);
+                std::process::exit(EXIT_USAGE);
+            },
+            None => return (matches, None),
+        },
+    };
+
+    let config_args = config::load_config(&config_path,
+                                          matches.value_of("profile"),
+                                          |name| matches.occurrences_of(name) > 0)
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid config file [{}]: {}", config_path.display(), e);
+            std::process::exit(EXIT_USAGE);
+        });
+    (parse_args(args.into_iter().chain(config_args).collect()), Some(config_path))
+}
+
+fn main() {
+    let args : Vec<OsString> = std::env::args_os().collect();
+    let (matches, config_path) = apply_config(args.clone(), parse_args(args));
+    let log_file = matches.value_of_os("log-file").map(|log_path| {
+        // Already checked by the validator
+        let max_size = matches.value_of("log-max-size").and_then(parse_size);
+        logger::LogFile::open(Path::new(log_path), max_size).unwrap_or_else(|e| {
+            eprintln!("Can't open the log file [{}]: {}", Path::new(log_path).display(), e);
+            std::process::exit(EXIT_USAGE);
+        })
+    });
+    logger::init(matches.is_present("quiet"), matches.occurrences_of("verbose"), log_file);
+
+    if let Some(completions_matches) = matches.subcommand_matches("completions") {
+        let shell = value_t!(completions_matches, "SHELL", Shell).unwrap_or_else(|e| exit_usage_error(e));
+        cli().gen_completions_to("png_header_scanner", shell, &mut std::io::stdout());
+        return;
+    }
+
+    if matches.is_present("generate-man") {
+        man::write_man_page(&mut cli(), ABOUT, &mut std::io::stdout()).expect("Failed to write the man page");
+        return;
+    }
+
+    #[cfg(feature = "fix")]
+    if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        let bench_options = bench::BenchOptions {
+            sample: value_t!(bench_matches, "sample", usize).unwrap_or_else(|e| exit_usage_error(e)),
+            zopfli: !bench_matches.is_present("no-zopfli"),
+        };
+        bench::bench(Path::new(bench_matches.value_of_os("PATH").unwrap()), &bench_options);
+        return;
+    }
+
+    #[cfg(feature = "fix")]
+    if let Some(dedup_matches) = matches.subcommand_matches("dedup") {
+        let replace = match dedup_matches.value_of("replace") {
+            Some("hard-link") => dedup::Replace::HardLink,
+            Some("copy") => dedup::Replace::Copy,
+            _ => dedup::Replace::ReportOnly,
+        };
+        let dedup_path = Path::new(dedup_matches.value_of_os("PATH").unwrap());
+        let _scan_lock = if replace == dedup::Replace::ReportOnly {
+            None
+        } else {
+            Some(lock_folder_or_exit(dedup_path, dedup_matches.is_present("wait-lock")))
+        };
+        dedup::dedup(dedup_path, replace);
+        return;
+    }
+
+    #[cfg(feature = "fix")]
+    if let Some(manifest_matches) = matches.subcommand_matches("manifest") {
+        run_manifest(manifest_matches);
+        return;
+    }
+
+    #[cfg(feature = "fix")]
+    if let Some(quantize_matches) = matches.subcommand_matches("quantize") {
+        let (min_quality, max_quality) = parse_quality(quantize_matches.value_of("quality").unwrap()).unwrap();
+        let quantize_options = quantize::QuantizeOptions {
+            min_quality,
+            max_quality,
+            dither: !quantize_matches.is_present("no-dither"),
+        };
+        let quantize_path = Path::new(quantize_matches.value_of_os("PATH").unwrap());
+        let _scan_lock = lock_folder_or_exit(quantize_path, quantize_matches.is_present("wait-lock"));
+        quantize::quantize(quantize_path, &quantize_options);
+        return;
+    }
+
+    if let Some(undo_matches) = matches.subcommand_matches("undo") {
+        let undo_path = Path::new(undo_matches.value_of_os("PATH").unwrap());
+        let _scan_lock = lock_folder_or_exit(undo_path, undo_matches.is_present("wait-lock"));
+        match journal::undo(undo_path, undo_matches.value_of("since")) {
+            Ok(num_restored) => info!("Restored {} files.", num_restored),
+            Err(e) => {
+                error!("Error: can't undo the changes to [{}]: {}", undo_path.display(), e);
+                std::process::exit(EXIT_ERRORS);
+            },
+        }
+        return;
+    }
+
+    // Left empty with --daemon, serve and hook, which get their paths from requests or git
+    let scan_path = Path::new(matches.value_of_os("PATH").unwrap_or_default());
+
+    // Other files' messages would be mixed in with the questions
+    let jobs = if matches.is_present("interactive") {
+        1
+    } else {
+        value_t!(matches, "jobs", usize).unwrap_or_else(|e| exit_usage_error(e))
+    };
+    let io_concurrency = if matches.is_present("io-concurrency") {
+        Some(value_t!(matches, "io-concurrency", usize).unwrap_or_else(|e| exit_usage_error(e)))
+    } else {
+        None
+    };
+
+    let assert_read_only = matches.is_present("assert-read-only");
+    // Headers read ahead could be out of date by the time a file is fixed
+    if matches.is_present("io-uring") && !matches.is_present("check") && !assert_read_only && cfg!(feature = "fix") {
+        eprintln!("--io-uring only works with --check or --assert-read-only");
+        std::process::exit(EXIT_USAGE);
+    }
+    // Another program's output can't be pinned down
+    if matches.is_present("reproducible") && matches.value_of("optimizer") != Some("oxipng") {
+        eprintln!("--reproducible only works with the built-in oxipng --optimizer");
+        std::process::exit(EXIT_USAGE);
+    }
+
+    let out_path = matches.value_of_os("out").map(|out_path| {
+        let out_path = Path::new(out_path);
+        fs::create_dir_all(out_path).expect("Failed to create the output folder");
+        // The output would be scanned again as part of the folder
+        let canonical_scan_path = fs::canonicalize(scan_path).expect("Can't resolve folder to scan");
+        let canonical_out_path = fs::canonicalize(out_path).expect("Can't resolve the output folder");
+        if canonical_out_path.starts_with(&canonical_scan_path) {
+            eprintln!("The --out folder can't be inside [{}]", scan_path.display());
+            std::process::exit(EXIT_USAGE);
+        }
+        out_path.to_path_buf()
+    });
+    // Where the lock, cache and checkpoint go. The scanned folder isn't written at all with --out.
+    let state_path = out_path.as_deref().unwrap_or(scan_path);
+
+    let policy = match matches.value_of_os("policy") {
+        Some(policy_path) => policy::load_policy(Path::new(policy_path)).unwrap_or_else(|e| {
+            eprintln!("Invalid policy file: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }),
+        None => Policy::default(),
+    };
+
+    let options = ScanOptions {
+        // Read-only mode never fixes anything, so it's the same as --check. So is a scan-only
+        // build without the fix feature.
+        check_only: matches.is_present("check") || assert_read_only || !cfg!(feature = "fix"),
+        estimate: matches.is_present("estimate"),
+        find_misnamed_pngs: matches.is_present("sarif"),
+        json_lines: matches.is_present("jsonl"),
+        deep: matches.is_present("deep"),
+        palette_report: matches.is_present("palette-report"),
+        truncate_trailing: matches.is_present("truncate-trailing"),
+        io_limiter: {
+            let io_limiter = IoLimiter::new(io_concurrency, assert_read_only, WriteOptions {
+                preserve_times: matches.is_present("preserve-times"),
+                force_readonly: matches.is_present("force-readonly"),
+            });
+            let io_limiter = match &out_path {
+                Some(out_path) => io_limiter.with_output_tree(
+                    output_tree::OutputTree::new(scan_path, out_path, matches.is_present("symlink-unchanged"))),
+                // The daemon, serve and the git hook have no folder of their own to keep a journal in
+                None if matches.is_present("no-journal") || matches.is_present("daemon") ||
+                    matches.subcommand_matches("serve").is_some() || matches.subcommand_matches("hook").is_some() => {
+                    io_limiter
+                },
+                None => io_limiter.with_journal(journal::Journal::new(scan_path)),
+            };
+            if matches.is_present("transactional") {
+                io_limiter.with_staging(staging::Staging::new(scan_path).expect("Failed to clear the staging folder"))
+            } else {
+                io_limiter
+            }
+        },
+        fix_options: FixOptions {
+            thumbnails: matches.is_present("html"),
+            deinterlace: matches.is_present("deinterlace"),
+            force_apng: matches.is_present("force-apng"),
+            min_savings: matches.value_of("min-savings").and_then(parse_min_savings),
+            force: matches.is_present("force"),
+            // Set for each image which --match selects
+            convert_any: false,
+            downconvert_16bit: matches.is_present("downconvert-16bit"),
+            drop_opaque_alpha: matches.is_present("drop-opaque-alpha"),
+            optimizer: match matches.value_of("optimizer") {
+                Some("oxipng") | None => oxipng_optimizer(&matches),
+                Some(program) => Some(Arc::new(ExternalOptimizer {
+                    program: program.to_string(),
+                    args: matches.value_of("optimizer-args")
+                        .map(|args| args.split_whitespace().map(String::from).collect())
+                        .unwrap_or_default(),
+                })),
+            },
+            timeout: matches.value_of("timeout-per-file").and_then(parse_duration),
+            // Already checked by the validators
+            max_pixels: matches.value_of("max-pixels").and_then(parse_size),
+            max_decode_mem: matches.value_of("max-decode-mem").and_then(parse_size),
+            verify: match matches.value_of("verify") {
+                Some("tolerant") => VerifyMode::Tolerant {
+                    tolerance: value_t!(matches, "verify-tolerance", u8).unwrap_or_else(|e| exit_usage_error(e)),
+                },
+                _ => VerifyMode::Exact,
+            },
+            reproducible: matches.is_present("reproducible"),
+        },
+        policy,
+        order: match matches.value_of("order") {
+            Some("name") => FileOrder::Name,
+            Some("size-desc") => FileOrder::SizeDesc,
+            Some("size-asc") => FileOrder::SizeAsc,
+            Some("mtime-desc") => FileOrder::ModifiedDesc,
+            Some("mtime-asc") => FileOrder::ModifiedAsc,
+            _ => FileOrder::Walk,
+        },
+        // Already checked by the validator
+        pixel_format_match: match matches.values_of("match") {
+            Some(criteria) => parse_pixel_format_match(criteria).unwrap(),
+            None => PixelFormatMatch::default(),
+        },
+        dimensions: DimensionFilter {
+            min_width: optional_value(&matches, "min-width"),
+            min_height: optional_value(&matches, "min-height"),
+            max_width: optional_value(&matches, "max-width"),
+            max_height: optional_value(&matches, "max-height"),
+        },
+        dimension_policy: DimensionPolicy {
+            power_of_two: matches.is_present("require-pot"),
+            multiple: optional_value(&matches, "require-mult"),
+        },
+        max_dimension: optional_value(&matches, "max-dimension"),
+        resize_over_limit: matches.is_present("resize-over-limit"),
+        // Already checked by the validator
+        set_dpi: matches.value_of("set-dpi").and_then(parse_dpi_setting),
+        fix_gamma: matches.is_present("fix-gamma"),
+        // Already checked by the validators
+        min_size: matches.value_of("min-size").and_then(parse_size),
+        max_size: matches.value_of("max-size").and_then(parse_size),
+        include_hidden: matches.is_present("hidden"),
+        min_age: matches.value_of("min-age").and_then(parse_duration),
+        stable_interval: matches.value_of("stable-interval").and_then(parse_duration),
+        limit: optional_value(&matches, "limit"),
+        sample: optional_value(&matches, "sample"),
+        convert_to: match matches.value_of("convert-to") {
+            Some("webp") => Some(ExportFormat::WebP),
+            Some("jxl") => Some(ExportFormat::Jxl),
+            _ => None,
+        },
+        replace_originals: matches.is_present("replace-originals"),
+        convert_others: matches.is_present("convert-others"),
+        scan_archives: matches.is_present("scan-archives"),
+        fix_archives: matches.is_present("fix-archives"),
+        exit_on_mismatch: !matches.is_present("daemon"),
+        prompt: if matches.is_present("interactive") { Some(interactive::Prompt::default()) } else { None },
+        exec: matches.value_of("exec").and_then(|command| {
+            let exec_for = match matches.value_of("exec-for") {
+                Some("finding") => exec::ExecFor::Finding,
+                _ => exec::ExecFor::Fixed,
+            };
+            exec::ExecCommand::parse(command, exec_for)
+        }),
+        header_prefetch: if matches.is_present("io-uring") {
+            match uring::HeaderPrefetch::new(io_concurrency.unwrap_or(uring::DEFAULT_QUEUE_DEPTH)) {
+                Ok(header_prefetch) => Some(header_prefetch),
+                Err(e) => {
+                    warn!("Warning: can't use io_uring, reading headers normally: {}", e);
+                    None
+                },
+            }
+        } else {
+            None
+        },
+        fix_pool: if matches.is_present("fix-jobs") {
+            let fix_jobs = value_t!(matches, "fix-jobs", usize).unwrap_or_else(|e| exit_usage_error(e));
+            Some(rayon::ThreadPoolBuilder::new()
+                .num_threads(fix_jobs)
+                .build()
+                .expect("Failed to create fix threads"))
+        } else {
+            None
+        },
+        retry_files: matches.value_of_os("retry").map(|list_path| {
+            failed_list::read_failed_list(Path::new(list_path)).expect("Failed to read the list of files to retry")
+        }),
+        git_changed_files: if matches.is_present("git-changed") {
+            let git_changed_files = git::changed_pngs(scan_path, matches.value_of("git-changed")).unwrap_or_else(|e| {
+                eprintln!("Can't list the changed files with git: {}", e);
+                std::process::exit(EXIT_USAGE);
+            });
+            info!("Only handling the {} PNGs changed according to git", git_changed_files.len());
+            Some(git_changed_files)
+        } else {
+            None
+        },
+    };
+    STATUS_TO_STDERR.store(options.json_lines, Ordering::Relaxed);
+    if let Some(config_path) = &config_path {
+        info!("Using settings from [{}]", config_path.display());
+    }
+
+    ctrlc::set_handler(interrupt).expect("Failed to set Ctrl-C handler");
+
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .expect("Failed to create worker threads");
+
+    if let Some(socket_path) = matches.value_of_os("daemon") {
+        run_daemon(Path::new(socket_path), options, &thread_pool, &matches);
+        return;
+    }
+
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        let serve_options = serve::ServeOptions {
+            listen: serve_matches.value_of("listen").unwrap().to_string(),
+            root: serve_matches.value_of_os("root").map(PathBuf::from),
+        };
+        if let Err(e) = serve::serve(serve_options, options, &thread_pool) {
+            error!("Error: the server stopped: {}", e);
+            std::process::exit(EXIT_ERRORS);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("hook").is_some() {
+        // pre-commit is the only hook
+        std::process::exit(hook::pre_commit(&options));
+    }
+
+    info!("Scanning [{}]", scan_path.display());
+
+    // Read-only mode can't conflict with anything, and mustn't create the lock file
+    let _scan_lock = if assert_read_only {
+        None
+    } else {
+        Some(lock_folder_or_exit(state_path, matches.is_present("wait-lock")))
+    };
+
+    if matches.is_present("clear-cache") {
+        Cache::clear(state_path).expect("Failed to delete the cache");
+    }
+    // Retried files failed last time, so they're not in the cache anyway
+    let mut cache = if matches.is_present("no-cache") || options.retry_files.is_some() {
+        None
+    } else {
+        Some(Cache::load(state_path, cache_settings(&options)))
+    };
+
+    // Read-only mode can't write a checkpoint into the scanned folder. Files done by a
+    // --transactional run which is interrupted aren't changed, so they mustn't be skipped later.
+    let checkpoint = if assert_read_only || options.io_limiter.is_staging() {
+        None
+    } else {
+        Some(Checkpoint::start(state_path, matches.is_present("resume")).expect("Failed to create checkpoint file"))
+    };
+    if let Some(checkpoint) = checkpoint.as_ref().filter(|checkpoint| checkpoint.is_resumed()) {
+        info!("Resuming, skipping {} files which were already done", checkpoint.num_done());
+    }
+
+    let tui = if matches.is_present("tui") {
+        if !std::io::stdout().is_terminal() {
+            eprintln!("--tui needs a terminal");
+            std::process::exit(EXIT_USAGE);
+        }
+        Some(tui::start().expect("Failed to start the interface"))
+    } else {
+        None
+    };
+    let summary = thread_pool.install(|| scan_folder(scan_path, &options, cache.as_mut(), checkpoint.as_ref()));
+
+    if let Some(tui) = tui {
+        tui.finish();
+    }
+
+    // Nothing was changed unless every file fixed
+    let discarded = options.io_limiter.is_staging() && (summary.any_failed() || is_interrupted());
+    if discarded {
+        options.io_limiter.discard_staged().expect("Failed to remove the staged files");
+        warn!("Warning: not changing any files, since not every file could be fixed");
+    } else if options.io_limiter.is_staging() {
+        let num_changed = options.io_limiter.commit_staged().expect("Failed to apply the staged changes");
+        info!("Applied the changes to {} files", num_changed);
+    }
+
+    // Kept after an interrupt, so the run can be resumed
+    if let Some(checkpoint) = checkpoint.filter(|_| !is_interrupted()) {
+        checkpoint.finish().expect("Failed to remove checkpoint file");
+    }
+
+    // The cache file is written into the scanned folder, which read-only mode must not touch
+    if let Some(cache) = &cache {
+        if !assert_read_only {
+            cache.save().expect("Failed to write the cache");
+        }
+    }
+
+    if let Some(quarantine_path) = matches.value_of_os("quarantine") {
+        let quarantine_path = Path::new(quarantine_path);
+        let num_moved = quarantine::quarantine(scan_path, quarantine_path, &summary.findings, &options.io_limiter)
+            .expect("Failed to quarantine files");
+        if num_moved > 0 {
+            changed!("Moved {} broken files to [{}]", num_moved, quarantine_path.display());
+        }
+    }
+
+    // An old list is overwritten even if nothing failed, so it isn't retried again
+    let failed_list_path = Path::new(matches.value_of_os("failed-list").unwrap());
+    if !assert_read_only && (summary.any_failed() || failed_list_path.exists()) {
+        let num_failed = failed_list::write_failed_list(failed_list_path, &summary.findings)
+            .expect("Failed to write the list of failed files");
+        if num_failed > 0 {
+            info!("{} files failed to fix, see [{}]. Use --retry to try them again.",
+                  num_failed, failed_list_path.display());
+        }
+    }
+
+    stats::print_statistics(&summary);
+
+    if matches.is_present("group-by-dir") {
+        group::print_directory_summary(&summary);
+    }
+
+    if let Some(sarif_path) = matches.value_of_os("sarif") {
+        sarif::write_sarif(Path::new(sarif_path), &summary.findings).expect("Failed to write SARIF file");
+    }
+
+    if let Some(html_path) = matches.value_of_os("html") {
+        html::write_report(Path::new(html_path), &summary).expect("Failed to write HTML report");
+    }
+
+    if let Some(bbcode_path) = matches.value_of_os("report-bbcode") {
+        bbcode::write_report(Path::new(bbcode_path), &summary).expect("Failed to write BBCode report");
+    }
+
+    if is_interrupted() {
+        info!("Stopped early after fixing {} files. Run again with --resume to handle the rest.",
+              summary.num_fixed());
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+
+    if options.check_only {
+        let disallowed = summary.disallowed();
+        if disallowed.is_empty() {
+            info!("No indexed or invalid PNGs found.");
+        } else {
+            info!("Found {} indexed or invalid PNGs:", disallowed.len());
+            for finding in disallowed {
+                info!("{}", finding.rel_path.display());
+            }
+        }
+        std::process::exit(summary.exit_code(true));
+    }
+
+    if options.estimate {
+        let total = summary.total_size_change();
+        info!("Estimate: fixing {} files would change their size by {:+.1}KB ({} to {} bytes).",
+              summary.num_fixed(), -total.saved() as f32 / 1000f32, total.before, total.after);
+        info!("No files were changed.");
+        std::process::exit(summary.exit_code(false));
+    }
+
+    if discarded {
+        info!("No files were changed.");
+    } else {
+        info!("Fixed {} files.", summary.num_fixed());
+    }
+    if let Some(journal) = options.io_limiter.journal().filter(|journal| !journal.is_empty()) {
+        info!("The changes were recorded as run {}. Use undo [{}] to restore the originals.",
+              journal.run_id(), scan_path.display());
+    }
+
+    if let Some(pack_path) = matches.value_of_os("pack") {
+        let pack_path = Path::new(pack_path);
+        // Only a successful run is packed, not a folder with files left unfixed
+        if discarded || summary.any_failed() {
+            warn!("Warning: not packing [{}], since not every file could be fixed", pack_path.display());
+        } else {
+            let num_packed = pack::pack(state_path, pack_path, options.include_hidden)
+                .expect("Failed to write zip file");
+            info!("Packed {} files into [{}]", num_packed, pack_path.display());
+        }
+    }
+
+    if matches.is_present("watch") {
+        // Watcher events use absolute paths
+        let watch_path = fs::canonicalize(scan_path).expect("Can't resolve folder to watch");
+        watch::watch(&watch_path, |path| {
+            let rel_path = path.strip_prefix(&watch_path).unwrap_or(path);
+            let skipped = rel_path.components()
+                .any(|component| is_skipped_name(component.as_os_str(), options.include_hidden));
+            if path.is_file() && is_png(path) && !skipped {
+                if !wait_until_settled(path, &options) {
+                    info!("Skipping {}: it's still being written", rel_path.display());
+                    return;
+                }
+                let result = handle_one_file(path, rel_path, &options);
+                exec::run_for_result(path, rel_path, &result, &options);
+                if options.json_lines {
+                    jsonl::print_file_result(rel_path, &result);
+                }
+            }
+        });
     }
 
-    println!("Fixed {} files.", num_fixed);
+    std::process::exit(summary.exit_code(false));
 }
@@ -0,0 +1,31 @@
+use clap::App;
+use std::io::{self, Write};
+
+// Characters roff would otherwise treat as commands
+fn escape_roff(line : &str) -> String {
+    let line = line.replace('\\', "\\\\").replace('-', "\\-");
+    if line.starts_with('.') || line.starts_with('\'') {
+        format!("\\&{}", line)
+    } else {
+        line
+    }
+}
+
+// Write a man page for --generate-man. clap can't produce one itself, so it's the --help text,
+// kept as it's laid out there.
+pub fn write_man_page<W: Write>(app : &mut App, about : &str, out : &mut W) -> io::Result<()> {
+    let mut help = Vec::new();
+    app.write_long_help(&mut help).map_err(|e| io::Error::other(e.to_string()))?;
+
+    let name = env!("CARGO_PKG_NAME");
+    writeln!(out, ".TH {} 1 \"\" \"{} {}\"", name.to_uppercase(), name, env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(out, "{} \\- {}", escape_roff(name), escape_roff(about))?;
+    writeln!(out, ".SH DESCRIPTION")?;
+    // No filling, so the columns of options stay lined up
+    writeln!(out, ".nf")?;
+    for line in String::from_utf8_lossy(&help).lines() {
+        writeln!(out, "{}", escape_roff(line))?;
+    }
+    writeln!(out, ".fi")
+}
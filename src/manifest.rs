@@ -0,0 +1,164 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+use walkdir::WalkDir;
+use log::{error, info, warn};
+use png_header_scanner::{parse_header, ParseResult, PngHeader};
+
+// Written to the root of the folder unless --manifest is given
+pub const MANIFEST_FILE_NAME: &str = "pngscanner-manifest.json";
+
+struct ManifestEntry {
+    header: PngHeader,
+    // SHA-256 of the dimensions and the pixels as RGBA, see pixel_hash
+    pixel_hash: String,
+}
+
+// Every value of a pixel as RGBA, in the image's own bit depth, so converting an indexed image to
+// RGBA or dropping an opaque alpha channel keeps the same hash
+fn rgba_samples(pixels : &[u8], channels : usize, sample_size : usize) -> Vec<u8> {
+    let opaque = vec![0xFF; sample_size];
+    let mut rgba = Vec::with_capacity(pixels.len() / channels * 4);
+    for pixel in pixels.chunks_exact(channels * sample_size) {
+        if channels <= 2 {
+            let grey = &pixel[..sample_size];
+            rgba.extend_from_slice(grey);
+            rgba.extend_from_slice(grey);
+            rgba.extend_from_slice(grey);
+        } else {
+            rgba.extend_from_slice(&pixel[..3 * sample_size]);
+        }
+        // Greyscale with alpha and RGBA end with the alpha channel
+        if matches!(channels, 2 | 4) {
+            rgba.extend_from_slice(&pixel[(channels - 1) * sample_size..]);
+        } else {
+            rgba.extend_from_slice(&opaque);
+        }
+    }
+    rgba
+}
+
+// Hash of the decoded pixels. Palettes, tRNS chunks and bit depths below 8 are expanded first, so
+// only a change to what the image looks like changes the hash.
+fn pixel_hash(data : &[u8]) -> Result<String, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut pixels).map_err(|e| e.to_string())?;
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        // Expanded to RGB/RGBA by the decoder
+        png::ColorType::Indexed => return Err(String::from("the palette wasn't expanded")),
+    };
+    let sample_size = if info.bit_depth == png::BitDepth::Sixteen { 2 } else { 1 };
+
+    let mut hasher = Sha256::new();
+    hasher.update(info.width.to_be_bytes());
+    hasher.update(info.height.to_be_bytes());
+    hasher.update([sample_size as u8]);
+    hasher.update(rgba_samples(&pixels, channels, sample_size));
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn read_entry(path : &Path) -> Result<ManifestEntry, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let header = match parse_header(&data) {
+        ParseResult::Valid(header) => header,
+        error_parse_result => return Err(format!("invalid PNG ({:?})", error_parse_result)),
+    };
+    Ok(ManifestEntry { header, pixel_hash: pixel_hash(&data)? })
+}
+
+fn entry_to_json(entry : &ManifestEntry) -> Value {
+    json!({
+        "width": entry.header.width,
+        "height": entry.header.height,
+        "bit_depth": entry.header.bit_depth,
+        "pixel_format": format!("{:?}", entry.header.pixel_format),
+        "interlaced": entry.header.interlaced,
+        "pixels": entry.pixel_hash,
+    })
+}
+
+// Relative paths of the PNGs under scan_path, sorted
+fn png_paths(scan_path : &Path) -> Vec<PathBuf> {
+    let mut rel_paths : Vec<PathBuf> = WalkDir::new(scan_path).into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file() && crate::is_png(entry.path()))
+        .map(|entry| entry.path().strip_prefix(scan_path).unwrap().to_path_buf())
+        .collect();
+    rel_paths.sort();
+    rel_paths
+}
+
+// Write the header and pixel hash of every PNG under scan_path to manifest_path. PNGs which can't
+// be decoded are left out. Returns the number of PNGs in the manifest.
+pub fn create(scan_path : &Path, manifest_path : &Path) -> io::Result<usize> {
+    let entries : Vec<(PathBuf, ManifestEntry)> = png_paths(scan_path).into_par_iter()
+        .filter_map(|rel_path| match read_entry(&scan_path.join(&rel_path)) {
+            Ok(entry) => Some((rel_path, entry)),
+            Err(e) => {
+                warn!("Warning: leaving {} out of the manifest: {}", rel_path.display(), e);
+                None
+            },
+        })
+        .collect();
+
+    let files : serde_json::Map<String, Value> = entries.iter()
+        .map(|(rel_path, entry)| (crate::slash_path(rel_path), entry_to_json(entry)))
+        .collect();
+    // Pretty, so the manifest can be kept in version control and diffed
+    serde_json::to_writer_pretty(File::create(manifest_path)?, &json!({ "files": files }))?;
+    info!("Wrote the pixel hashes of {} PNGs to [{}]", entries.len(), manifest_path.display());
+    Ok(entries.len())
+}
+
+// Decode every PNG in the manifest again and compare its pixels with the hash. Returns false if
+// any of them changed, are missing or can't be decoded any more.
+pub fn verify(scan_path : &Path, manifest_path : &Path) -> io::Result<bool> {
+    let manifest : Value = serde_json::from_reader(File::open(manifest_path)?)?;
+    let files = manifest.get("files")
+        .and_then(|files| files.as_object())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the manifest has no files"))?;
+
+    let problems : Vec<String> = files.iter()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .filter_map(|(rel_path, expected)| {
+            let path = scan_path.join(rel_path);
+            if !path.is_file() {
+                return Some(format!("{} is missing", rel_path));
+            }
+            let expected_hash = expected.get("pixels").and_then(|hash| hash.as_str()).unwrap_or_default();
+            match read_entry(&path) {
+                Ok(entry) if entry.pixel_hash == expected_hash => None,
+                Ok(_entry) => Some(format!("{} has different pixels", rel_path)),
+                Err(e) => Some(format!("{} can't be checked: {}", rel_path, e)),
+            }
+        })
+        .collect();
+    for problem in &problems {
+        error!("Error: {}", problem);
+    }
+
+    let in_manifest : HashSet<&str> = files.keys().map(|rel_path| rel_path.as_str()).collect();
+    let num_new = png_paths(scan_path).iter()
+        .filter(|rel_path| !in_manifest.contains(crate::slash_path(rel_path).as_str()))
+        .count();
+    if num_new > 0 {
+        info!("{} PNGs aren't in the manifest, so they weren't checked", num_new);
+    }
+
+    info!("Checked {} PNGs: {} unchanged, {} changed or missing",
+          files.len(), files.len() - problems.len(), problems.len());
+    Ok(problems.is_empty())
+}
@@ -0,0 +1,252 @@
+#[cfg(feature = "fix")]
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::fix::{FixError, FixResult};
+
+// What the optimizer is asked to do besides recompressing the image
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OptimizeSettings {
+    // Write the image non-interlaced
+    pub deinterlace: bool,
+    // Remove chunks which don't affect how the image looks
+    pub strip_metadata: bool,
+    // Give up once optimizing takes longer than this, see FixOptions::timeout
+    pub timeout: Option<Duration>,
+    // Give the same output for the same input every time. oxipng then tries every setting on one
+    // thread, instead of picking from whichever trials finished before the timeout.
+    pub reproducible: bool,
+}
+
+// Makes a PNG smaller without changing its pixels. Converted images are verified against the
+// original afterwards, so an optimizer must keep the color type it was given.
+pub trait Optimizer: fmt::Debug + Send + Sync {
+    fn optimize(&self, data : &[u8], settings : &OptimizeSettings) -> FixResult<Vec<u8>>;
+}
+
+// The built-in optimizer, with settings matching oxipng's own flags. Color type reduction is
+// always off, since it would turn converted images back into palettes.
+#[cfg(feature = "fix")]
+#[derive(Debug, Clone)]
+pub struct Oxipng {
+    // oxipng's optimization level, 0-6. The other settings are applied on top of it.
+    pub level: u8,
+    // Filter strategies to try, 0-5. None uses the level's.
+    pub filters: Option<HashSet<u8>>,
+    // zlib compression levels (1-9) and strategies (0-3) to try. None uses the level's.
+    pub compression: Option<HashSet<u8>>,
+    pub strategies: Option<HashSet<u8>>,
+    // zlib window size in bits, 8-15
+    pub window: Option<u8>,
+    // Compress with zopfli instead of zlib. Much slower, and the zlib settings don't apply.
+    pub zopfli: bool,
+    // 0 writes the image non-interlaced and 1 interlaced. None keeps its current interlacing.
+    pub interlace: Option<u8>,
+    // Try changing the color of fully transparent pixels so they compress better
+    pub alpha: bool,
+    // The reductions oxipng tries by default, which can be turned off
+    pub bit_depth_reduction: bool,
+    pub palette_reduction: bool,
+    // Recompress the image data even if no reduction applied
+    pub idat_recoding: bool,
+}
+
+#[cfg(feature = "fix")]
+impl Default for Oxipng {
+    fn default() -> Oxipng {
+        Oxipng {
+            level: 2,
+            filters: None,
+            compression: None,
+            strategies: None,
+            window: None,
+            zopfli: false,
+            interlace: None,
+            alpha: false,
+            bit_depth_reduction: true,
+            palette_reduction: true,
+            idat_recoding: true,
+        }
+    }
+}
+
+#[cfg(feature = "fix")]
+impl Oxipng {
+    fn options(&self, settings : &OptimizeSettings) -> oxipng::Options {
+        let mut options = oxipng::Options::from_preset(self.level);
+        options.color_type_reduction = false;
+        options.bit_depth_reduction &= self.bit_depth_reduction;
+        options.palette_reduction &= self.palette_reduction;
+        options.idat_recoding &= self.idat_recoding;
+        options.interlace = if settings.deinterlace { Some(0) } else { self.interlace };
+        options.strip = if settings.strip_metadata { oxipng::Headers::Safe } else { oxipng::Headers::None };
+        // oxipng stops trying new settings once this runs out, and returns the best so far. That
+        // depends on how fast the machine is, so reproducible runs check the timeout afterwards.
+        options.timeout = if settings.reproducible { None } else { settings.timeout };
+        // Don't guess the best filter from the image, try every one
+        options.use_heuristics &= !settings.reproducible;
+
+        // Alpha optimizations are off unless asked for
+        options.alphas = if self.alpha {
+            [oxipng::AlphaOptim::NoOp,
+             oxipng::AlphaOptim::Black,
+             oxipng::AlphaOptim::White,
+             oxipng::AlphaOptim::Up,
+             oxipng::AlphaOptim::Down,
+             oxipng::AlphaOptim::Left,
+             oxipng::AlphaOptim::Right].iter().cloned().collect()
+        } else {
+            HashSet::new()
+        };
+        if let Some(filters) = &self.filters {
+            options.filter = filters.clone();
+        }
+        if self.zopfli {
+            options.deflate = oxipng::Deflaters::Zopfli;
+        }
+        // Only used by zlib
+        if let Some(levels) = &self.compression {
+            options.compression = levels.clone();
+        }
+        if let Some(strategies) = &self.strategies {
+            options.strategies = strategies.clone();
+        }
+        if let Some(window) = self.window {
+            options.window = window;
+        }
+        options
+    }
+}
+
+#[cfg(feature = "fix")]
+impl Optimizer for Oxipng {
+    fn optimize(&self, data : &[u8], settings : &OptimizeSettings) -> FixResult<Vec<u8>> {
+        let start = Instant::now();
+        let options = self.options(settings);
+        let optimized_data = if settings.reproducible {
+            // The trials run in parallel, so a tie can go to whichever finished first
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(1)
+                .build()
+                .map_err(|e| FixError::Io(io::Error::other(e)))?
+                .install(|| oxipng::optimize_from_memory(data, &options))?
+        } else {
+            oxipng::optimize_from_memory(data, &options)?
+        };
+        match settings.timeout {
+            Some(timeout) if start.elapsed() >= timeout => Err(FixError::TimedOut(timeout)),
+            _ => Ok(optimized_data),
+        }
+    }
+}
+
+// Runs another program, like zopflipng, pngcrush or ect. {input} and {output} in the arguments
+// are replaced with the paths of temporary files. Without either, the input and output paths are
+// added after the arguments. With only {input}, the program is expected to optimize in place.
+// Apart from the timeout, the settings are left to the program's own arguments.
+#[derive(Debug, Clone)]
+pub struct ExternalOptimizer {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+// Numbers the temporary files of concurrent runs
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+// How often to check whether a program with a timeout has exited
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn temp_file_path(suffix : &str) -> PathBuf {
+    let number = NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("png_header_scanner-{}-{}-{}.png", process::id(), number, suffix))
+}
+
+// Wait for the program to exit, killing it once it runs past the timeout. Returns None if it was
+// killed.
+fn wait_with_timeout(child : &mut Child, timeout : Option<Duration>) -> io::Result<Option<ExitStatus>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(Some),
+    };
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            // It may have exited in the meantime
+            let _ = child.kill();
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
+impl ExternalOptimizer {
+    fn run(&self,
+           data : &[u8],
+           input_path : &Path,
+           output_path : &Path,
+           settings : &OptimizeSettings) -> FixResult<Vec<u8>> {
+        fs::write(input_path, data)?;
+
+        let has_input = self.args.iter().any(|arg| arg.contains("{input}"));
+        let has_output = self.args.iter().any(|arg| arg.contains("{output}"));
+        let mut command = Command::new(&self.program);
+        for arg in &self.args {
+            command.arg(arg.replace("{input}", &input_path.to_string_lossy())
+                           .replace("{output}", &output_path.to_string_lossy()));
+        }
+        if !has_input && !has_output {
+            command.arg(input_path).arg(output_path);
+        }
+
+        let mut child = command.stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| FixError::ExternalOptimizer(format!("failed to run {}: {}", self.program, e)))?;
+
+        // Read stderr on another thread, so a program which prints a lot can't block on a full pipe
+        let mut stderr_pipe = child.stderr.take().unwrap();
+        let stderr_reader = thread::spawn(move || {
+            let mut stderr = Vec::new();
+            let _ = stderr_pipe.read_to_end(&mut stderr);
+            stderr
+        });
+        // The reader is left behind after a timeout, since anything the program started could
+        // still hold the pipe open
+        let status = match wait_with_timeout(&mut child, settings.timeout)? {
+            Some(status) => status,
+            None => return Err(FixError::TimedOut(settings.timeout.unwrap())),
+        };
+        if !status.success() {
+            let stderr = stderr_reader.join().unwrap_or_default();
+            return Err(FixError::ExternalOptimizer(format!("{} failed ({}): {}",
+                                                           self.program,
+                                                           status,
+                                                           String::from_utf8_lossy(&stderr).trim())));
+        }
+
+        let optimized_path = if has_input && !has_output { input_path } else { output_path };
+        Ok(fs::read(optimized_path)?)
+    }
+}
+
+impl Optimizer for ExternalOptimizer {
+    fn optimize(&self, data : &[u8], settings : &OptimizeSettings) -> FixResult<Vec<u8>> {
+        let input_path = temp_file_path("in");
+        let output_path = temp_file_path("out");
+        let result = self.run(data, &input_path, &output_path, settings);
+        let _ = fs::remove_file(&input_path);
+        let _ = fs::remove_file(&output_path);
+        result
+    }
+}
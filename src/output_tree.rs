@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Parallel folder which receives every write instead of the scanned folder, from --out. Fixed
+// files are written there, and everything else is copied or linked across once it's handled, so
+// the scanned folder is never touched.
+pub struct OutputTree {
+    source_root: PathBuf,
+    output_root: PathBuf,
+    // Link unchanged files to the originals instead of copying them
+    symlink: bool,
+    // Relative paths which were written or removed this run, so they aren't mirrored over
+    written: Mutex<HashSet<PathBuf>>,
+}
+
+#[cfg(unix)]
+fn symlink_file(original : &Path, link : &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(original : &Path, link : &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+// Clear whatever an earlier run left at path. Writing through a symlink would change the original.
+fn remove_existing(path : &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl OutputTree {
+    pub fn new(source_root : &Path, output_root : &Path, symlink : bool) -> OutputTree {
+        OutputTree {
+            source_root: source_root.to_path_buf(),
+            output_root: output_root.to_path_buf(),
+            symlink,
+            written: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn rel_path<'a>(&self, path : &'a Path) -> Option<&'a Path> {
+        path.strip_prefix(&self.source_root).ok()
+    }
+
+    // Where a file in the scanned folder ends up. Paths which are already in the output folder
+    // stay where they are.
+    pub fn output_path(&self, path : &Path) -> PathBuf {
+        match self.rel_path(path) {
+            Some(rel_path) => self.output_root.join(rel_path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    // The latest version of a file: its output if it was already written this run, otherwise
+    // the original
+    pub fn current_path(&self, path : &Path) -> PathBuf {
+        let written = self.rel_path(path).is_some_and(|rel_path| self.written.lock().unwrap().contains(rel_path));
+        if written {
+            self.output_path(path)
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    // Get the output path ready to be written, and stop it from being mirrored
+    pub fn prepare_write(&self, path : &Path) -> io::Result<PathBuf> {
+        let output_path = self.output_path(path);
+        if let Some(rel_path) = self.rel_path(path) {
+            self.written.lock().unwrap().insert(rel_path.to_path_buf());
+        }
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(&output_path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            fs::remove_file(&output_path)?;
+        }
+        Ok(output_path)
+    }
+
+    // Leave a file out of the output folder, like an original replaced by a converted copy
+    pub fn remove(&self, path : &Path) -> io::Result<()> {
+        let output_path = self.prepare_write(path)?;
+        remove_existing(&output_path)
+    }
+
+    // Copy or link a file which wasn't written into the output folder, replacing anything an
+    // earlier run left there
+    pub fn mirror_if_unchanged(&self, path : &Path) -> io::Result<()> {
+        let rel_path = match self.rel_path(path) {
+            Some(rel_path) => rel_path,
+            None => return Ok(()),
+        };
+        if self.written.lock().unwrap().contains(rel_path) {
+            return Ok(());
+        }
+
+        let output_path = self.output_root.join(rel_path);
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        remove_existing(&output_path)?;
+        if self.symlink {
+            symlink_file(&fs::canonicalize(path)?, &output_path)
+        } else {
+            fs::copy(path, &output_path).map(|_| ())
+        }
+    }
+}
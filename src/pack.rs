@@ -0,0 +1,40 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use zip::result::ZipResult;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+// Write every PNG under scan_path into a zip file at output_path, returning the number of files
+// packed. Entries are sorted by path and share a fixed timestamp and permissions, so the same set
+// of files always produces a byte-identical archive.
+//
+// Only the files a scan covers are packed, so no macOS metadata, dotfiles unless include_hidden
+// is set, or files this tool keeps in the folder.
+pub fn pack(scan_path : &Path, output_path : &Path, include_hidden : bool) -> ZipResult<usize> {
+    let mut rel_paths : Vec<PathBuf> = WalkDir::new(scan_path)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !crate::is_skipped_name(entry.file_name(), include_hidden))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file() && crate::is_png(entry.path()))
+        .map(|entry| entry.path().strip_prefix(scan_path).unwrap().to_path_buf())
+        .filter(|rel_path| !crate::is_state_file(rel_path))
+        .collect();
+    rel_paths.sort();
+
+    // PNGs are already compressed, so deflating them again only costs time
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .last_modified_time(DateTime::default())
+        .unix_permissions(0o644);
+
+    let mut zip = ZipWriter::new(File::create(output_path)?);
+    for rel_path in &rel_paths {
+        zip.start_file(crate::slash_path(rel_path), options)?;
+        zip.write_all(&fs::read(scan_path.join(rel_path))?)?;
+    }
+    zip.finish()?;
+
+    Ok(rel_paths.len())
+}
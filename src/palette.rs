@@ -0,0 +1,199 @@
+use crate::chunks;
+use crate::fix::{FixError, FixResult};
+
+fn format_error(message : String) -> FixError {
+    FixError::Decode(image::ImageError::FormatError(message))
+}
+
+// RGB palette entries and their alpha values. Entries without a tRNS value are opaque.
+struct Palette {
+    colors: Vec<[u8; 3]>,
+    alphas: Vec<u8>,
+}
+
+// Only reads the chunks up to the first IDAT, so nothing after it can get in the way
+fn read_palette(data : &[u8]) -> FixResult<Palette> {
+    let mut colors = None;
+    let mut alphas = Vec::new();
+    for chunk in chunks::RawChunks::new(data)? {
+        let chunk = chunk?;
+        match &chunk.chunk_type {
+            b"PLTE" => colors = Some(chunk.data.chunks_exact(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect()),
+            b"tRNS" => alphas = chunk.data.to_vec(),
+            b"IDAT" => break,
+            _ => {},
+        }
+    }
+
+    let colors = colors.ok_or_else(|| format_error("indexed PNG has no PLTE chunk".to_string()))?;
+    Ok(Palette { colors, alphas })
+}
+
+// Palette index of pixel x in a row packed at bit_depth bits per pixel, first pixel in the high bits
+fn packed_index(row : &[u8], x : usize, bit_depth : usize) -> usize {
+    let bit = x * bit_depth;
+    let shift = 8 - bit_depth - bit % 8;
+    ((row[bit / 8] >> shift) as usize) & ((1 << bit_depth) - 1)
+}
+
+// The palette index of every pixel, packed like the image data, and the bits per index
+fn decode_indices(data : &[u8]) -> FixResult<(png::OutputInfo, usize, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let (info, mut reader) = decoder.read_info().map_err(|e| format_error(e.to_string()))?;
+    if info.color_type != png::ColorType::Indexed {
+        return Err(format_error("PNG isn't indexed".to_string()));
+    }
+    let bit_depth = match info.bit_depth {
+        png::BitDepth::One => 1,
+        png::BitDepth::Two => 2,
+        png::BitDepth::Four => 4,
+        png::BitDepth::Eight => 8,
+        png::BitDepth::Sixteen => return Err(format_error("indexed PNG can't be 16-bit".to_string())),
+    };
+
+    let mut indices = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut indices).map_err(|e| format_error(e.to_string()))?;
+    Ok((info, bit_depth, indices))
+}
+
+// Decode an indexed PNG straight from its palette, instead of relying on the image crate's
+// expansion. Handles 1, 2 and 4-bit images, palettes with fewer entries than the bit depth
+// allows, and tRNS chunks covering only part of the palette. Images with any transparency come
+// out as RGBA, others as RGB.
+pub fn expand_indexed(data : &[u8]) -> FixResult<image::DynamicImage> {
+    let palette = read_palette(data)?;
+    let (info, bit_depth, indices) = decode_indices(data)?;
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let has_alpha = palette.alphas.iter().any(|&alpha| alpha != 255);
+    let channels = if has_alpha { 4 } else { 3 };
+    let mut pixels = Vec::with_capacity(width * height * channels);
+    for row in indices.chunks(info.line_size).take(height) {
+        for x in 0..width {
+            let index = packed_index(row, x, bit_depth);
+            let color = palette.colors.get(index)
+                .ok_or_else(|| format_error(format!("palette index {} is past the end of the palette", index)))?;
+            pixels.extend_from_slice(color);
+            if has_alpha {
+                pixels.push(palette.alphas.get(index).copied().unwrap_or(255));
+            }
+        }
+    }
+
+    let (width, height) = (info.width, info.height);
+    let image = if has_alpha {
+        image::ImageBuffer::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgba8)
+    } else {
+        image::ImageBuffer::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgb8)
+    };
+    image.ok_or_else(|| format_error("decoded image has the wrong size".to_string()))
+}
+
+// Which palette entries at least one pixel uses, for --palette-report. Indices past the end of
+// the palette aren't counted.
+pub fn used_entries(data : &[u8]) -> FixResult<Vec<bool>> {
+    let (info, bit_depth, indices) = decode_indices(data)?;
+    let mut used = vec![false; 1 << bit_depth];
+    for row in indices.chunks(info.line_size).take(info.height as usize) {
+        for x in 0..info.width as usize {
+            used[packed_index(row, x, bit_depth)] = true;
+        }
+    }
+    Ok(used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EXPECTED_PNG_HEADER;
+
+    // An indexed PNG with one unfiltered row per slice in rows, followed by trailing
+    fn indexed_png(width : u32,
+                   bit_depth : u8,
+                   palette : &[u8],
+                   transparency : Option<&[u8]>,
+                   rows : &[&[u8]],
+                   trailing : &[u8]) -> Vec<u8> {
+        let mut data = EXPECTED_PNG_HEADER.to_vec();
+        let mut ihdr = width.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&(rows.len() as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[bit_depth, 3, 0, 0, 0]);
+        chunks::write_chunk(&mut data, b"IHDR", &ihdr);
+        chunks::write_chunk(&mut data, b"PLTE", palette);
+        if let Some(transparency) = transparency {
+            chunks::write_chunk(&mut data, b"tRNS", transparency);
+        }
+        let mut raw = Vec::new();
+        for row in rows {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        chunks::write_chunk(&mut data, b"IDAT", &miniz_oxide::deflate::compress_to_vec_zlib(&raw, 6));
+        chunks::write_chunk(&mut data, b"IEND", &[]);
+        data.extend_from_slice(trailing);
+        data
+    }
+
+    fn expand_rgb(data : &[u8]) -> Vec<u8> {
+        match expand_indexed(data).unwrap() {
+            image::DynamicImage::ImageRgb8(buffer) => buffer.into_raw(),
+            _ => panic!("expected an RGB image"),
+        }
+    }
+
+    fn expand_rgba(data : &[u8]) -> Vec<u8> {
+        match expand_indexed(data).unwrap() {
+            image::DynamicImage::ImageRgba8(buffer) => buffer.into_raw(),
+            _ => panic!("expected an RGBA image"),
+        }
+    }
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    #[test]
+    fn one_bit() {
+        let data = indexed_png(3, 1, &[RED, GREEN].concat(), None, &[&[0b1010_0000]], &[]);
+        assert_eq!(expand_rgb(&data), [GREEN, RED, GREEN].concat());
+    }
+
+    #[test]
+    fn two_bit_with_partial_palette() {
+        // 3 of the 4 entries a 2-bit palette can have
+        let data = indexed_png(4, 2, &[RED, GREEN, BLUE].concat(), None, &[&[0b00_01_10_10]], &[]);
+        assert_eq!(expand_rgb(&data), [RED, GREEN, BLUE, BLUE].concat());
+    }
+
+    #[test]
+    fn four_bit_with_partial_transparency() {
+        // Entries without a tRNS value are opaque
+        let data = indexed_png(3, 4, &[RED, GREEN, BLUE].concat(), Some(&[0, 128]), &[&[0x01, 0x20]], &[]);
+        assert_eq!(expand_rgba(&data), [255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn multiple_rows() {
+        let data = indexed_png(2, 4, &[RED, GREEN].concat(), None, &[&[0x01], &[0x10]], &[]);
+        assert_eq!(expand_rgb(&data), [RED, GREEN, GREEN, RED].concat());
+    }
+
+    #[test]
+    fn index_past_the_end_of_the_palette() {
+        let data = indexed_png(2, 2, &[RED, GREEN].concat(), None, &[&[0b01_11_00_00]], &[]);
+        assert!(expand_indexed(&data).is_err());
+    }
+
+    #[test]
+    fn data_after_iend() {
+        let data = indexed_png(2, 1, &[RED, GREEN].concat(), None, &[&[0b0100_0000]], b"not a chunk");
+        assert_eq!(expand_rgb(&data), [RED, GREEN].concat());
+    }
+
+    #[test]
+    fn used_entries_of_partial_palette() {
+        let data = indexed_png(3, 2, &[RED, GREEN, BLUE].concat(), None, &[&[0b00_10_00_00]], &[]);
+        assert_eq!(used_entries(&data).unwrap(), [true, false, true, false]);
+    }
+}
@@ -0,0 +1,98 @@
+use serde_json::Value;
+use std::fs::File;
+use std::path::Path;
+use png_header_scanner::chunks::ChunkInfo;
+
+// A chunk type which must not be shipped. For text chunks, it can be limited to keywords
+// containing one of the given strings (compared case insensitively).
+#[derive(Debug)]
+pub struct ForbiddenChunk {
+    pub chunk_type: [u8; 4],
+    pub keywords: Option<Vec<String>>,
+}
+
+impl ForbiddenChunk {
+    fn matches(&self, chunk : &ChunkInfo) -> bool {
+        if chunk.chunk_type != self.chunk_type {
+            return false;
+        }
+
+        match (&self.keywords, &chunk.keyword) {
+            (None, _) => true,
+            (Some(keywords), Some(keyword)) => {
+                let keyword = keyword.to_lowercase();
+                keywords.iter().any(|part| keyword.contains(&part.to_lowercase()))
+            },
+            (Some(_), None) => false,
+        }
+    }
+}
+
+// Project rules loaded from a --policy file, e.g.
+//
+// {
+//     "forbidden_chunks": ["eXIf", { "type": "tEXt", "keywords": ["GPS"] }],
+//     "strip_forbidden_chunks": true
+// }
+#[derive(Debug, Default)]
+pub struct Policy {
+    pub forbidden_chunks: Vec<ForbiddenChunk>,
+    // Remove forbidden chunks instead of only reporting them
+    pub strip_forbidden_chunks: bool,
+}
+
+impl Policy {
+    pub fn is_forbidden(&self, chunk : &ChunkInfo) -> bool {
+        self.forbidden_chunks.iter().any(|forbidden| forbidden.matches(chunk))
+    }
+}
+
+fn parse_chunk_type(value : &Value) -> Result<[u8; 4], String> {
+    let name = value.as_str().ok_or("chunk type must be a string")?;
+    if name.len() != 4 || !name.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+        return Err(format!("'{}' is not a valid chunk type", name));
+    }
+
+    let mut chunk_type : [u8; 4] = [0; 4];
+    chunk_type.copy_from_slice(name.as_bytes());
+    Ok(chunk_type)
+}
+
+fn parse_forbidden_chunk(value : &Value) -> Result<ForbiddenChunk, String> {
+    // Either just the chunk type, or an object with the type and keywords
+    if value.is_string() {
+        return Ok(ForbiddenChunk { chunk_type: parse_chunk_type(value)?, keywords: None });
+    }
+
+    let chunk_type = parse_chunk_type(value.get("type").ok_or("forbidden chunk is missing its type")?)?;
+    let keywords = match value.get("keywords") {
+        Some(Value::Array(keywords)) => {
+            let keywords = keywords.iter()
+                .map(|keyword| keyword.as_str().map(String::from).ok_or("keywords must be strings"))
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(keywords)
+        },
+        Some(_) => return Err(String::from("keywords must be a list")),
+        None => None,
+    };
+
+    Ok(ForbiddenChunk { chunk_type, keywords })
+}
+
+pub fn load_policy(path : &Path) -> Result<Policy, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let value : Value = serde_json::from_reader(file).map_err(|e| e.to_string())?;
+
+    let forbidden_chunks = match value.get("forbidden_chunks") {
+        Some(Value::Array(chunks)) => chunks.iter().map(parse_forbidden_chunk).collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err(String::from("forbidden_chunks must be a list")),
+        None => Vec::new(),
+    };
+    let strip_forbidden_chunks = match value.get("strip_forbidden_chunks") {
+        Some(Value::Bool(strip)) => *strip,
+        Some(_) => return Err(String::from("strip_forbidden_chunks must be true or false")),
+        None => false,
+    };
+
+    Ok(Policy { forbidden_chunks, strip_forbidden_chunks })
+}
@@ -0,0 +1,119 @@
+use std::path::Path;
+use walkdir::WalkDir;
+use log::{error, info};
+use png_header_scanner::atomic_write;
+use png_header_scanner::{parse_one, ParseResult, PixelFormat};
+
+// Settings for the quantize subcommand
+pub struct QuantizeOptions {
+    // imagequant quality range, 0-100. Images which can't reach min_quality are left alone.
+    pub min_quality: u8,
+    pub max_quality: u8,
+    pub dither: bool,
+}
+
+// Why an image was left as it is
+enum Skipped {
+    QualityTooLow,
+    WouldGrow(u64, u64),
+}
+
+fn encode_indexed(width : u32, height : u32, palette : &[imagequant::RGBA], indices : &[u8]) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+
+        let colors : Vec<u8> = palette.iter().flat_map(|color| vec![color.r, color.g, color.b]).collect();
+        writer.write_chunk(*b"PLTE", &colors).map_err(|e| e.to_string())?;
+
+        // Trailing opaque entries can be left out of tRNS
+        let alphas : Vec<u8> = palette.iter().map(|color| color.a).collect();
+        let num_alphas = alphas.iter().rposition(|&alpha| alpha != 255).map_or(0, |last| last + 1);
+        if num_alphas > 0 {
+            writer.write_chunk(*b"tRNS", &alphas[..num_alphas]).map_err(|e| e.to_string())?;
+        }
+
+        writer.write_image_data(indices).map_err(|e| e.to_string())?;
+    }
+    Ok(data)
+}
+
+// Reduce the image to at most 256 colors, returning an optimized indexed PNG
+fn quantize_data(original_data : &[u8], options : &QuantizeOptions) -> Result<Result<Vec<u8>, Skipped>, String> {
+    let image = image::load_from_memory(original_data).map_err(|e| e.to_string())?.to_rgba();
+    let (width, height) = image.dimensions();
+    let pixels : Vec<imagequant::RGBA> = image.chunks_exact(4)
+        .map(|pixel| imagequant::RGBA { r: pixel[0], g: pixel[1], b: pixel[2], a: pixel[3] })
+        .collect();
+
+    let mut attributes = imagequant::new();
+    attributes.set_quality(u32::from(options.min_quality), u32::from(options.max_quality))
+        .ok().map_err(|e| format!("{:?}", e))?;
+    let mut liq_image = attributes.new_image(&pixels, width as usize, height as usize, 0.0)
+        .map_err(|e| format!("{:?}", e))?;
+    let mut quantized = match attributes.quantize(&liq_image) {
+        Ok(quantized) => quantized,
+        Err(imagequant::liq_error::QualityTooLow) => return Ok(Err(Skipped::QualityTooLow)),
+        Err(e) => return Err(format!("{:?}", e)),
+    };
+    quantized.set_dithering_level(if options.dither { 1.0 } else { 0.0 })
+        .ok().map_err(|e| format!("{:?}", e))?;
+    let (palette, indices) = quantized.remapped(&mut liq_image).map_err(|e| format!("{:?}", e))?;
+
+    let indexed_data = encode_indexed(width, height, &palette, &indices)?;
+    let optimized_data = oxipng::optimize_from_memory(&indexed_data, &oxipng::Options {
+        // Keep the palette as imagequant made it
+        color_type_reduction: false,
+        ..Default::default()
+    }).map_err(|e| e.to_string())?;
+
+    if optimized_data.len() >= original_data.len() {
+        return Ok(Err(Skipped::WouldGrow(original_data.len() as u64, optimized_data.len() as u64)));
+    }
+    Ok(Ok(optimized_data))
+}
+
+// Convert every truecolor PNG under scan_path to an indexed PNG, the opposite of the usual
+// fix. Returns the number of files converted.
+pub fn quantize(scan_path : &Path, options : &QuantizeOptions) -> usize {
+    let mut num_quantized = 0;
+    for entry in WalkDir::new(scan_path).into_iter().filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if !path.is_file() || !crate::is_png(path) {
+            continue;
+        }
+        let rel_path = path.strip_prefix(scan_path).unwrap();
+
+        // Greyscale and palette images are left alone
+        match parse_one(path) {
+            ParseResult::Valid(header) if header.pixel_format == PixelFormat::TrueColor ||
+                                          header.pixel_format == PixelFormat::TrueColorWithAlpha => {},
+            _ => continue,
+        }
+
+        let original_data = std::fs::read(path).expect("Failed to read image!");
+        match quantize_data(&original_data, options) {
+            Ok(Ok(quantized_data)) => {
+                atomic_write::write_atomic(path, &quantized_data, &Default::default()).expect("Failed to save image!");
+                changed!("Quantized {} [{}KB -> {}KB]",
+                         rel_path.display(),
+                         original_data.len() as f32 / 1000f32,
+                         quantized_data.len() as f32 / 1000f32);
+                num_quantized += 1;
+            },
+            Ok(Err(Skipped::QualityTooLow)) => {
+                info!("Skipped {}: can't reach quality {}", rel_path.display(), options.min_quality);
+            },
+            Ok(Err(Skipped::WouldGrow(before, after))) => {
+                info!("Skipped {}: would grow from {} to {} bytes", rel_path.display(), before, after);
+            },
+            Err(e) => error!("Failed to quantize {}: {}", rel_path.display(), e),
+        }
+    }
+
+    info!("Quantized {} files.", num_quantized);
+    num_quantized
+}
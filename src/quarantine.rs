@@ -0,0 +1,66 @@
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use png_header_scanner::validate::ChunkErrorKind;
+use png_header_scanner::ParseResult;
+use crate::io_limit::IoLimiter;
+use crate::{Finding, FindingKind};
+
+const MANIFEST_FILE_NAME: &str = "quarantine_manifest.json";
+
+// Broken files which no decoder could make sense of. Files in another image format or with
+// fixable problems are left where they are.
+fn is_broken(kind : &FindingKind) -> bool {
+    match kind {
+        FindingKind::Invalid(parse_result) => {
+            matches!(parse_result, ParseResult::InvalidPngHeader | ParseResult::InvalidIhdr | ParseResult::ReadFail)
+        },
+        FindingKind::CorruptChunk(chunk_error) => matches!(chunk_error.kind, ChunkErrorKind::Truncated),
+        _ => false,
+    }
+}
+
+// Entries from an earlier run are kept, so the manifest covers everything in the folder
+fn read_manifest(manifest_path : &Path) -> Vec<Value> {
+    let manifest : Option<Value> = File::open(manifest_path).ok()
+        .and_then(|file| serde_json::from_reader(file).ok());
+    match manifest {
+        Some(Value::Array(entries)) => entries,
+        _ => Vec::new(),
+    }
+}
+
+// Move every broken file under scan_path into quarantine_path, keeping its relative path, and
+// list them in a manifest there. Returns the number of files moved.
+pub fn quarantine(scan_path : &Path, quarantine_path : &Path, findings : &[Finding], io_limiter : &IoLimiter)
+                  -> io::Result<usize> {
+    let mut manifest = Vec::new();
+    let mut moved = HashSet::new();
+    for finding in findings.iter().filter(|finding| is_broken(&finding.kind)) {
+        // PNGs inside archives aren't files of their own
+        let path = scan_path.join(&finding.rel_path);
+        if !path.is_file() || !moved.insert(&finding.rel_path) {
+            continue;
+        }
+
+        io_limiter.move_file(&path, &quarantine_path.join(&finding.rel_path))?;
+        changed!("Quarantined {}", finding.rel_path.display());
+        manifest.push(json!({
+            "path": crate::slash_path(&finding.rel_path),
+            "reason": finding.kind.description(),
+        }));
+    }
+
+    if manifest.is_empty() {
+        return Ok(0);
+    }
+
+    let manifest_path = quarantine_path.join(MANIFEST_FILE_NAME);
+    let mut entries = read_manifest(&manifest_path);
+    entries.extend(manifest);
+    serde_json::to_writer_pretty(File::create(manifest_path)?, &entries)?;
+
+    Ok(moved.len())
+}
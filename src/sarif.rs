@@ -0,0 +1,100 @@
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use crate::{Finding, FindingKind};
+
+fn level(kind : &FindingKind) -> &'static str {
+    match kind {
+        FindingKind::Indexed { fixed: true } |
+        FindingKind::MatchedPixelFormat { fixed: true, .. } |
+        FindingKind::SixteenBit { fixed: true } |
+        FindingKind::OpaqueAlpha { fixed: true } |
+        FindingKind::Exported { .. } |
+        FindingKind::OtherFormat { converted: true, .. } => "note",
+        FindingKind::Interlaced { fixed: true } |
+        FindingKind::ForbiddenChunk { stripped: true, .. } |
+        FindingKind::AppleCgbi { repaired: true } |
+        FindingKind::TrailingData { removed: true, .. } |
+        FindingKind::OverMaxDimension { resized: true, .. } |
+        FindingKind::InconsistentDpi { normalized: true, .. } |
+        FindingKind::ColorSpace { fixed: true, .. } => "note",
+        FindingKind::Animated |
+        FindingKind::ReadOnly |
+        FindingKind::OtherFormat { converted: false, .. } |
+        FindingKind::TrailingData { removed: false, .. } |
+        FindingKind::TooLarge { .. } |
+        FindingKind::WouldGrow(_) |
+        FindingKind::BelowMinSavings(_) |
+        FindingKind::MissingPngExtension |
+        FindingKind::OversizedBitDepth { .. } |
+        FindingKind::InconsistentDpi { normalized: false, .. } |
+        FindingKind::ColorSpace { fixed: false, .. } |
+        FindingKind::Interlaced { fixed: false } => "warning",
+        _ => "error",
+    }
+}
+
+fn rule(id : &str, description : &str) -> Value {
+    json!({
+        "id": id,
+        "shortDescription": { "text": description },
+    })
+}
+
+fn result(finding : &Finding) -> Value {
+    json!({
+        "ruleId": finding.kind.id(),
+        "level": level(&finding.kind),
+        "message": { "text": finding.kind.description() },
+        "locations": [{
+            "physicalLocation": {
+                "artifactLocation": {
+                    "uri": crate::slash_path(&finding.rel_path),
+                    "uriBaseId": "%SRCROOT%",
+                }
+            }
+        }],
+    })
+}
+
+// Write the findings as a SARIF 2.1.0 log. Paths are relative to the scanned folder.
+pub fn write_sarif(output_path : &Path, findings : &[Finding]) -> io::Result<()> {
+    let sarif = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "png_header_scanner",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": [
+                        rule("indexed-png", "PNG uses indexed color"),
+                        rule("invalid-png", "PNG header is corrupt or unsupported"),
+                        rule("extension-mismatch", "File extension doesn't match the file contents"),
+                        rule("oversized-bit-depth", "Indexed PNG bit depth is larger than its palette needs"),
+                        rule("interlaced-png", "PNG is Adam7 interlaced"),
+                        rule("would-grow", "Fixed PNG would be larger than the original"),
+                        rule("below-min-savings", "Fixing the PNG wouldn't save enough to be worth rewriting"),
+                        rule("too-large", "PNG is too large to decode safely"),
+                        rule("trailing-data", "PNG has data after the IEND chunk"),
+                        rule("corrupt-chunk", "PNG chunk is corrupt or out of order"),
+                        rule("apple-cgbi", "PNG is in Apple's CgBI format"),
+                        rule("animated-png", "PNG is animated (APNG)"),
+                        rule("forbidden-chunk", "PNG contains a chunk forbidden by the policy"),
+                        rule("read-only", "File was skipped because it is read-only"),
+                        rule("fix-failed", "Fixing the PNG failed with an error"),
+                        rule("matched-pixel-format", "PNG has a pixel format selected by --match"),
+                        rule("16-bit-png", "PNG uses 16 bits per channel"),
+                        rule("exported", "PNG was converted to a lossless delivery format"),
+                        rule("other-format", "Image is in another raster format, like BMP, TGA or TIFF"),
+                    ],
+                }
+            },
+            "results": findings.iter().map(result).collect::<Vec<_>>(),
+        }],
+    });
+
+    serde_json::to_writer_pretty(File::create(output_path)?, &sarif)?;
+    Ok(())
+}
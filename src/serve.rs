@@ -0,0 +1,188 @@
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::{FileResult, FindingKind, ScanOptions};
+
+// How often the idle server checks whether it was asked to stop with Ctrl-C
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Larger uploads are refused rather than read into memory
+const MAX_UPLOAD_SIZE: u64 = 256_000_000;
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+// Settings for the serve subcommand
+pub struct ServeOptions {
+    pub listen: String,
+    // ?path= requests may read files below this folder. Without it, only uploads are accepted.
+    pub root: Option<PathBuf>,
+}
+
+fn header(name : &str, value : &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Invalid HTTP header")
+}
+
+fn json_response(status : u16, value : &Value) -> HttpResponse {
+    Response::from_data(value.to_string().into_bytes())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error_response(status : u16, message : &str) -> HttpResponse {
+    json_response(status, &json!({ "error": message }))
+}
+
+// %XX escapes and + for spaces, as in a query string
+fn percent_decode(value : &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut chars = value.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'%' => {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            b'+' => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn query_param(query : &str, name : &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(value))
+}
+
+// A file below the root folder. Anything which resolves to somewhere outside it, through .. or
+// a symlink, is refused.
+fn resolve_server_path(root : &Path, rel_path : &str) -> Result<PathBuf, HttpResponse> {
+    let canonical_root = fs::canonicalize(root).map_err(|e| error_response(500, &e.to_string()))?;
+    let path = fs::canonicalize(root.join(rel_path)).map_err(|_| error_response(404, "no such file"))?;
+    if !path.starts_with(&canonical_root) || !path.is_file() {
+        return Err(error_response(403, "the path must be a file inside the served folder"));
+    }
+    Ok(path)
+}
+
+// The PNG to work on and the name to report it as, from ?path= or the request body
+fn request_data(request : &mut Request, query : &str, serve_options : &ServeOptions)
+    -> Result<(PathBuf, Vec<u8>), HttpResponse> {
+    if let Some(rel_path) = query_param(query, "path") {
+        let root = serve_options.root.as_ref()
+            .ok_or_else(|| error_response(403, "server paths are only allowed with --root"))?;
+        let path = resolve_server_path(root, &rel_path)?;
+        let data = fs::read(&path).map_err(|e| error_response(500, &e.to_string()))?;
+        return Ok((PathBuf::from(rel_path), data));
+    }
+
+    if *request.method() != Method::Post {
+        return Err(error_response(400, "POST the PNG as the request body, or give ?path="));
+    }
+    if request.body_length().is_some_and(|length| length as u64 > MAX_UPLOAD_SIZE) {
+        return Err(error_response(413, "the upload is too large"));
+    }
+    let mut data = Vec::new();
+    request.as_reader().take(MAX_UPLOAD_SIZE + 1).read_to_end(&mut data)
+        .map_err(|e| error_response(400, &e.to_string()))?;
+    if data.len() as u64 > MAX_UPLOAD_SIZE {
+        return Err(error_response(413, "the upload is too large"));
+    }
+    let name = query_param(query, "name").unwrap_or_else(|| "upload.png".to_string());
+    Ok((PathBuf::from(name), data))
+}
+
+// What a scan would report about the file, with its header if it could be read
+fn analysis_json(name : &Path, result : &FileResult) -> Value {
+    let mut analysis = crate::jsonl::file_result_json(name, result);
+    let header = result.header.map(|header| json!({
+        "width": header.width,
+        "height": header.height,
+        "pixel_format": format!("{:?}", header.pixel_format),
+        "bit_depth": header.bit_depth,
+        "interlaced": header.interlaced,
+    }));
+    if let Some(analysis) = analysis.as_object_mut() {
+        analysis.insert("header".to_string(), header.unwrap_or(Value::Null));
+    }
+    analysis
+}
+
+// GET or POST /analyze returns the findings as JSON.
+//
+// GET or POST /fix returns the fixed PNG, with the findings in the X-Findings header. If it didn't
+// need fixing the response is 204 No Content, and if it couldn't be fixed it's 422 with the
+// findings as JSON. Files given with ?path= are never changed on the server.
+fn handle(request : &mut Request, options : &ScanOptions, serve_options : &ServeOptions) -> HttpResponse {
+    let url = request.url().to_string();
+    let (route, query) = url.split_once('?').unwrap_or((url.as_str(), ""));
+    if route != "/analyze" && route != "/fix" {
+        return error_response(404, "unknown endpoint, use /analyze or /fix");
+    }
+    let (name, data) = match request_data(request, query, serve_options) {
+        Ok(request_data) => request_data,
+        Err(response) => return response,
+    };
+
+    if route == "/analyze" {
+        let result = crate::archive_member_result(&name, png_header_scanner::parse_bytes(&data), options);
+        return json_response(200, &analysis_json(&name, &result));
+    }
+
+    let (result, fixed_data) = match crate::try_fix_archive_member(&name, &data, options) {
+        Ok(fixed) => fixed,
+        // A mismatch only fails this request, the server keeps running
+        Err(e) => {
+            error!("Error: optimized image wasn't identical to original image ({})", name.display());
+            let mut result = crate::archive_member_result(&name, png_header_scanner::parse_header(&data), options);
+            result.findings.push(FindingKind::FixFailed(crate::fix_error_kind(&e)));
+            return json_response(422, &analysis_json(&name, &result));
+        },
+    };
+    let analysis = analysis_json(&name, &result);
+    match (fixed_data, result.fix_outcome) {
+        (Some(fixed_data), Some(fix_outcome)) => {
+            Response::from_data(fixed_data)
+                .with_header(header("Content-Type", "image/png"))
+                .with_header(header("X-Size-Before", &fix_outcome.size_change.before.to_string()))
+                .with_header(header("X-Size-After", &fix_outcome.size_change.after.to_string()))
+                .with_header(header("X-Findings", &analysis["findings"].to_string()))
+        },
+        _ if result.findings.iter().any(|kind| kind.is_disallowed()) => json_response(422, &analysis),
+        _ => Response::from_data(Vec::new()).with_status_code(204),
+    }
+}
+
+// Answer HTTP requests until Ctrl-C is pressed. Requests are handled on the thread pool, so
+// --jobs of them at a time, with the settings from the main command line.
+pub fn serve(serve_options : ServeOptions, options : ScanOptions, thread_pool : &rayon::ThreadPool)
+    -> io::Result<()> {
+    let server = Server::http(serve_options.listen.as_str()).map_err(|e| io::Error::other(e.to_string()))?;
+    info!("Listening on http://{}", serve_options.listen);
+
+    let options = Arc::new(options);
+    let serve_options = Arc::new(serve_options);
+    while !crate::is_interrupted() {
+        let mut request = match server.recv_timeout(RECEIVE_POLL_INTERVAL)? {
+            Some(request) => request,
+            None => continue,
+        };
+        let options = Arc::clone(&options);
+        let serve_options = Arc::clone(&serve_options);
+        thread_pool.spawn(move || {
+            let response = handle(&mut request, &options, &serve_options);
+            info!("{} {} {}", request.method(), request.url(), response.status_code().0);
+            if let Err(e) = request.respond(response) {
+                warn!("Warning: failed to send the response: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
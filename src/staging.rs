@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use png_header_scanner::atomic_write::{self, WriteOptions};
+
+// Kept at the root of the scanned folder while a --transactional run is going
+pub const STAGING_DIR_NAME: &str = ".png_header_scanner_staging";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StagedChange {
+    Write,
+    Remove,
+}
+
+// Holds every change a --transactional run makes until the end of the run, so they can all be
+// applied together if every file fixed, or dropped without touching the folder. The staged files
+// are laid out like the scanned folder, so a file derived from a staged one, like a converted
+// copy, is staged under the right name too.
+pub struct Staging {
+    source_root: PathBuf,
+    staging_root: PathBuf,
+    // Relative paths, in order so the changes are applied the same way every time
+    changes: Mutex<BTreeMap<PathBuf, StagedChange>>,
+}
+
+impl Staging {
+    // Clears anything left behind by a run which crashed before it finished
+    pub fn new(source_root : &Path) -> io::Result<Staging> {
+        let staging_root = source_root.join(STAGING_DIR_NAME);
+        match fs::remove_dir_all(&staging_root) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            result => result?,
+        }
+        Ok(Staging {
+            source_root: source_root.to_path_buf(),
+            staging_root,
+            changes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    // Paths of staged files map back to the file they'll replace
+    fn rel_path(&self, path : &Path) -> io::Result<PathBuf> {
+        path.strip_prefix(&self.staging_root)
+            .or_else(|_e| path.strip_prefix(&self.source_root))
+            .map(|rel_path| rel_path.to_path_buf())
+            .map_err(|_e| io::Error::other(format!("{} is outside the scanned folder", path.display())))
+    }
+
+    // The staged version of a file if it was written this run, otherwise the original
+    pub fn current_path(&self, path : &Path) -> PathBuf {
+        match self.rel_path(path) {
+            Ok(rel_path) if self.changes.lock().unwrap().get(&rel_path) == Some(&StagedChange::Write) => {
+                self.staging_root.join(rel_path)
+            },
+            _ => path.to_path_buf(),
+        }
+    }
+
+    pub fn write(&self, path : &Path, data : &[u8]) -> io::Result<()> {
+        let rel_path = self.rel_path(path)?;
+        let staged_path = self.staging_root.join(&rel_path);
+        if let Some(parent) = staged_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        atomic_write::write_atomic(&staged_path, data, &WriteOptions::default())?;
+        self.changes.lock().unwrap().insert(rel_path, StagedChange::Write);
+        Ok(())
+    }
+
+    pub fn remove(&self, path : &Path) -> io::Result<()> {
+        let rel_path = self.rel_path(path)?;
+        match fs::remove_file(self.staging_root.join(&rel_path)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            result => result?,
+        }
+        self.changes.lock().unwrap().insert(rel_path, StagedChange::Remove);
+        Ok(())
+    }
+
+    // Every file to change, with the staged file to replace it with, or None to remove it
+    pub fn changes(&self) -> Vec<(PathBuf, Option<PathBuf>)> {
+        self.changes.lock().unwrap().iter()
+            .map(|(rel_path, change)| {
+                let staged_path = match change {
+                    StagedChange::Write => Some(self.staging_root.join(rel_path)),
+                    StagedChange::Remove => None,
+                };
+                (self.source_root.join(rel_path), staged_path)
+            })
+            .collect()
+    }
+
+    // Drop every staged change, once they're applied or if the run failed
+    pub fn clear(&self) -> io::Result<()> {
+        self.changes.lock().unwrap().clear();
+        match fs::remove_dir_all(&self.staging_root) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
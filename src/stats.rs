@@ -0,0 +1,88 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use log::info;
+use crate::ScanSummary;
+
+// Images are bucketed by their largest side
+const DIMENSION_BUCKETS: [u32; 7] = [64, 128, 256, 512, 1024, 2048, 4096];
+const HISTOGRAM_WIDTH: usize = 40;
+const LARGEST_SAVINGS_SHOWN: usize = 5;
+
+fn print_row(label : &str, count : usize) {
+    info!("  {:<24}{:>8}", label, count);
+}
+
+fn print_dimension_histogram(summary : &ScanSummary) {
+    let mut bucket_counts = [0; DIMENSION_BUCKETS.len() + 1];
+    for header in summary.headers() {
+        let largest_side = cmp::max(header.width, header.height);
+        let bucket = DIMENSION_BUCKETS.iter()
+            .position(|&limit| largest_side <= limit)
+            .unwrap_or(DIMENSION_BUCKETS.len());
+        bucket_counts[bucket] += 1;
+    }
+
+    let max_count = cmp::max(1, *bucket_counts.iter().max().unwrap());
+
+    info!("Dimensions (largest side):");
+    for (bucket, &count) in bucket_counts.iter().enumerate() {
+        let label = match DIMENSION_BUCKETS.get(bucket) {
+            Some(limit) => format!("<= {}", limit),
+            None => format!("> {}", DIMENSION_BUCKETS[DIMENSION_BUCKETS.len() - 1]),
+        };
+        info!("  {:<10}{:>8} {}", label, count, "#".repeat(count * HISTOGRAM_WIDTH / max_count));
+    }
+}
+
+fn print_largest_savings(summary : &ScanSummary) {
+    let mut fixed_files : Vec<_> = summary.fixed.iter().collect();
+    fixed_files.sort_by_key(|fixed_file| cmp::Reverse(fixed_file.size_change.saved()));
+
+    info!("Largest savings:");
+    for fixed_file in fixed_files.iter().take(LARGEST_SAVINGS_SHOWN) {
+        info!("  {:+.1}KB {}",
+              -fixed_file.size_change.saved() as f32 / 1000f32,
+              fixed_file.rel_path.display());
+    }
+}
+
+pub fn print_statistics(summary : &ScanSummary) {
+    let mut pixel_formats = BTreeMap::new();
+    let mut bit_depths = BTreeMap::new();
+    let mut num_interlaced = 0;
+    for header in summary.headers() {
+        *pixel_formats.entry(header.pixel_format).or_insert(0) += 1;
+        *bit_depths.entry(header.bit_depth).or_insert(0) += 1;
+        if header.interlaced {
+            num_interlaced += 1;
+        }
+    }
+
+    info!("===============================");
+    info!("Scanned {} PNGs", summary.num_scanned());
+
+    info!("Pixel formats:");
+    for (pixel_format, &count) in &pixel_formats {
+        print_row(&format!("{:?}", pixel_format), count);
+    }
+
+    info!("Bit depths:");
+    for (bit_depth, &count) in &bit_depths {
+        print_row(&format!("{}-bit", bit_depth), count);
+    }
+
+    info!("Interlaced:");
+    print_row("Adam7", num_interlaced);
+
+    print_dimension_histogram(summary);
+
+    if !summary.fixed.is_empty() {
+        let total = summary.total_size_change();
+        info!("Total size of fixed files: {:.1}KB -> {:.1}KB ({:+.1}KB)",
+              total.before as f32 / 1000f32,
+              total.after as f32 / 1000f32,
+              -total.saved() as f32 / 1000f32);
+        print_largest_savings(summary);
+    }
+    info!("===============================");
+}
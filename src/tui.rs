@@ -0,0 +1,262 @@
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use log::Level;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How often the screen is redrawn, which is also how long a key press can take to be noticed
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+// Older messages scroll out of the pane
+const MESSAGES_KEPT: usize = 200;
+
+const KEY_HELP: &str = "p pause/resume   s skip the rest of the current folder   q abort";
+
+// Set while the interface owns the terminal, so nothing else prints over it
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Tells the drawing thread to stop
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+static STATE: Mutex<Option<TuiState>> = Mutex::new(None);
+
+struct TuiState {
+    start: Instant,
+    // None while the folder is still being walked
+    total: Option<usize>,
+    done: usize,
+    fixed: usize,
+    failed: usize,
+    skipped: usize,
+    bytes_saved: i64,
+    // Files being handled right now, oldest first
+    active: Vec<(PathBuf, Instant)>,
+    // Warnings and errors, newest last
+    messages: VecDeque<String>,
+    paused: bool,
+    // Folders whose remaining files are left for another run
+    skipped_dirs: HashSet<PathBuf>,
+}
+
+impl TuiState {
+    fn push_message(&mut self, message : String) {
+        if self.messages.len() == MESSAGES_KEPT {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+}
+
+// Handle on the running interface. finish gives the terminal back.
+pub struct Tui {
+    thread: Option<JoinHandle<()>>,
+}
+
+fn with_state<T>(f : impl FnOnce(&mut TuiState) -> T) -> Option<T> {
+    STATE.lock().unwrap().as_mut().map(f)
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+// Take over the terminal and start drawing, until finish is called
+pub fn start() -> io::Result<Tui> {
+    *STATE.lock().unwrap() = Some(TuiState {
+        start: Instant::now(),
+        total: None,
+        done: 0,
+        fixed: 0,
+        failed: 0,
+        skipped: 0,
+        bytes_saved: 0,
+        active: Vec::new(),
+        messages: VecDeque::new(),
+        paused: false,
+        skipped_dirs: HashSet::new(),
+    });
+
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+    ACTIVE.store(true, Ordering::Relaxed);
+
+    // A panic message would otherwise be lost on the alternate screen, and leave the terminal raw
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+
+    let thread = thread::spawn(|| {
+        while !STOPPING.load(Ordering::Relaxed) {
+            // Drawing is best effort, the scan carries on without it
+            let _ = draw();
+            match event::poll(REDRAW_INTERVAL) {
+                Ok(true) => {
+                    if let Ok(Event::Key(key)) = event::read() {
+                        if key.kind == KeyEventKind::Press {
+                            handle_key(key.code, key.modifiers);
+                        }
+                    }
+                },
+                Ok(false) => {},
+                Err(_e) => thread::sleep(REDRAW_INTERVAL),
+            }
+        }
+    });
+    Ok(Tui { thread: Some(thread) })
+}
+
+impl Tui {
+    pub fn finish(mut self) {
+        STOPPING.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        restore_terminal();
+    }
+}
+
+// Leave the alternate screen. Safe to call more than once, or when the interface never started.
+pub fn restore_terminal() {
+    if ACTIVE.swap(false, Ordering::Relaxed) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn handle_key(code : KeyCode, modifiers : KeyModifiers) {
+    match code {
+        KeyCode::Char('p') => {
+            with_state(|state| state.paused = !state.paused);
+        },
+        KeyCode::Char('s') => {
+            with_state(|state| {
+                // The folder of the file which was started last is the one being worked through
+                let dir = state.active.last().and_then(|(rel_path, _)| rel_path.parent()).map(Path::to_path_buf);
+                if let Some(dir) = dir {
+                    state.push_message(format!("Skipping the rest of [{}]", dir.display()));
+                    state.skipped_dirs.insert(dir);
+                }
+            });
+        },
+        KeyCode::Char('q') | KeyCode::Esc => abort(),
+        // Raw mode turns Ctrl-C into a key press instead of a signal
+        KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => abort(),
+        _ => {},
+    }
+}
+
+// Like Ctrl-C, a second time stops right away
+fn abort() {
+    with_state(|state| state.push_message("Aborting, finishing the files which are being handled...".to_string()));
+    crate::interrupt();
+}
+
+// Cut a line to the width of the terminal, so it doesn't wrap onto the next one
+fn fit(line : &str, width : usize) -> String {
+    line.chars().take(width).collect()
+}
+
+fn draw() -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let (width, height) = (width as usize, height as usize);
+
+    let mut lines = Vec::new();
+    let footer = match STATE.lock().unwrap().as_ref() {
+        Some(state) => {
+            let elapsed = state.start.elapsed().as_secs();
+            let progress = match state.total {
+                Some(total) => format!("{}/{}", state.done, total),
+                None => "finding files...".to_string(),
+            };
+            lines.push(format!("png_header_scanner{}", if state.paused { "   [PAUSED]" } else { "" }));
+            lines.push(format!("Files {}   Fixed {}   Failed {}   Skipped {}   Saved {:.1}KB   \
+                                Elapsed {}:{:02}:{:02}",
+                               progress,
+                               state.fixed,
+                               state.failed,
+                               state.skipped,
+                               state.bytes_saved as f32 / 1000f32,
+                               elapsed / 3600,
+                               elapsed / 60 % 60,
+                               elapsed % 60));
+            lines.push(String::new());
+            lines.push("Handling:".to_string());
+            for (rel_path, started) in &state.active {
+                lines.push(format!("  {:>7.1}s  {}", started.elapsed().as_secs_f32(), rel_path.display()));
+            }
+            lines.push(String::new());
+            lines.push("Warnings and errors:".to_string());
+
+            // The newest messages which fit between the table and the key help
+            let room = height.saturating_sub(lines.len() + 2);
+            let skip = state.messages.len().saturating_sub(room);
+            lines.extend(state.messages.iter().skip(skip).map(|message| format!("  {}", message)));
+            KEY_HELP
+        },
+        None => return Ok(()),
+    };
+
+    let mut stdout = io::stdout();
+    queue!(stdout, MoveTo(0, 0), Clear(ClearType::All))?;
+    for (row, line) in lines.iter().take(height.saturating_sub(1)).enumerate() {
+        queue!(stdout, MoveTo(0, row as u16), Print(fit(line, width)))?;
+    }
+    queue!(stdout, MoveTo(0, height.saturating_sub(1) as u16), Print(fit(footer, width)))?;
+    stdout.flush()
+}
+
+// Called once the files to handle are known
+pub fn set_total(total : usize) {
+    with_state(|state| state.total = Some(total));
+}
+
+pub fn file_started(rel_path : &Path) {
+    with_state(|state| state.active.push((rel_path.to_path_buf(), Instant::now())));
+}
+
+pub fn file_finished(rel_path : &Path, fixed : usize, failed : usize, bytes_saved : i64) {
+    with_state(|state| {
+        state.active.retain(|(active_path, _)| active_path != rel_path);
+        state.done += 1;
+        state.fixed += fixed;
+        state.failed += failed;
+        state.bytes_saved += bytes_saved;
+    });
+}
+
+// Whether the file is in a folder skipped with 's'. Counted as done, but left for another run.
+pub fn skip_file(rel_path : &Path) -> bool {
+    with_state(|state| {
+        let skipped = rel_path.parent().is_some_and(|dir| state.skipped_dirs.contains(dir));
+        if skipped {
+            state.done += 1;
+            state.skipped += 1;
+        }
+        skipped
+    }).unwrap_or(false)
+}
+
+// Block the calling worker while paused, so no new file is started
+pub fn wait_while_paused() {
+    while with_state(|state| state.paused).unwrap_or(false) && !crate::is_interrupted() {
+        thread::sleep(REDRAW_INTERVAL);
+    }
+}
+
+// Messages from the logger while the interface is up. Only warnings and errors go in the pane,
+// since every fixed file would push them out of view.
+pub fn show_message(level : Level, message : String) {
+    if level <= Level::Warn {
+        with_state(|state| state.push_message(message));
+    }
+}
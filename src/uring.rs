@@ -0,0 +1,174 @@
+use png_header_scanner::ParseResult;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(target_os = "linux")]
+use io_uring::{opcode, squeue, types, IoUring};
+
+// How many files have their headers read ahead at once. Bounds the memory used by the headers
+// which haven't been picked up yet, and lets progress be shown while a huge folder is scanned.
+pub const PREFETCH_BATCH_SIZE: usize = 4096;
+
+// Reads in flight at once, unless --io-concurrency is given
+pub const DEFAULT_QUEUE_DEPTH: usize = 256;
+
+// Enough for the signature, a CgBI chunk and the IHDR chunk
+#[cfg(target_os = "linux")]
+const HEADER_READ_SIZE: usize = 64;
+
+// The kernel refuses larger rings
+#[cfg(target_os = "linux")]
+const MAX_QUEUE_DEPTH: usize = 4096;
+
+// Reads the headers of many files at once through io_uring, for --io-uring. With millions of
+// small files the time goes into open and read syscalls, one file at a time per thread, so
+// instead every open, read and close of a batch is submitted together and the kernel does them
+// concurrently.
+//
+// The headers are read ahead of the normal scan with fill, and each is picked up with take
+// when its file is handled. Files which aren't picked up are read normally.
+//
+// Only ever created on Linux
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct HeaderPrefetch {
+    #[cfg(target_os = "linux")]
+    ring: Mutex<IoUring>,
+    #[cfg(target_os = "linux")]
+    queue_depth: usize,
+    headers: Mutex<HashMap<PathBuf, ParseResult>>,
+}
+
+impl HeaderPrefetch {
+    // Fails if the kernel doesn't support io_uring, or it's blocked, as it often is in containers
+    #[cfg(target_os = "linux")]
+    pub fn new(queue_depth : usize) -> io::Result<HeaderPrefetch> {
+        let queue_depth = queue_depth.clamp(1, MAX_QUEUE_DEPTH);
+        Ok(HeaderPrefetch {
+            ring: Mutex::new(IoUring::new(queue_depth as u32)?),
+            queue_depth,
+            headers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_queue_depth : usize) -> io::Result<HeaderPrefetch> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "io_uring is only available on Linux"))
+    }
+
+    // Read the headers of these files, replacing any which weren't picked up from the last batch
+    #[cfg(target_os = "linux")]
+    pub fn fill(&self, paths : &[&Path]) -> io::Result<()> {
+        let mut ring = self.ring.lock().unwrap();
+        let mut headers = HashMap::with_capacity(paths.len());
+        for batch in paths.chunks(self.queue_depth) {
+            let parse_results = read_headers(&mut ring, batch)?;
+            headers.extend(batch.iter().map(|path| path.to_path_buf()).zip(parse_results));
+        }
+        *self.headers.lock().unwrap() = headers;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn fill(&self, _paths : &[&Path]) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The header read ahead for this file, if there is one. Each is only given out once.
+    pub fn take(&self, path : &Path) -> Option<ParseResult> {
+        self.headers.lock().unwrap().remove(path)
+    }
+}
+
+// Submit the entries and wait for all of them to complete. The result of each, a file
+// descriptor, a length or a negative errno, is in the same position as its entry.
+#[cfg(target_os = "linux")]
+fn run_entries(ring : &mut IoUring, entries : Vec<squeue::Entry>) -> io::Result<Vec<i32>> {
+    let num_entries = entries.len();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let entry = entry.user_data(index as u64);
+        // Safe because every buffer and path the entries point to outlives this call, which
+        // doesn't return until the kernel is done with all of them
+        unsafe { ring.submission().push(&entry) }
+            .map_err(|_e| io::Error::other("the io_uring submission queue is full"))?;
+    }
+
+    let mut results = vec![0; num_entries];
+    let mut num_completed = 0;
+    while num_completed < num_entries {
+        match ring.submit_and_wait(num_entries - num_completed) {
+            Ok(_) => {},
+            // Ctrl-C is noticed by the caller
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+        for completion in ring.completion() {
+            results[completion.user_data() as usize] = completion.result();
+            num_completed += 1;
+        }
+    }
+    Ok(results)
+}
+
+// Open every file, then read the start of every file which opened, then close them, with no more
+// than three waits however many files there are
+#[cfg(target_os = "linux")]
+fn read_headers(ring : &mut IoUring, paths : &[&Path]) -> io::Result<Vec<ParseResult>> {
+    use png_header_scanner::{parse_header, parse_one};
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // A path with a null byte in it can't be opened anyway
+    let c_paths : Vec<Option<CString>> = paths.iter()
+        .map(|path| CString::new(path.as_os_str().as_bytes()).ok())
+        .collect();
+    let opened : Vec<usize> = (0..paths.len()).filter(|&index| c_paths[index].is_some()).collect();
+    let open_entries = opened.iter()
+        .map(|&index| {
+            let c_path = c_paths[index].as_ref().unwrap();
+            opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), c_path.as_ptr())
+                .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+                .build()
+        })
+        .collect();
+    let mut fds : Vec<Option<i32>> = vec![None; paths.len()];
+    for (&index, fd) in opened.iter().zip(run_entries(ring, open_entries)?) {
+        if fd >= 0 {
+            fds[index] = Some(fd);
+        }
+    }
+
+    let readable : Vec<usize> = (0..paths.len()).filter(|&index| fds[index].is_some()).collect();
+    let mut buffers = vec![[0u8; HEADER_READ_SIZE]; readable.len()];
+    let read_entries = readable.iter()
+        .zip(buffers.iter_mut())
+        .map(|(&index, buffer)| {
+            opcode::Read::new(types::Fd(fds[index].unwrap()), buffer.as_mut_ptr(), HEADER_READ_SIZE as u32)
+                .offset(0)
+                .build()
+        })
+        .collect();
+    let lengths = run_entries(ring, read_entries)?;
+
+    // Failing to close a file which was only read doesn't matter
+    let close_entries = readable.iter()
+        .map(|&index| opcode::Close::new(types::Fd(fds[index].unwrap())).build())
+        .collect();
+    run_entries(ring, close_entries)?;
+
+    let mut parse_results : Vec<ParseResult> = vec![ParseResult::OpenFail; paths.len()];
+    for ((&index, buffer), length) in readable.iter().zip(&buffers).zip(lengths) {
+        let parse_result = if length < 0 {
+            ParseResult::ReadFail
+        } else {
+            parse_header(&buffer[..length as usize])
+        };
+        // Read again the normal way, which also covers a short read from a network filesystem and
+        // a CgBI chunk large enough to push the IHDR chunk past the end of the buffer
+        parse_results[index] = match parse_result {
+            ParseResult::ReadFail => parse_one(paths[index]),
+            parse_result => parse_result,
+        };
+    }
+    Ok(parse_results)
+}
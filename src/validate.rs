@@ -0,0 +1,353 @@
+use byteorder::{BigEndian, ReadBytesExt};
+use crc::crc32::{self, Hasher32};
+use miniz_oxide::inflate::TINFLStatus;
+use miniz_oxide::inflate::core::{self as inflate, DecompressorOxide};
+use miniz_oxide::inflate::core::inflate_flags::*;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChunkErrorKind {
+    OpenFail,
+    // The file ends in the middle of the chunk, or before IEND
+    Truncated,
+    BadCrc,
+    OutOfOrder(&'static str),
+    // A chunk which may only appear once was repeated
+    Duplicate,
+    MissingPalette,
+    MissingImageData,
+    // The IDAT data doesn't decompress to valid scanlines
+    CorruptImageData(&'static str),
+}
+
+// The first problem found in a file. The chunk type is missing if the problem isn't inside a
+// chunk, like missing IEND.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkError {
+    pub offset: u64,
+    pub chunk_type: Option<[u8; 4]>,
+    pub kind: ChunkErrorKind,
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ChunkErrorKind::OpenFail => write!(f, "file can't be opened")?,
+            ChunkErrorKind::Truncated => write!(f, "file is truncated")?,
+            ChunkErrorKind::BadCrc => write!(f, "CRC mismatch")?,
+            ChunkErrorKind::OutOfOrder(rule) => write!(f, "chunk out of order ({})", rule)?,
+            ChunkErrorKind::Duplicate => write!(f, "chunk may only appear once")?,
+            ChunkErrorKind::MissingPalette => write!(f, "indexed image has no PLTE chunk")?,
+            ChunkErrorKind::MissingImageData => write!(f, "no IDAT chunk")?,
+            ChunkErrorKind::CorruptImageData(reason) => write!(f, "corrupt image data ({})", reason)?,
+        }
+        match self.chunk_type {
+            Some(chunk_type) => write!(f, " in {} chunk at offset {}", String::from_utf8_lossy(&chunk_type), self.offset),
+            None => write!(f, " at offset {}", self.offset),
+        }
+    }
+}
+
+// Chunks which may only appear once
+const UNIQUE_CHUNKS: [&[u8; 4]; 15] = [
+    b"CgBI", b"IHDR", b"PLTE", b"IEND", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB",
+    b"bKGD", b"hIST", b"tRNS", b"pHYs", b"tIME", b"acTL",
+];
+
+// Chunks which must come before PLTE
+const BEFORE_PLTE_CHUNKS: [&[u8; 4]; 5] = [b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB"];
+
+// Chunks which must come before the first IDAT
+const BEFORE_IDAT_CHUNKS: [&[u8; 4]; 11] = [
+    b"PLTE", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"bKGD", b"hIST", b"tRNS", b"pHYs", b"sPLT",
+];
+
+// Chunks which must come after PLTE, if there is one
+const AFTER_PLTE_CHUNKS: [&[u8; 4]; 3] = [b"bKGD", b"hIST", b"tRNS"];
+
+// How much of a chunk is read into memory at once while checking its CRC
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// Size of the inflate output buffer. It's used as a ring buffer, so it has to be a power of two
+// at least as large as the deflate window.
+const INFLATE_BUFFER_SIZE: usize = 32 * 1024;
+
+// Adam7 passes as (x start, y start, x step, y step)
+const ADAM7_PASSES: [(u64, u64, u64, u64); 7] = [
+    (0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2),
+];
+
+// Decompresses the IDAT data as it's read, without keeping it, and checks every scanline starts
+// with a valid filter type
+struct ImageDataChecker {
+    decompressor: Box<DecompressorOxide>,
+    output: Vec<u8>,
+    output_position: usize,
+    // (bytes per scanline including the filter byte, number of scanlines) for each pass
+    passes: Vec<(u64, u64)>,
+    pass: usize,
+    scanline: u64,
+    position_in_scanline: u64,
+    done: bool,
+}
+
+impl ImageDataChecker {
+    fn new(ihdr : &[u8]) -> ImageDataChecker {
+        let width = u64::from((&ihdr[0..4]).read_u32::<BigEndian>().unwrap());
+        let height = u64::from((&ihdr[4..8]).read_u32::<BigEndian>().unwrap());
+        let bit_depth = u64::from(ihdr[8]);
+        let channels = match ihdr[9] {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        };
+        let scanline_bytes = |pixels : u64| 1 + (pixels * channels * bit_depth).div_ceil(8);
+
+        let passes = if ihdr[12] == 0 {
+            vec![(scanline_bytes(width), height)]
+        } else {
+            ADAM7_PASSES.iter()
+                .map(|&(x_start, y_start, x_step, y_step)| {
+                    let pass_width = (width + x_step - 1 - x_start.min(width)) / x_step;
+                    let pass_height = (height + y_step - 1 - y_start.min(height)) / y_step;
+                    (scanline_bytes(pass_width), if pass_width == 0 { 0 } else { pass_height })
+                })
+                .filter(|&(_, scanlines)| scanlines > 0)
+                .collect()
+        };
+
+        ImageDataChecker {
+            decompressor: Box::new(DecompressorOxide::new()),
+            output: vec![0; INFLATE_BUFFER_SIZE],
+            output_position: 0,
+            passes,
+            pass: 0,
+            scanline: 0,
+            position_in_scanline: 0,
+            done: false,
+        }
+    }
+
+    fn check_output(&mut self, start : usize, length : usize) -> Result<(), &'static str> {
+        for &byte in &self.output[start..start + length] {
+            if self.pass == self.passes.len() {
+                return Err("more data than the image size");
+            }
+
+            let (scanline_bytes, scanlines) = self.passes[self.pass];
+            if self.position_in_scanline == 0 && byte > 4 {
+                return Err("invalid filter type");
+            }
+
+            self.position_in_scanline += 1;
+            if self.position_in_scanline == scanline_bytes {
+                self.position_in_scanline = 0;
+                self.scanline += 1;
+                if self.scanline == scanlines {
+                    self.scanline = 0;
+                    self.pass += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Decompress as much as possible. more_input is false once all the IDAT data was given.
+    fn feed(&mut self, mut input : &[u8], more_input : bool) -> Result<(), &'static str> {
+        let mut flags = TINFL_FLAG_PARSE_ZLIB_HEADER | TINFL_FLAG_COMPUTE_ADLER32;
+        if more_input {
+            flags |= TINFL_FLAG_HAS_MORE_INPUT;
+        }
+
+        while !self.done {
+            let start = self.output_position;
+            let (status, consumed, written) = {
+                let mut output = Cursor::new(self.output.as_mut_slice());
+                output.set_position(start as u64);
+                inflate::decompress(&mut self.decompressor, input, &mut output, flags)
+            };
+            input = &input[consumed..];
+            self.check_output(start, written)?;
+            self.output_position = (start + written) & (INFLATE_BUFFER_SIZE - 1);
+
+            match status {
+                TINFLStatus::Done => self.done = true,
+                TINFLStatus::HasMoreOutput => {},
+                TINFLStatus::NeedsMoreInput => return Ok(()),
+                TINFLStatus::Adler32Mismatch => return Err("zlib checksum mismatch"),
+                TINFLStatus::FailedCannotMakeProgress => return Err("compressed data ends early"),
+                _ => return Err("invalid zlib stream"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), &'static str> {
+        self.feed(&[], false)?;
+        if !self.done || self.pass < self.passes.len() {
+            return Err("less data than the image size");
+        }
+        Ok(())
+    }
+}
+
+// Position in the file, so errors can say where the problem is
+struct ChunkReader<R> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: Read> ChunkReader<R> {
+    fn read_exact(&mut self, buffer : &mut [u8]) -> Result<(), ChunkErrorKind> {
+        self.reader.read_exact(buffer).map_err(|_e| ChunkErrorKind::Truncated)?;
+        self.offset += buffer.len() as u64;
+        Ok(())
+    }
+}
+
+// State needed to check the order of the chunks seen so far
+#[derive(Default)]
+struct ChunkOrder {
+    seen: Vec<[u8; 4]>,
+    seen_palette: bool,
+    seen_image_data: bool,
+    // The chunk before this one was IDAT
+    in_image_data: bool,
+}
+
+impl ChunkOrder {
+    fn check(&mut self, chunk_type : &[u8; 4]) -> Result<(), ChunkErrorKind> {
+        let is_first = self.seen.is_empty() || (self.seen.len() == 1 && &self.seen[0] == b"CgBI");
+
+        if is_first && chunk_type != b"IHDR" && chunk_type != b"CgBI" {
+            return Err(ChunkErrorKind::OutOfOrder("IHDR must be the first chunk"));
+        }
+        if !is_first && (chunk_type == b"IHDR" || chunk_type == b"CgBI") {
+            return Err(ChunkErrorKind::OutOfOrder("IHDR must be the first chunk"));
+        }
+        if UNIQUE_CHUNKS.contains(&chunk_type) && self.seen.contains(chunk_type) {
+            return Err(ChunkErrorKind::Duplicate);
+        }
+        if BEFORE_PLTE_CHUNKS.contains(&chunk_type) && self.seen_palette {
+            return Err(ChunkErrorKind::OutOfOrder("must come before PLTE"));
+        }
+        if BEFORE_IDAT_CHUNKS.contains(&chunk_type) && self.seen_image_data {
+            return Err(ChunkErrorKind::OutOfOrder("must come before IDAT"));
+        }
+        if chunk_type == b"IDAT" && self.seen_image_data && !self.in_image_data {
+            return Err(ChunkErrorKind::OutOfOrder("IDAT chunks must be consecutive"));
+        }
+
+        // Chunks which need PLTE to come first can't be checked until the end, as PLTE is
+        // optional for non-indexed images
+        match chunk_type {
+            b"PLTE" => {
+                if self.seen.iter().any(|seen| AFTER_PLTE_CHUNKS.contains(&seen)) {
+                    return Err(ChunkErrorKind::OutOfOrder("bKGD, hIST and tRNS must come after PLTE"));
+                }
+                self.seen_palette = true;
+            },
+            b"IDAT" => self.seen_image_data = true,
+            _ => {},
+        }
+
+        self.in_image_data = chunk_type == b"IDAT";
+        self.seen.push(*chunk_type);
+        Ok(())
+    }
+}
+
+// Walk every chunk in the file, checking the CRCs and the order of the chunks. The signature is
+// expected to already have been checked. Anything after IEND is ignored, see
+// chunks::read_trailing_data_length.
+pub fn validate_chunks<R: Read>(reader : R) -> Result<(), ChunkError> {
+    let mut reader = ChunkReader { reader, offset: 0 };
+    let mut order = ChunkOrder::default();
+    let mut indexed = false;
+    let mut image_data : Option<ImageDataChecker> = None;
+
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature)
+        .map_err(|kind| ChunkError { offset: 0, chunk_type: None, kind })?;
+
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let offset = reader.offset;
+        let error = |chunk_type, kind| ChunkError { offset, chunk_type, kind };
+
+        let mut chunk_header = [0u8; 8];
+        reader.read_exact(&mut chunk_header).map_err(|kind| error(None, kind))?;
+        let length = (&chunk_header[..4]).read_u32::<BigEndian>().unwrap();
+        let mut chunk_type : [u8; 4] = [0; 4];
+        chunk_type.copy_from_slice(&chunk_header[4..]);
+        let error = |kind| error(Some(chunk_type), kind);
+
+        order.check(&chunk_type).map_err(error)?;
+
+        // The image data ended with the previous chunk
+        if &chunk_type != b"IDAT" && order.seen_image_data {
+            if let Some(mut checker) = image_data.take() {
+                checker.finish().map_err(|reason| error(ChunkErrorKind::CorruptImageData(reason)))?;
+            }
+        }
+
+        // The CRC covers the type and the data
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        digest.write(&chunk_type);
+
+        // A bad CRC is the more useful error, so image data errors wait until it's checked
+        let mut image_data_error = None;
+
+        let mut remaining = length as usize;
+        while remaining > 0 {
+            let part = &mut buffer[..remaining.min(READ_BUFFER_SIZE)];
+            reader.read_exact(part).map_err(error)?;
+            if &chunk_type == b"IHDR" && remaining == length as usize && part.len() >= 13 {
+                indexed = part[9] == 3;
+                // CgBI image data isn't a zlib stream, so it can't be checked
+                if !order.seen.contains(b"CgBI") {
+                    image_data = Some(ImageDataChecker::new(part));
+                }
+            }
+            if &chunk_type == b"IDAT" && image_data_error.is_none() {
+                if let Some(checker) = &mut image_data {
+                    image_data_error = checker.feed(part, true).err();
+                }
+            }
+            digest.write(part);
+            remaining -= part.len();
+        }
+
+        let mut crc = [0u8; 4];
+        reader.read_exact(&mut crc).map_err(error)?;
+        if (&crc[..]).read_u32::<BigEndian>().unwrap() != digest.sum32() {
+            return Err(error(ChunkErrorKind::BadCrc));
+        }
+        if let Some(reason) = image_data_error {
+            return Err(error(ChunkErrorKind::CorruptImageData(reason)));
+        }
+
+        if &chunk_type == b"IEND" {
+            // Reported at IEND, by which point the chunk can't come anymore
+            if !order.seen_image_data {
+                return Err(ChunkError { offset, chunk_type: None, kind: ChunkErrorKind::MissingImageData });
+            }
+            if indexed && !order.seen_palette {
+                return Err(ChunkError { offset, chunk_type: None, kind: ChunkErrorKind::MissingPalette });
+            }
+            return Ok(());
+        }
+    }
+}
+
+pub fn validate_file(filename : &Path) -> Result<(), ChunkError> {
+    match File::open(filename) {
+        Ok(file) => validate_chunks(BufReader::new(file)),
+        Err(_e) => Err(ChunkError { offset: 0, chunk_type: None, kind: ChunkErrorKind::OpenFail }),
+    }
+}
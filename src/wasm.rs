@@ -0,0 +1,124 @@
+// Header checks for web pages, so an uploader can turn down PNGs the scanner would flag before
+// they're sent. Built for wasm32 with wasm-pack, without the fix feature:
+//
+// wasm-pack build --target web -- --no-default-features
+//
+// And used from JavaScript as:
+//
+// const inspection = inspectPng(new Uint8Array(await file.arrayBuffer()));
+// const problems = inspection.problems(4096 * 4096, undefined);
+
+use std::io::Cursor;
+use wasm_bindgen::prelude::*;
+use crate::fix::{self, FixOptions};
+use crate::{chunks, ParseResult, PixelFormat, PngHeader};
+
+// What inspectPng found. The header fields are 0 or false unless valid is true.
+#[wasm_bindgen]
+pub struct PngInspection {
+    parse_result: ParseResult,
+    animated: bool,
+}
+
+impl PngInspection {
+    fn header(&self) -> Option<PngHeader> {
+        match self.parse_result {
+            ParseResult::Valid(header) | ParseResult::AppleCgbi(header) => Some(header),
+            _ => None,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl PngInspection {
+    // The signature, IHDR and every chunk up to the image data could be read
+    #[wasm_bindgen(getter)]
+    pub fn valid(&self) -> bool {
+        self.header().is_some()
+    }
+
+    // Why the PNG isn't valid, like InvalidIhdr, or undefined if it is
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        match self.parse_result {
+            ParseResult::Valid(_) | ParseResult::AppleCgbi(_) => None,
+            error_parse_result => Some(format!("{:?}", error_parse_result)),
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.header().map_or(0, |header| header.width)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.header().map_or(0, |header| header.height)
+    }
+
+    #[wasm_bindgen(getter, js_name = bitDepth)]
+    pub fn bit_depth(&self) -> u8 {
+        self.header().map_or(0, |header| header.bit_depth)
+    }
+
+    // Like Greyscale or IndexedColor
+    #[wasm_bindgen(getter, js_name = pixelFormat)]
+    pub fn pixel_format(&self) -> Option<String> {
+        self.header().map(|header| format!("{:?}", header.pixel_format))
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn interlaced(&self) -> bool {
+        self.header().is_some_and(|header| header.interlaced)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn animated(&self) -> bool {
+        self.animated
+    }
+
+    // The ids of what a scan would report, the same as in its --jsonl and SARIF output:
+    // invalid-png, apple-cgbi, indexed-png, interlaced-png, animated-png and too-large. The
+    // limits are the scanner's --max-pixels and --max-decode-mem, and too-large is only
+    // reported if one is given.
+    pub fn problems(&self, max_pixels : Option<f64>, max_decode_mem : Option<f64>) -> Vec<String> {
+        let header = match self.parse_result {
+            ParseResult::Valid(header) => header,
+            ParseResult::AppleCgbi(_) => return vec!["apple-cgbi".to_string()],
+            _ => return vec!["invalid-png".to_string()],
+        };
+
+        let mut problems = Vec::new();
+        if header.pixel_format == PixelFormat::IndexedColor {
+            problems.push("indexed-png");
+        }
+        if header.interlaced {
+            problems.push("interlaced-png");
+        }
+        if self.animated {
+            problems.push("animated-png");
+        }
+        let limits = FixOptions {
+            max_pixels: max_pixels.map(|max_pixels| max_pixels as u64),
+            max_decode_mem: max_decode_mem.map(|max_decode_mem| max_decode_mem as u64),
+            ..FixOptions::default()
+        };
+        if fix::check_decode_limits(&header, &limits).is_err() {
+            problems.push("too-large");
+        }
+        problems.into_iter().map(String::from).collect()
+    }
+}
+
+// Check a PNG held in memory, the same way the scanner checks files
+#[wasm_bindgen(js_name = inspectPng)]
+pub fn inspect_png(data : &[u8]) -> PngInspection {
+    let parse_result = crate::parse_bytes(data);
+    let animated = match parse_result {
+        ParseResult::Valid(_) | ParseResult::AppleCgbi(_) => {
+            chunks::read_chunks(&mut Cursor::new(data)).is_ok_and(|summary| summary.animated)
+        },
+        _ => false,
+    };
+    PngInspection { parse_result, animated }
+}
@@ -0,0 +1,47 @@
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+use log::{error, info};
+
+// Events for a file are only delivered once it hasn't been written to for this long, so files
+// which are still being copied or exported aren't processed half-written
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+// How often to check whether Ctrl-C was pressed while waiting for events
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Block until Ctrl-C, calling handle_path for every file created or modified under scan_path
+pub fn watch<F>(scan_path : &Path, mut handle_path : F) where F: FnMut(&Path) {
+    let (tx, rx) = channel();
+
+    let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY).expect("Failed to create file watcher");
+    watcher.watch(scan_path, RecursiveMode::Recursive).expect("Failed to watch folder");
+
+    info!("Watching [{}] for new or modified files...", scan_path.display());
+
+    loop {
+        match rx.recv_timeout(INTERRUPT_POLL_INTERVAL) {
+            Ok(DebouncedEvent::Create(path)) |
+            Ok(DebouncedEvent::Write(path)) |
+            Ok(DebouncedEvent::Rename(_, path)) => handle_path(&path),
+            Ok(DebouncedEvent::Error(e, path)) => {
+                match path {
+                    Some(path) => error!("Watch error {}: {}", e, path.display()),
+                    None => error!("Watch error {}", e),
+                }
+            },
+            Ok(_) => {},
+            Err(RecvTimeoutError::Timeout) => {
+                if crate::is_interrupted() {
+                    info!("Stopped watching [{}]", scan_path.display());
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("Watcher stopped: {}", e);
+                return;
+            },
+        }
+    }
+}
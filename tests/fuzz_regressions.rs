@@ -0,0 +1,29 @@
+// Replays the malformed files in fuzz/regressions/parse_bytes, so every crash the fuzzer found
+// stays fixed. A new file needs its expected result added to expected_result.
+use std::fs;
+use std::path::Path;
+use png_header_scanner::ParseResult;
+
+fn expected_result(name : &str) -> fn(&ParseResult) -> bool {
+    match name {
+        "oversized_plte_length.png" => |result| matches!(result, ParseResult::ReadFail),
+        "truncated_ihdr.png" => |result| matches!(result, ParseResult::ReadFail),
+        _ => panic!("no expected result for fuzz/regressions/parse_bytes/{}", name),
+    }
+}
+
+#[test]
+fn parse_bytes_regressions() {
+    let regressions_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions/parse_bytes");
+    let mut num_replayed = 0;
+    for entry in fs::read_dir(&regressions_path).expect("Failed to list the regressions") {
+        let path = entry.expect("Failed to list the regressions").path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let data = fs::read(&path).expect("Failed to read a regression");
+
+        let result = png_header_scanner::parse_bytes(&data);
+        assert!(expected_result(&name)(&result), "{} gave {:?}", name, result);
+        num_replayed += 1;
+    }
+    assert!(num_replayed > 0);
+}